@@ -1,10 +1,13 @@
 use std::{env, path::PathBuf};
 
 use anyhow::{Result, anyhow, bail};
-use clap::Parser;
-use styrolite::config::{IdMapping, MountSpec as StyroMountSpec};
+use clap::{Parser, ValueEnum};
+use styrolite::config::{DnsConfig, IdMapping, MountSpec as StyroMountSpec};
+use styrolite::idmap::rootless_mappings;
 use styrolite::namespace::Namespace;
+use styrolite::network::{NetworkConfig, PortForward, Protocol, SLIRP_DNS, SlirpConfig};
 use styrolite::runner::{CreateRequestBuilder, Runner};
+use styrolite::systemd::CGroupDriver;
 
 #[derive(Clone, Debug)]
 struct ResourceLimit {
@@ -19,6 +22,16 @@ struct CliMountSpec {
     read_write: bool,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum NetworkMode {
+    /// Share the host's network
+    Host,
+    /// Only a loopback interface
+    None,
+    /// A user-mode network stack with outbound connectivity
+    Slirp,
+}
+
 #[derive(Debug, Parser)]
 #[command(
     name = "styrojail",
@@ -50,6 +63,24 @@ struct Cli {
     #[arg(long, value_name = "key:value", value_parser = parse_resource_limit)]
     limit: Vec<ResourceLimit>,
 
+    /// Map a full range of subordinate ids from /etc/subuid and /etc/subgid
+    /// into the jail, running the program as root inside it
+    #[arg(long)]
+    subids: bool,
+
+    /// Place the jail in a delegated systemd scope (on the user manager
+    /// unless running as root)
+    #[arg(long)]
+    systemd: bool,
+
+    /// Network mode for the jail
+    #[arg(long, value_enum, default_value = "host")]
+    net: NetworkMode,
+
+    /// Forward a host port to the jail (requires --net slirp)
+    #[arg(long, value_name = "[HOSTADDR:]HOSTPORT:JAILPORT[/udp]", value_parser = parse_publish)]
+    publish: Vec<PortForward>,
+
     /// The program being jailed
     #[arg(value_name = "PROGRAM")]
     program: String,
@@ -138,6 +169,30 @@ fn parse_resource_limit(s: &str) -> Result<ResourceLimit> {
     })
 }
 
+fn parse_publish(s: &str) -> Result<PortForward> {
+    const USAGE: &str = "publish must look like [hostaddr:]hostport:jailport[/udp]";
+
+    let (ports, protocol) = match s.rsplit_once('/') {
+        Some((ports, "tcp")) => (ports, Protocol::Tcp),
+        Some((ports, "udp")) => (ports, Protocol::Udp),
+        Some(_) => return Err(anyhow!("only /tcp and /udp are supported protocols")),
+        None => (s, Protocol::Tcp),
+    };
+
+    let (host, guest_port) = ports.rsplit_once(':').ok_or(anyhow!(USAGE))?;
+    let (host_address, host_port) = match host.rsplit_once(':') {
+        Some((address, port)) => (Some(address.to_string()), port),
+        None => (None, host),
+    };
+
+    Ok(PortForward {
+        protocol,
+        host_address,
+        host_port: host_port.parse().map_err(|_| anyhow!(USAGE))?,
+        guest_port: guest_port.parse().map_err(|_| anyhow!(USAGE))?,
+    })
+}
+
 fn to_styrolite_mount(m: &CliMountSpec) -> StyroMountSpec {
     StyroMountSpec {
         source: Some(m.hostpath.clone()),
@@ -162,20 +217,7 @@ fn main() -> Result<()> {
         .set_rootfs_readonly(!cli.no_rootfs_readonly)
         .set_skip_two_stage_userns(true)
         .set_executable(&cli.program)
-        .set_uid(uid)
-        .set_gid(gid)
-        .set_setgroups_deny(true)
         .set_workload_id(format!("styrojail-{}", std::process::id()).as_str())
-        .push_uid_mapping(IdMapping {
-            base_nsid: uid,
-            base_hostid: uid,
-            remap_count: 1,
-        })
-        .push_gid_mapping(IdMapping {
-            base_nsid: gid,
-            base_hostid: gid,
-            remap_count: 1,
-        })
         .push_namespace(Namespace::Uts)
         .push_namespace(Namespace::Time)
         .push_namespace(Namespace::Pid)
@@ -183,6 +225,59 @@ fn main() -> Result<()> {
         .push_namespace(Namespace::Ipc)
         .push_namespace(Namespace::Mount);
 
+    if !cli.publish.is_empty() && cli.net != NetworkMode::Slirp {
+        bail!("--publish requires --net slirp");
+    }
+
+    match cli.net {
+        NetworkMode::Host => {}
+        NetworkMode::None => builder = builder.push_namespace(Namespace::Net),
+        NetworkMode::Slirp => {
+            // The host's resolver may only be reachable from the host, so
+            // point the jail at the one forwarded by the user-mode network.
+            builder = builder
+                .push_namespace(Namespace::Net)
+                .set_network(NetworkConfig {
+                    slirp: Some(SlirpConfig {
+                        port_forwards: cli.publish.clone(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .set_dns(DnsConfig {
+                    servers: vec![SLIRP_DNS.to_string()],
+                    copy_from_host: true,
+                    ..Default::default()
+                });
+        }
+    }
+
+    if cli.subids {
+        let (uid_mappings, gid_mappings) = rootless_mappings()?;
+        builder = builder.set_uid(0).set_gid(0).set_id_map_helpers(true);
+        for mapping in uid_mappings {
+            builder = builder.push_uid_mapping(mapping);
+        }
+        for mapping in gid_mappings {
+            builder = builder.push_gid_mapping(mapping);
+        }
+    } else {
+        builder = builder
+            .set_uid(uid)
+            .set_gid(gid)
+            .set_setgroups_deny(true)
+            .push_uid_mapping(IdMapping {
+                base_nsid: uid,
+                base_hostid: uid,
+                remap_count: 1,
+            })
+            .push_gid_mapping(IdMapping {
+                base_nsid: gid,
+                base_hostid: gid,
+                remap_count: 1,
+            });
+    }
+
     if cli.rootfs == "/" {
         builder = builder
             .set_working_directory(std::env::current_dir()?.as_os_str().to_str().unwrap_or("/"))
@@ -199,6 +294,14 @@ fn main() -> Result<()> {
         builder = builder.push_mount(to_styrolite_mount(m));
     }
 
+    if cli.systemd {
+        builder = builder.set_cgroup_driver(if uid == 0 {
+            CGroupDriver::Systemd
+        } else {
+            CGroupDriver::SystemdUser
+        });
+    }
+
     for lim in &cli.limit {
         builder = builder.push_resource_limit(&lim.key, &lim.value);
     }
@@ -2,6 +2,8 @@
 use anyhow::{Error, anyhow};
 use libc::syscall;
 use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fs;
 use std::io;
 use std::str::FromStr;
 
@@ -49,8 +51,30 @@ pub enum CapabilityBit {
     Perfmon = 38,
     Bpf = 39,
     CheckpointRestore = 40,
+    /// A capability supported by the kernel but not known by name, written
+    /// as `CAP_<N>`. Only made by [`CapabilityBit::from_cap_number`].
+    Unknown(UnknownCapability),
 }
 
+/// The number of a capability not known by name, always above
+/// `CheckpointRestore` and below 64.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UnknownCapability(u8);
+
+impl UnknownCapability {
+    pub fn number(&self) -> u8 {
+        self.0
+    }
+}
+
+/// The names of unknown capabilities, indexed by their number.
+const NUMBERS: [&str; 64] = [
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13", "14", "15", "16",
+    "17", "18", "19", "20", "21", "22", "23", "24", "25", "26", "27", "28", "29", "30", "31", "32",
+    "33", "34", "35", "36", "37", "38", "39", "40", "41", "42", "43", "44", "45", "46", "47", "48",
+    "49", "50", "51", "52", "53", "54", "55", "56", "57", "58", "59", "60", "61", "62", "63",
+];
+
 pub struct CapabilityState {
     pub permitted: Vec<CapabilityBit>,
     pub effective: Vec<CapabilityBit>,
@@ -103,7 +127,43 @@ impl CapabilityBit {
     ];
 
     pub fn to_cap_number(&self) -> u8 {
-        *self as u8
+        match self {
+            CapabilityBit::Unknown(unknown) => unknown.number(),
+            known => Self::ALL
+                .iter()
+                .position(|bit| bit == known)
+                .expect("every capability known by name is in ALL") as u8,
+        }
+    }
+
+    /// The capability with the given number, if it fits in a capability set.
+    pub fn from_cap_number(number: u8) -> Option<CapabilityBit> {
+        match number {
+            0..=40 => Some(Self::ALL[number as usize]),
+            41..=63 => Some(CapabilityBit::Unknown(UnknownCapability(number))),
+            _ => None,
+        }
+    }
+
+    /// The number of the last capability supported by the running kernel,
+    /// from `/proc/sys/kernel/cap_last_cap`. Without it, every capability
+    /// known by name is assumed to be supported.
+    pub fn last_supported() -> u8 {
+        let fallback = CapabilityBit::CheckpointRestore.to_cap_number();
+        match fs::read_to_string("/proc/sys/kernel/cap_last_cap") {
+            Ok(last) => last.trim().parse().unwrap_or(fallback).min(63),
+            Err(e) => {
+                debug!("unable to read cap_last_cap: {e}");
+                fallback
+            }
+        }
+    }
+
+    /// Every capability supported by the running kernel.
+    pub fn supported() -> Vec<CapabilityBit> {
+        (0..=Self::last_supported())
+            .filter_map(Self::from_cap_number)
+            .collect()
     }
 
     pub fn bit_mask(&self) -> u64 {
@@ -123,13 +183,10 @@ impl CapabilityBit {
     }
 
     pub fn parse_bits(value: u64) -> Vec<CapabilityBit> {
-        let mut bits = Vec::new();
-        for bit in Self::ALL {
-            if bit.get_from(value) {
-                bits.push(*bit);
-            }
-        }
-        bits
+        (0..64)
+            .filter_map(Self::from_cap_number)
+            .filter(|bit| bit.get_from(value))
+            .collect()
     }
 
     pub fn raw_bits(bits: &[CapabilityBit]) -> u64 {
@@ -160,6 +217,12 @@ impl FromStr for CapabilityBit {
             input = &s[4..];
         }
         let refined = input.to_uppercase().trim().to_string();
+        if input.len() < s.len()
+            && let Ok(number) = refined.parse::<u8>()
+        {
+            return CapabilityBit::from_cap_number(number)
+                .ok_or_else(|| anyhow!("unknown capability: '{}'", s));
+        }
         for capability in CapabilityBit::ALL {
             if refined == capability.as_ref() {
                 return Ok(*capability);
@@ -213,14 +276,68 @@ impl AsRef<str> for CapabilityBit {
             CapabilityBit::Perfmon => "PERFMON",
             CapabilityBit::Bpf => "BPF",
             CapabilityBit::CheckpointRestore => "CHECKPOINT_RESTORE",
+            CapabilityBit::Unknown(unknown) => NUMBERS[unknown.number() as usize],
         }
     }
 }
 
 pub const PR_SET_SECUREBITS: i32 = 28;
-pub const SECBIT_KEEP_CAPS: i32 = 16;
+pub const SECBIT_NOROOT: i32 = 1;
+pub const SECBIT_NOROOT_LOCKED: i32 = 2;
 pub const SECBIT_NO_SETUID_FIXUP: i32 = 4;
+pub const SECBIT_NO_SETUID_FIXUP_LOCKED: i32 = 8;
+pub const SECBIT_KEEP_CAPS: i32 = 16;
 pub const SECBIT_KEEP_CAPS_LOCKED: i32 = 32;
+pub const SECBIT_NO_CAP_AMBIENT_RAISE: i32 = 64;
+pub const SECBIT_NO_CAP_AMBIENT_RAISE_LOCKED: i32 = 128;
+
+/// A securebits flag, see capabilities(7). The `_LOCKED` variants prevent
+/// the corresponding flag from being changed again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SecureBit {
+    /// uid 0 is not granted capabilities on execve(2).
+    #[serde(rename = "NOROOT", alias = "SECBIT_NOROOT")]
+    NoRoot,
+    #[serde(rename = "NOROOT_LOCKED", alias = "SECBIT_NOROOT_LOCKED")]
+    NoRootLocked,
+    /// Capabilities are not adjusted when uids change to or from 0.
+    #[serde(rename = "NO_SETUID_FIXUP", alias = "SECBIT_NO_SETUID_FIXUP")]
+    NoSetuidFixup,
+    #[serde(
+        rename = "NO_SETUID_FIXUP_LOCKED",
+        alias = "SECBIT_NO_SETUID_FIXUP_LOCKED"
+    )]
+    NoSetuidFixupLocked,
+    /// Permitted capabilities are kept when all uids change from 0. Cleared
+    /// on execve(2).
+    #[serde(rename = "KEEP_CAPS", alias = "SECBIT_KEEP_CAPS")]
+    KeepCaps,
+    #[serde(rename = "KEEP_CAPS_LOCKED", alias = "SECBIT_KEEP_CAPS_LOCKED")]
+    KeepCapsLocked,
+    /// Ambient capabilities can no longer be raised.
+    #[serde(rename = "NO_CAP_AMBIENT_RAISE", alias = "SECBIT_NO_CAP_AMBIENT_RAISE")]
+    NoCapAmbientRaise,
+    #[serde(
+        rename = "NO_CAP_AMBIENT_RAISE_LOCKED",
+        alias = "SECBIT_NO_CAP_AMBIENT_RAISE_LOCKED"
+    )]
+    NoCapAmbientRaiseLocked,
+}
+
+impl SecureBit {
+    pub fn bit_mask(&self) -> i32 {
+        match self {
+            SecureBit::NoRoot => SECBIT_NOROOT,
+            SecureBit::NoRootLocked => SECBIT_NOROOT_LOCKED,
+            SecureBit::NoSetuidFixup => SECBIT_NO_SETUID_FIXUP,
+            SecureBit::NoSetuidFixupLocked => SECBIT_NO_SETUID_FIXUP_LOCKED,
+            SecureBit::KeepCaps => SECBIT_KEEP_CAPS,
+            SecureBit::KeepCapsLocked => SECBIT_KEEP_CAPS_LOCKED,
+            SecureBit::NoCapAmbientRaise => SECBIT_NO_CAP_AMBIENT_RAISE,
+            SecureBit::NoCapAmbientRaiseLocked => SECBIT_NO_CAP_AMBIENT_RAISE_LOCKED,
+        }
+    }
+}
 
 /* from <unistd.h> */
 
@@ -431,6 +548,21 @@ pub fn set_keep_caps() -> anyhow::Result<()> {
     }
 }
 
+/// Replace the securebits of the calling process with `bits`. Requires
+/// `CAP_SETPCAP`.
+pub fn set_securebits(bits: &[SecureBit]) -> anyhow::Result<()> {
+    let value = bits.iter().fold(0, |value, bit| value | bit.bit_mask());
+    let ret = unsafe { libc::prctl(PR_SET_SECUREBITS, value) };
+    if ret < 0 {
+        Err(anyhow!(
+            "failed to set securebits {bits:?}: {}",
+            io::Error::last_os_error()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -498,4 +630,40 @@ mod tests {
         assert_eq!(rt.permitted, all_caps);
         assert_eq!(rt.inheritable, all_caps);
     }
+
+    #[test]
+    fn unknown_capabilities_are_parsed_by_number() {
+        assert_eq!(
+            CapabilityBit::from_str("CAP_41").unwrap(),
+            CapabilityBit::from_cap_number(41).unwrap()
+        );
+        assert_eq!(
+            CapabilityBit::from_str("CAP_13").unwrap(),
+            CapabilityBit::NetRaw
+        );
+        assert!(CapabilityBit::from_str("CAP_64").is_err());
+        assert_eq!(
+            CapabilityBit::from_cap_number(13),
+            Some(CapabilityBit::NetRaw)
+        );
+        assert_eq!(CapabilityBit::from_cap_number(64), None);
+        assert!(CapabilityBit::from_str("41").is_err());
+
+        let unknown = CapabilityBit::from_cap_number(63).unwrap();
+        assert_eq!(unknown.to_cap_number(), 63);
+        assert_eq!(unknown.as_ref(), "63");
+        assert_eq!(CapabilityBit::Bpf.to_cap_number(), 39);
+        assert_eq!(
+            CapabilityBit::parse_bits(unknown.bit_mask() | CapabilityBit::Chown.bit_mask()),
+            vec![CapabilityBit::Chown, unknown]
+        );
+
+        let supported = CapabilityBit::supported();
+        assert_eq!(
+            supported.len(),
+            CapabilityBit::last_supported() as usize + 1
+        );
+        let known = supported.len().min(CapabilityBit::ALL.len());
+        assert_eq!(supported[..known], CapabilityBit::ALL[..known]);
+    }
 }
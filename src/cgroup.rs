@@ -36,17 +36,25 @@
 //! $ echo 12345 >> /sys/fs/cgroup/styrolite-1000/a/cgroup.procs
 //! ```
 //!
-//! In styrolite, we move the supervisor (styrolite-bin) into the
-//! configured cgroup.  This is because it allows us to guarantee
-//! that supervised processes automatically get spawned into the
-//! correct cgroup without any race conditions.
+//! In styrolite, the workload is spawned directly into the configured
+//! cgroup with `clone3(2)` and `CLONE_INTO_CGROUP`, so the supervisor
+//! (styrolite-bin) stays in its own cgroup.  This keeps the supervisor's
+//! memory out of the workload's accounting, avoids "no internal processes"
+//! conflicts when the workload cgroup needs children of its own, and still
+//! guarantees the workload is in the correct cgroup from its first
+//! instruction without any race conditions.
+//!
+//! On kernels older than 5.7, which lack `CLONE_INTO_CGROUP`, we fall back
+//! to moving the supervisor into the configured cgroup before forking.
 
 use std::ffi::CString;
 use std::fs;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 
 use anyhow::{Error, Result, bail};
-use libc::{AT_EACCESS, AT_FDCWD, F_OK, c_char, faccessat};
+use libc::{AT_EACCESS, AT_FDCWD, F_OK, W_OK, c_char, faccessat};
 
 #[derive(Clone, Debug)]
 pub struct CGroup {
@@ -71,6 +79,77 @@ impl CGroup {
         })
     }
 
+    /// Open the cgroup2 node of process `pid`, as mounted in the caller's
+    /// mount namespace. Returns `None` if the process is not on a cgroup2
+    /// hierarchy, or if that is not mounted.
+    pub fn of_process(pid: libc::pid_t) -> Result<Option<CGroup>> {
+        let cgroups = fs::read_to_string(format!("/proc/{pid}/cgroup"))?;
+        let Some(path) = cgroups.lines().find_map(|line| line.strip_prefix("0::")) else {
+            return Ok(None);
+        };
+
+        // Fields are described in proc_pid_mountinfo(5): the mount root is
+        // the fourth and the mount point the fifth, and the filesystem type
+        // follows the separator.
+        let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+        for line in mountinfo.lines() {
+            let Some((mount, filesystem)) = line.split_once(" - ") else {
+                continue;
+            };
+            if filesystem.split(' ').next() != Some("cgroup2") {
+                continue;
+            }
+            let fields = mount.split(' ').collect::<Vec<_>>();
+            let (Some(root), Some(mountpoint)) = (fields.get(3), fields.get(4)) else {
+                continue;
+            };
+            let relative = match path.strip_prefix(root.trim_end_matches('/')) {
+                Some(relative) if relative.is_empty() || relative.starts_with('/') => relative,
+                _ => continue,
+            };
+            return Ok(Some(CGroup::open(&format!("{mountpoint}{relative}"))?));
+        }
+
+        Ok(None)
+    }
+
+    /// The path of this cgroup node.
+    pub fn path(&self) -> &str {
+        &self.root
+    }
+
+    /// Open a directory file descriptor for this cgroup node, suitable for
+    /// use with `CLONE_INTO_CGROUP`. Fails if the node is not on a cgroup2
+    /// filesystem.
+    pub fn open_fd(&self) -> Result<OwnedFd> {
+        let dir = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY | libc::O_CLOEXEC)
+            .open(&self.root)?;
+
+        let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+        unsafe {
+            if libc::fstatfs(dir.as_raw_fd(), &mut stat) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+
+        if stat.f_type != libc::CGROUP2_SUPER_MAGIC {
+            bail!("{} is not on a cgroup2 filesystem", self.root);
+        }
+
+        Ok(dir.into())
+    }
+
+    /// Whether the calling process may move processes into this cgroup.
+    pub fn writable(&self) -> bool {
+        let Ok(path) = CString::new(format!("{}/cgroup.procs", self.root)) else {
+            return false;
+        };
+
+        unsafe { faccessat(AT_FDCWD, path.as_ptr(), W_OK, AT_EACCESS) == 0 }
+    }
+
     /// Open a CGroup walker at a given child node.
     pub fn open_child<P: AsRef<Path>>(self, child: P) -> Result<CGroup> {
         let mut path = PathBuf::from(self.root);
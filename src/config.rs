@@ -1,8 +1,16 @@
-use crate::caps::CapabilityBit;
-use crate::namespace::Namespace;
+use crate::caps::{CapabilityBit, SecureBit};
+use crate::devices::DeviceRule;
+use crate::idmap::IdTranslator;
+use crate::namespace::{Namespace, NamespaceJoin, NamespacePin};
+use crate::network::NetworkConfig;
+use crate::rootfs::Rootfs;
+use crate::sched::{IoPriority, Scheduler};
 use crate::seccomp::SeccompFilter;
+use crate::signal;
+use crate::systemd::CGroupDriver;
 use anyhow::{Result, bail};
-use libc::{gid_t, pid_t, uid_t};
+use libc::{c_ulong, gid_t, pid_t, uid_t};
+use nix::sys::signal::Signal;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashSet};
 use std::str::FromStr;
@@ -24,17 +32,23 @@ pub struct AttachRequest {
     /// container.
     pub workload_id: Option<String>,
 
-    /// An optional path to a cgroup2 filesystem to attach to. See
-    /// CreateRequest::cgroupfs for a more detailed explanation.
+    /// Ignored: the cgroup to attach to is the one the workload was spawned
+    /// into, found from the workload process whichever `cgroupfs` or
+    /// `cgroup_driver` it was created with.
+    #[deprecated(note = "the workload's cgroup is found from its process")]
     pub cgroupfs: Option<String>,
 
     /// A set of namespaces to join.
     pub namespaces: Option<Vec<Namespace>>,
     /// Capabilities for this attachment.
     pub capabilities: Option<Capabilities>,
+
+    /// See `CreateRequest::parent_death_signal`.
+    #[serde(default)]
+    pub parent_death_signal: Option<String>,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct IdMapping {
     /// The base UID/GID inside the user namespace.
     pub base_nsid: u32,
@@ -46,7 +60,7 @@ pub struct IdMapping {
     pub remap_count: u32,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct ExecutableSpec {
     /// Executable path (not resolved by PATH)
     pub executable: Option<String>,
@@ -72,6 +86,14 @@ pub struct ExecutableSpec {
     /// These GIDs are relative to the user namespace that is optionally set up.
     pub supplemental_gids: Option<Vec<gid_t>>,
 
+    /// An optional user to run as, like `nginx`, `1000` or `app:staff`.
+    /// It is resolved against the container's `/etc/passwd` and `/etc/group`
+    /// once the rootfs is in place, like Docker's `--user`, and provides
+    /// `uid`, `gid` and `supplemental_gids` where those are not set, as well
+    /// as `HOME` if the environment does not set it.
+    #[serde(default)]
+    pub user: Option<String>,
+
     /// An optional set of process-specific resource limits.
     /// If this set is not provided, setrlimit(2) will not be called.
     pub process_limits: Option<ProcessResourceLimits>,
@@ -94,6 +116,29 @@ pub struct ExecutableSpec {
 
     /// An optional out-of-memory score adjustment value.
     pub oom_score_adj: Option<i32>,
+
+    /// An optional scheduling policy, set with sched_setattr(2).
+    #[serde(default)]
+    pub scheduler: Option<Scheduler>,
+
+    /// An optional list of CPUs to run on, like `0-3,8`.
+    #[serde(default)]
+    pub cpu_affinity: Option<String>,
+
+    /// An optional I/O priority, set with ioprio_set(2).
+    #[serde(default)]
+    pub io_priority: Option<IoPriority>,
+
+    /// An optional file mode creation mask, like `0o022`, set with umask(2)
+    /// before `execvpe()`. If unset, the supervisor's umask is inherited.
+    #[serde(default)]
+    pub umask: Option<u32>,
+
+    /// An optional execution domain, set with personality(2) before
+    /// `execvpe()`, such as `LINUX32` for 32-bit workloads or
+    /// `ADDR_NO_RANDOMIZE` for reproducible debugging.
+    #[serde(default)]
+    pub personality: Option<Personality>,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
@@ -103,14 +148,31 @@ pub struct CreateRequest {
     /// process ID will be used as a limited fallback.
     pub workload_id: Option<String>,
 
-    /// A bare rootfs. It should be assumed the rootfs is writable via a read-only layer and a writeable backing layer.
-    /// It should be assumed the rootfs might already have mounts.
+    /// A bare rootfs. It should be assumed the rootfs might already have mounts.
     /// It should be assumed that the rootfs might already have proc, sys, and dev mounts. (This might need to change?)
+    /// Either this or `rootfs_overlay` must be set.
     pub rootfs: Option<String>,
 
+    /// An overlayfs rootfs assembled from read-only layers, used instead of
+    /// `rootfs`.
+    #[serde(default)]
+    pub rootfs_overlay: Option<OverlayRootfs>,
+
     /// Whether the rootfs should be mounted readonly.
     pub rootfs_readonly: Option<bool>,
 
+    /// Whether the rootfs should be an idmapped mount, and with which
+    /// mappings.
+    #[serde(default)]
+    pub rootfs_idmap: Option<IdMap>,
+
+    /// The propagation type of the mount namespace's rootfs. Defaults to
+    /// `RPrivate`, so no mount events are shared with the host. `RSlave`
+    /// lets mounts made on the host later on (e.g. a FUSE or CSI volume
+    /// below a bind-mounted directory) show up inside the container.
+    #[serde(default)]
+    pub rootfs_propagation: Option<Propagation>,
+
     /// The executable specification for the initial process created in this
     /// container.
     pub exec: ExecutableSpec,
@@ -127,6 +189,14 @@ pub struct CreateRequest {
     /// `/proc` will be mounted regardless of whether a mount specification is configured.
     pub mounts: Option<Vec<MountSpec>>,
 
+    /// Whether to mount a standard set of filesystems after `/proc`: a
+    /// tmpfs `/dev` with the usual device nodes and symlinks, a private
+    /// `/dev/pts`, `/dev/shm`, `/dev/mqueue` (with an IPC namespace), a
+    /// read-only `/sys`, and `/sys/fs/cgroup`. Mount specifications are
+    /// applied on top.
+    #[serde(default)]
+    pub standard_filesystems: Option<bool>,
+
     /// Paths inside the container to mask so their contents are inaccessible,
     /// applied after all mounts are in place (so freshly-mounted targets like
     /// `/proc` are covered) but before pivot. A file target is covered by a
@@ -155,26 +225,201 @@ pub struct CreateRequest {
     /// delegation. Ideally, this should be a path to that delegation.
     pub cgroupfs: Option<String>,
 
+    /// How the cgroup root is obtained. With a systemd driver, a transient
+    /// scope with `Delegate=yes` is created for the workload and used as the
+    /// cgroup root instead of `cgroupfs`, with `limits` also mapped onto the
+    /// scope's unit properties where systemd has an equivalent. They are
+    /// written to the workload's cgroup below the scope as well. Defaults to
+    /// `CGroupDriver::Cgroupfs`.
+    #[serde(default)]
+    pub cgroup_driver: Option<CGroupDriver>,
+
+    /// An optional device access policy, mirroring the OCI runtime-spec
+    /// `linux.resources.devices`. If set, the workload may only use the
+    /// devices in `devices::DEFAULT_DEVICES` plus those allowed here, with
+    /// the last matching rule winning. Enforced by a device program attached
+    /// to the workload cgroup, so a cgroup will be configured even if no
+    /// `limits` are set.
+    #[serde(default)]
+    pub devices: Option<Vec<DeviceRule>>,
+
     /// An optional hostname to be used for the container.
     /// If this is not provided, the workload identity will be used.
     pub hostname: Option<String>,
 
+    /// Whether to generate an `/etc/hostname` containing the container
+    /// hostname. Like `hosts` and `dns`, the file is written to a
+    /// per-workload tmpfs and bind-mounted read-only over the rootfs path.
+    #[serde(default)]
+    pub hostname_file: Option<bool>,
+
+    /// Extra entries for a generated `/etc/hosts`. If set, even to an empty
+    /// list, `/etc/hosts` is generated with the usual localhost entries, an
+    /// entry for the container hostname, and these entries.
+    #[serde(default)]
+    pub hosts: Option<Vec<HostEntry>>,
+
+    /// The DNS configuration for a generated `/etc/resolv.conf`.
+    #[serde(default)]
+    pub dns: Option<DnsConfig>,
+
     /// An optional list of mutations to apply to the container FS.
     pub mutations: Option<Vec<Mutation>>,
 
     /// A set of namespaces to join.
     pub namespaces: Option<Vec<Namespace>>,
 
+    /// Existing namespaces to join instead of creating new ones, such as the
+    /// network, IPC and UTS namespaces of a pod. A namespace can't be both
+    /// in `namespaces` and joined, and the mount namespace is never joined.
+    #[serde(default)]
+    pub join_namespaces: Option<Vec<NamespaceJoin>>,
+
+    /// Namespaces of the workload to pin to paths, so that they outlive it.
+    /// They are pinned in the host's mount namespace before the workload is
+    /// executed, and must be created or joined by this request.
+    #[serde(default)]
+    pub pin_namespaces: Option<Vec<NamespacePin>>,
+
+    /// The clocks of a new time namespace. Defaults to
+    /// [`TimeOffsets::ResetBoottime`].
+    #[serde(default)]
+    pub time_offsets: Option<TimeOffsets>,
+
+    /// Namespaced kernel parameters to set, such as
+    /// `net.ipv4.ip_unprivileged_port_start` or `kernel.shmmax`. Each must
+    /// belong to a namespace created by this request; see the `sysctl`
+    /// module.
+    #[serde(default)]
+    pub sysctls: Option<BTreeMap<String, String>>,
+
+    /// How the network namespace is set up, if `namespaces` contains
+    /// `Namespace::Net`. By default, only the loopback interface is brought
+    /// up. Configured by the supervisor before the workload is executed.
+    #[serde(default)]
+    pub network: Option<NetworkConfig>,
+
     /// Whether setgroups(2) should be denied in this container.
     pub setgroups_deny: Option<bool>,
 
+    /// Whether `uid_mappings` and `gid_mappings` should be applied through
+    /// the setuid newuidmap(1) and newgidmap(1) helpers instead of being
+    /// written directly. This lets unprivileged callers map the subordinate
+    /// id ranges delegated to them in `/etc/subuid` and `/etc/subgid`; see
+    /// `idmap::rootless_mappings`. In this mode, setgroups(2) is only denied
+    /// if `setgroups_deny` is explicitly set.
+    #[serde(default)]
+    pub id_map_helpers: Option<bool>,
+
     /// Capabilities for this container.
     pub capabilities: Option<Capabilities>,
 
     /// Whether the two-stage userns setup should be skipped.
     pub skip_two_stage_userns: Option<bool>,
+
+    /// A signal, like `SIGTERM` or `SIGKILL`, that the supervisor receives
+    /// when the process which started it exits, see `PR_SET_PDEATHSIG` in
+    /// prctl(2). A forwarded signal lets the workload exit gracefully, and
+    /// the workload is killed if the supervisor dies. If unset, the
+    /// container outlives its parent.
+    #[serde(default)]
+    pub parent_death_signal: Option<String>,
+}
+
+/// The offsets of the clocks in a new time namespace from the host's, see
+/// time_namespaces(7).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum TimeOffsets {
+    /// Start `CLOCK_BOOTTIME` at about zero, as if the workload had just
+    /// booted, and keep the host's `CLOCK_MONOTONIC`.
+    #[default]
+    ResetBoottime,
+
+    /// Keep the host's clocks.
+    PreserveHost,
+
+    /// Add explicit offsets to the host's clocks, for example to keep the
+    /// clocks of a restored workload continuous.
+    Explicit {
+        #[serde(default)]
+        monotonic: Option<ClockOffset>,
+        #[serde(default)]
+        boottime: Option<ClockOffset>,
+    },
+}
+
+impl TimeOffsets {
+    /// Check that explicit offsets are well formed.
+    pub fn check(&self) -> Result<()> {
+        if let TimeOffsets::Explicit {
+            monotonic,
+            boottime,
+        } = self
+        {
+            for (clock, offset) in [("monotonic", monotonic), ("boottime", boottime)] {
+                if let Some(offset) = offset
+                    && offset.nanos >= 1_000_000_000
+                {
+                    bail!("{clock} offset nanoseconds must be below one second");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An offset added to a clock, which may be negative as long as the clock
+/// stays positive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClockOffset {
+    pub secs: i64,
+
+    /// Nanoseconds added to `secs`, below one second.
+    #[serde(default)]
+    pub nanos: u32,
+}
+
+/// An entry in a generated `/etc/hosts`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostEntry {
+    /// The IPv4 or IPv6 address.
+    pub address: String,
+
+    /// The names the address is known by, canonical name first.
+    pub names: Vec<String>,
 }
 
+/// The contents of a generated `/etc/resolv.conf`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DnsConfig {
+    /// Nameserver addresses, in order of preference.
+    #[serde(default)]
+    pub servers: Vec<String>,
+
+    /// Search domains.
+    #[serde(default)]
+    pub search: Vec<String>,
+
+    /// Resolver options, such as `ndots:2` or `edns0`.
+    #[serde(default)]
+    pub options: Vec<String>,
+
+    /// Whether to start from a copy of the host's `/etc/resolv.conf`. Its
+    /// `nameserver`, `search` (and `domain`) and `options` lines are
+    /// replaced by `servers`, `search` and `options` respectively, if those
+    /// are set.
+    #[serde(default)]
+    pub copy_from_host: bool,
+}
+
+/// The capabilities of the workload, see capabilities(7).
+///
+/// `raise` and `drop` adjust the supervisor's effective set, which then
+/// becomes the permitted and inheritable sets too, and `drop` also removes
+/// capabilities from the bounding and ambient sets. Each of the sets below
+/// which is given replaces what is derived from them, like the capabilities
+/// of the OCI runtime-spec.
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct Capabilities {
     /// Capabilities to raise on the container.
@@ -183,17 +428,56 @@ pub struct Capabilities {
     pub raise_ambient: Option<Vec<String>>,
     /// Capabilities to drop on the container.
     pub drop: Option<Vec<String>>,
+
+    /// The bounding set. Every other capability is dropped from it.
+    #[serde(default)]
+    pub bounding: Option<Vec<String>>,
+
+    /// The effective set, which must be a subset of the permitted set.
+    #[serde(default)]
+    pub effective: Option<Vec<String>>,
+
+    /// The permitted set.
+    #[serde(default)]
+    pub permitted: Option<Vec<String>>,
+
+    /// The inheritable set.
+    #[serde(default)]
+    pub inheritable: Option<Vec<String>>,
+
+    /// The ambient set, which must be a subset of both the permitted and
+    /// inheritable sets. Every other ambient capability is lowered.
+    #[serde(default)]
+    pub ambient: Option<Vec<String>>,
+
+    /// Securebits to set, replacing the supervisor's, such as `NOROOT` and
+    /// `NOROOT_LOCKED` to keep a workload running as uid 0 from regaining
+    /// capabilities on execve(2). Setting them requires `CAP_SETPCAP`, which
+    /// the workload may drop.
+    #[serde(default)]
+    pub securebits: Option<Vec<SecureBit>>,
 }
 
 impl Capabilities {
+    /// Parse capability names, like `CAP_CHOWN`, or `CAP_<N>` for those not
+    /// known by name. `ALL` stands for every capability supported by the
+    /// running kernel, and others must be supported too.
     pub fn names_as_bits(names: &[String]) -> Result<Vec<CapabilityBit>> {
+        let last_supported = CapabilityBit::last_supported();
         let mut caps = HashSet::new();
         for name in names {
             let bit = CapabilityBit::from_str(name).ok();
             if let Some(bit) = bit {
+                if bit.to_cap_number() > last_supported {
+                    bail!(
+                        "capability {} is not supported by the kernel, whose last is {}",
+                        name,
+                        last_supported
+                    );
+                }
                 caps.insert(bit);
             } else if name.to_uppercase() == "ALL" {
-                caps.extend(CapabilityBit::ALL.iter().cloned());
+                caps.extend(CapabilityBit::supported());
             } else {
                 bail!("unknown capability: {}", name);
             }
@@ -214,16 +498,186 @@ pub enum Config {
     Attach(Box<AttachRequest>),
 }
 
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct OverlayRootfs {
+    /// The read-only lower layers, from the top-most layer down.
+    pub lower: Vec<String>,
+
+    /// The writable upper layer. Without one, the rootfs is read-only.
+    #[serde(default)]
+    pub upper: Option<OverlayUpper>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum OverlayUpper {
+    /// Persistent upper and work directories, which must be on the same
+    /// filesystem.
+    Directory { upper: String, work: String },
+
+    /// Upper and work directories on a tmpfs which is discarded when the
+    /// container exits, optionally limited to `size` (e.g. "512m").
+    Ephemeral { size: Option<String> },
+}
+
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct CreateDirMutation {
     /// The directory inside the container FS to create.
     pub target: String,
 }
 
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct WriteFileMutation {
+    /// The file inside the container FS to write. An existing file is
+    /// replaced.
+    pub target: String,
+
+    /// The contents of the file, as text.
+    #[serde(default)]
+    pub content: Option<String>,
+
+    /// The contents of the file, base64 encoded, for binary files. Used
+    /// instead of `content`.
+    #[serde(default)]
+    pub content_base64: Option<String>,
+
+    /// The permission bits of the file. Defaults to 0644.
+    #[serde(default)]
+    pub mode: Option<u32>,
+
+    /// The uid owning the file inside the container. If unset, the file is
+    /// owned by the supervisor's uid.
+    #[serde(default)]
+    pub uid: Option<uid_t>,
+
+    /// The gid owning the file inside the container. If unset, the file is
+    /// owned by the supervisor's gid.
+    #[serde(default)]
+    pub gid: Option<gid_t>,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct SymlinkMutation {
+    /// The path the symlink points to, which is not resolved.
+    pub source: String,
+
+    /// The symlink inside the container FS to create. An existing file is
+    /// replaced.
+    pub target: String,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct ChmodMutation {
+    /// The file inside the container FS to change.
+    pub target: String,
+
+    /// The new permission bits, including setuid, setgid and sticky bits.
+    pub mode: u32,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct ChownMutation {
+    /// The file inside the container FS to change.
+    pub target: String,
+
+    /// The new owning uid inside the container. If unset, it is unchanged.
+    #[serde(default)]
+    pub uid: Option<uid_t>,
+
+    /// The new owning gid inside the container. If unset, it is unchanged.
+    #[serde(default)]
+    pub gid: Option<gid_t>,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct CopyFromHostMutation {
+    /// The file or directory on the host to copy. Directories are copied
+    /// recursively, and permission bits are preserved.
+    pub source: String,
+
+    /// The path inside the container FS to copy to. An existing file is
+    /// replaced.
+    pub target: String,
+
+    /// The uid owning everything copied inside the container. If unset,
+    /// copies are owned by the supervisor's uid.
+    #[serde(default)]
+    pub uid: Option<uid_t>,
+
+    /// The gid owning everything copied inside the container. If unset,
+    /// copies are owned by the supervisor's gid.
+    #[serde(default)]
+    pub gid: Option<gid_t>,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct RemoveMutation {
+    /// The file or directory inside the container FS to remove, recursively.
+    /// Nothing happens if it does not exist.
+    pub target: String,
+}
+
+/// The type of a special file created by a [`MknodMutation`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeType {
+    /// A character device.
+    #[default]
+    #[serde(rename = "c")]
+    Char,
+
+    /// A block device.
+    #[serde(rename = "b")]
+    Block,
+
+    /// A named pipe.
+    #[serde(rename = "p")]
+    Fifo,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct MknodMutation {
+    /// The special file inside the container FS to create. An existing file
+    /// is replaced.
+    pub target: String,
+
+    /// The type of special file.
+    #[serde(rename = "type", default)]
+    pub kind: NodeType,
+
+    /// The device major number, for devices.
+    #[serde(default)]
+    pub major: u32,
+
+    /// The device minor number, for devices.
+    #[serde(default)]
+    pub minor: u32,
+
+    /// The permission bits of the file. Defaults to 0666.
+    #[serde(default)]
+    pub mode: Option<u32>,
+
+    /// The uid owning the file inside the container. If unset, the file is
+    /// owned by the supervisor's uid.
+    #[serde(default)]
+    pub uid: Option<uid_t>,
+
+    /// The gid owning the file inside the container. If unset, the file is
+    /// owned by the supervisor's gid.
+    #[serde(default)]
+    pub gid: Option<gid_t>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "kind")]
 pub enum Mutation {
     CreateDir(CreateDirMutation),
+    WriteFile(WriteFileMutation),
+    Symlink(SymlinkMutation),
+    Chmod(ChmodMutation),
+    Chown(ChownMutation),
+    CopyFromHost(CopyFromHostMutation),
+    Remove(RemoveMutation),
+    Mknod(MknodMutation),
 }
 
 pub trait Wrappable {
@@ -238,30 +692,55 @@ pub trait Validatable {
 
 impl Validatable for CreateRequest {
     fn validate(&self) -> Result<()> {
+        if self.rootfs.is_some() && self.rootfs_overlay.is_some() {
+            bail!("rootfs and rootfs_overlay are mutually exclusive");
+        }
+
+        self.parent_death_signal()?;
+
         Ok(())
     }
 }
 
 impl Validatable for AttachRequest {
     fn validate(&self) -> Result<()> {
+        self.parent_death_signal()?;
+
         Ok(())
     }
 }
 
 pub trait Configurable: Serialize + Validatable {
     fn encapsulate(self) -> Result<Config>;
+
+    /// The signal the supervisor should receive when its parent exits.
+    fn parent_death_signal(&self) -> Result<Option<Signal>>;
 }
 
 impl Configurable for CreateRequest {
     fn encapsulate(self) -> Result<Config> {
         Ok(Config::Create(Box::new(self)))
     }
+
+    fn parent_death_signal(&self) -> Result<Option<Signal>> {
+        self.parent_death_signal
+            .as_deref()
+            .map(signal::parse_signal)
+            .transpose()
+    }
 }
 
 impl Configurable for AttachRequest {
     fn encapsulate(self) -> Result<Config> {
         Ok(Config::Attach(Box::new(self)))
     }
+
+    fn parent_death_signal(&self) -> Result<Option<Signal>> {
+        self.parent_death_signal
+            .as_deref()
+            .map(signal::parse_signal)
+            .transpose()
+    }
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
@@ -296,6 +775,62 @@ pub struct MountSpec {
 
     /// Optional mount data (e.g., "size=64m" for tmpfs).
     pub data: Option<String>,
+
+    /// Whether the mount point should be an idmapped mount, and with which
+    /// mappings. Only bind mounts can be idmapped.
+    #[serde(default)]
+    pub idmap: Option<IdMap>,
+
+    /// The propagation type the mount point should be given once mounted.
+    /// This is applied independently of `unshare`.
+    #[serde(default)]
+    pub propagation: Option<Propagation>,
+
+    /// fstab/OCI-style mount options, e.g. `nosuid`, `relatime`, `rro`,
+    /// `rslave` or `size=64m`. Options that are not mount flags, mount
+    /// attributes or propagation types are passed to the filesystem along
+    /// with `data`. The dedicated fields above take precedence.
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// The id mappings of an idmapped mount. Ids stored on disk are treated as
+/// ids inside a user namespace with these mappings, so a volume written with
+/// the container's own ids shows up with the right owners when the mount
+/// uses the container's mappings.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum IdMap {
+    /// Use the container's `uid_mappings` and `gid_mappings`.
+    Container,
+
+    /// Use explicit mappings.
+    Mappings {
+        uid_mappings: Vec<IdMapping>,
+        gid_mappings: Vec<IdMapping>,
+    },
+}
+
+/// The propagation type of a mount point, see mount_namespaces(7). The
+/// recursive variants also apply to every mount below the mount point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Propagation {
+    /// Mount and unmount events propagate to and from peer mounts.
+    Shared,
+    RShared,
+
+    /// Mount and unmount events propagate in from the master peer group,
+    /// but not back out.
+    Slave,
+    RSlave,
+
+    /// Mount and unmount events do not propagate in either direction.
+    Private,
+    RPrivate,
+
+    /// Like `Private`, and the mount point cannot be bind mounted.
+    Unbindable,
+    RUnbindable,
 }
 
 pub trait Mountable {
@@ -313,7 +848,8 @@ pub trait Mountable {
 pub type ResourceLimits = BTreeMap<String, String>;
 
 pub trait Mutatable {
-    fn mutate(&self, rootfs: &str) -> Result<()>;
+    /// Apply the mutation to `rootfs`, translating container ids with `ids`.
+    fn mutate(&self, rootfs: &Rootfs, ids: &IdTranslator) -> Result<()>;
 }
 
 /// Resource limits for processes inside the container itself.
@@ -389,3 +925,100 @@ pub struct ProcessResourceLimits {
     /// See RLIMIT_STACK in setrlimit(2) for more detail.
     pub main_thread_stack_size: Option<u64>,
 }
+
+/// The execution domain of the workload, see personality(2).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Personality {
+    pub domain: PersonalityDomain,
+
+    /// Flags altering the behaviour of the domain.
+    #[serde(default)]
+    pub flags: Vec<PersonalityFlag>,
+}
+
+/// An execution domain of personality(2).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PersonalityDomain {
+    /// The native domain.
+    #[default]
+    #[serde(rename = "LINUX")]
+    Linux,
+
+    /// A 32-bit domain, in which uname(2) reports a 32-bit machine such as
+    /// `i686`, like linux32(1).
+    #[serde(rename = "LINUX32")]
+    Linux32,
+}
+
+/// A flag of personality(2).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PersonalityFlag {
+    /// Disable address space layout randomization.
+    #[serde(rename = "ADDR_NO_RANDOMIZE")]
+    AddrNoRandomize,
+    /// Use the legacy virtual address space layout.
+    #[serde(rename = "ADDR_COMPAT_LAYOUT")]
+    AddrCompatLayout,
+    /// Limit the address space to 32 bits.
+    #[serde(rename = "ADDR_LIMIT_32BIT")]
+    AddrLimit32Bit,
+    /// Limit the address space to 3GB.
+    #[serde(rename = "ADDR_LIMIT_3GB")]
+    AddrLimit3Gb,
+    /// Map page 0 read-only, as SVr4 does.
+    #[serde(rename = "MMAP_PAGE_ZERO")]
+    MmapPageZero,
+    /// Make readable memory executable as well.
+    #[serde(rename = "READ_IMPLIES_EXEC")]
+    ReadImpliesExec,
+    #[serde(rename = "SHORT_INODE")]
+    ShortInode,
+    #[serde(rename = "STICKY_TIMEOUTS")]
+    StickyTimeouts,
+    #[serde(rename = "WHOLE_SECONDS")]
+    WholeSeconds,
+}
+
+impl PersonalityFlag {
+    fn bits(self) -> c_ulong {
+        let bits = match self {
+            PersonalityFlag::AddrNoRandomize => libc::ADDR_NO_RANDOMIZE,
+            PersonalityFlag::AddrCompatLayout => libc::ADDR_COMPAT_LAYOUT,
+            PersonalityFlag::AddrLimit32Bit => libc::ADDR_LIMIT_32BIT,
+            PersonalityFlag::AddrLimit3Gb => libc::ADDR_LIMIT_3GB,
+            PersonalityFlag::MmapPageZero => libc::MMAP_PAGE_ZERO,
+            PersonalityFlag::ReadImpliesExec => libc::READ_IMPLIES_EXEC,
+            PersonalityFlag::ShortInode => libc::SHORT_INODE,
+            PersonalityFlag::StickyTimeouts => libc::STICKY_TIMEOUTS,
+            PersonalityFlag::WholeSeconds => libc::WHOLE_SECONDS,
+        };
+        bits as c_ulong
+    }
+}
+
+impl Personality {
+    /// The persona passed to personality(2).
+    pub fn persona(&self) -> c_ulong {
+        // PER_LINUX and PER_LINUX32 from <linux/personality.h>.
+        let domain = match self.domain {
+            PersonalityDomain::Linux => 0x0000,
+            PersonalityDomain::Linux32 => 0x0008,
+        };
+        self.flags
+            .iter()
+            .fold(domain, |persona, flag| persona | flag.bits())
+    }
+
+    /// Set the persona of the calling process. It takes effect for the
+    /// programs it executes next.
+    pub fn apply(&self) -> Result<()> {
+        let persona = self.persona();
+        if unsafe { libc::personality(persona) } < 0 {
+            bail!(
+                "unable to set personality {self:?}: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+}
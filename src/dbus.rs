@@ -0,0 +1,430 @@
+//! A minimal D-Bus client, just large enough to make method calls against
+//! the systemd manager over a unix socket.
+//!
+//! Only the pieces of the wire protocol that styrolite needs are implemented:
+//! `EXTERNAL` authentication, little-endian marshalling of the basic types we
+//! send, and enough header parsing to match replies and surface errors.
+
+use std::env;
+use std::io::{Read, Write};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixStream};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+const MESSAGE_TYPE_METHOD_CALL: u8 = 1;
+const MESSAGE_TYPE_METHOD_RETURN: u8 = 2;
+const MESSAGE_TYPE_ERROR: u8 = 3;
+
+/// Never activate the destination service to handle a call. The services we
+/// talk to are either already running or absent.
+const MESSAGE_FLAG_NO_AUTO_START: u8 = 0x2;
+
+const HEADER_FIELD_PATH: u8 = 1;
+const HEADER_FIELD_INTERFACE: u8 = 2;
+const HEADER_FIELD_MEMBER: u8 = 3;
+const HEADER_FIELD_ERROR_NAME: u8 = 4;
+const HEADER_FIELD_REPLY_SERIAL: u8 = 5;
+const HEADER_FIELD_DESTINATION: u8 = 6;
+const HEADER_FIELD_SIGNATURE: u8 = 8;
+
+/// Marshals values in the D-Bus wire format. Alignment is relative to the
+/// start of the buffer, so a body must be marshalled into its own encoder.
+#[derive(Default)]
+pub(crate) struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub(crate) fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub(crate) fn align(&mut self, alignment: usize) {
+        while !self.buf.len().is_multiple_of(alignment) {
+            self.buf.push(0);
+        }
+    }
+
+    pub(crate) fn byte(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub(crate) fn boolean(&mut self, value: bool) {
+        self.uint32(value as u32);
+    }
+
+    pub(crate) fn uint32(&mut self, value: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn uint64(&mut self, value: u64) {
+        self.align(8);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(crate) fn string(&mut self, value: &str) {
+        self.uint32(value.len() as u32);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    pub(crate) fn signature(&mut self, value: &str) {
+        self.buf.push(value.len() as u8);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    /// Marshal an array whose elements have the given alignment, with
+    /// `elements` writing each of them.
+    pub(crate) fn array(&mut self, element_alignment: usize, elements: impl FnOnce(&mut Encoder)) {
+        self.uint32(0);
+        let length_at = self.buf.len() - 4;
+        // Padding to the first element is not counted in the array length.
+        self.align(element_alignment);
+        let start = self.buf.len();
+        elements(self);
+        let length = (self.buf.len() - start) as u32;
+        self.buf[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    /// Marshal a struct, with `fields` writing its members.
+    pub(crate) fn structure(&mut self, fields: impl FnOnce(&mut Encoder)) {
+        self.align(8);
+        fields(self);
+    }
+
+    /// Marshal a variant holding a value of type `signature`, with `value`
+    /// writing it.
+    pub(crate) fn variant(&mut self, signature: &str, value: impl FnOnce(&mut Encoder)) {
+        self.signature(signature);
+        value(self);
+    }
+}
+
+/// Reads values from a received message, honouring its byte order.
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    little_endian: bool,
+}
+
+impl Decoder<'_> {
+    fn align(&mut self, alignment: usize) {
+        self.pos = self.pos.div_ceil(alignment) * alignment;
+    }
+
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow!("truncated D-Bus message"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uint32(&mut self) -> Result<u32> {
+        self.align(4);
+        let bytes: [u8; 4] = self.take(4)?.try_into()?;
+        Ok(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.uint32()? as usize;
+        let value = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.take(1)?;
+        Ok(value)
+    }
+
+    fn signature(&mut self) -> Result<String> {
+        let len = self.byte()? as usize;
+        let value = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.take(1)?;
+        Ok(value)
+    }
+}
+
+/// The parts of a received message we care about.
+struct Message {
+    kind: u8,
+    reply_serial: Option<u32>,
+    error_name: Option<String>,
+    signature: String,
+    little_endian: bool,
+    body: Vec<u8>,
+}
+
+impl Message {
+    /// The leading string argument of the body, if it has one. For errors,
+    /// this is the human-readable error message.
+    fn first_string(&self) -> Option<String> {
+        if !self.signature.starts_with('s') {
+            return None;
+        }
+
+        Decoder {
+            buf: &self.body,
+            pos: 0,
+            little_endian: self.little_endian,
+        }
+        .string()
+        .ok()
+    }
+}
+
+/// A connection to a message bus.
+pub(crate) struct Connection {
+    stream: UnixStream,
+    serial: u32,
+}
+
+impl Connection {
+    /// Connect to the system bus.
+    pub(crate) fn system() -> Result<Connection> {
+        let address = env::var("DBUS_SYSTEM_BUS_ADDRESS")
+            .unwrap_or("unix:path=/run/dbus/system_bus_socket".to_string());
+        Connection::open(&address)
+    }
+
+    /// Connect to the session bus of the calling user.
+    pub(crate) fn user() -> Result<Connection> {
+        let address = match env::var("DBUS_SESSION_BUS_ADDRESS") {
+            Ok(address) => address,
+            Err(_) => {
+                let runtime_dir = env::var("XDG_RUNTIME_DIR")
+                    .context("neither DBUS_SESSION_BUS_ADDRESS nor XDG_RUNTIME_DIR is set")?;
+                format!("unix:path={runtime_dir}/bus")
+            }
+        };
+        Connection::open(&address)
+    }
+
+    /// Connect to the first usable `unix:` transport in a D-Bus server
+    /// address, authenticate, and register with the bus.
+    pub(crate) fn open(address: &str) -> Result<Connection> {
+        let socket = address
+            .split(';')
+            .filter_map(|transport| transport.strip_prefix("unix:"))
+            .find_map(|params| {
+                params
+                    .split(',')
+                    .find_map(|param| match param.split_once('=') {
+                        Some(("path", path)) => SocketAddr::from_pathname(path).ok(),
+                        Some(("abstract", name)) => SocketAddr::from_abstract_name(name).ok(),
+                        _ => None,
+                    })
+            })
+            .ok_or_else(|| anyhow!("no usable unix transport in D-Bus address '{address}'"))?;
+
+        let stream = UnixStream::connect_addr(&socket)
+            .with_context(|| format!("failed to connect to D-Bus at '{address}'"))?;
+
+        let mut connection = Connection { stream, serial: 0 };
+        connection.authenticate()?;
+        connection.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+            "",
+            Vec::new(),
+        )?;
+
+        Ok(connection)
+    }
+
+    fn authenticate(&mut self) -> Result<()> {
+        let uid = unsafe { libc::geteuid() }.to_string();
+        let hex_uid: String = uid.bytes().map(|b| format!("{b:02x}")).collect();
+
+        self.stream.write_all(b"\0")?;
+        self.stream
+            .write_all(format!("AUTH EXTERNAL {hex_uid}\r\n").as_bytes())?;
+
+        let reply = self.read_line()?;
+        if !reply.starts_with("OK ") {
+            bail!("D-Bus authentication failed: {}", reply.trim());
+        }
+
+        self.stream.write_all(b"BEGIN\r\n")?;
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while !line.ends_with(b"\r\n") {
+            self.stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    /// Call a method and wait for its reply. `body` must already be
+    /// marshalled according to `signature`. D-Bus errors are returned as
+    /// errors carrying the error name and message.
+    pub(crate) fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        signature: &str,
+        body: Vec<u8>,
+    ) -> Result<()> {
+        self.serial += 1;
+        let serial = self.serial;
+
+        let mut header = Encoder::new();
+        header.byte(b'l');
+        header.byte(MESSAGE_TYPE_METHOD_CALL);
+        header.byte(MESSAGE_FLAG_NO_AUTO_START);
+        header.byte(1);
+        header.uint32(body.len() as u32);
+        header.uint32(serial);
+        header.array(8, |fields| {
+            let string_field = |fields: &mut Encoder, code, kind, value| {
+                fields.structure(|field| {
+                    field.byte(code);
+                    field.variant(kind, |v| v.string(value));
+                });
+            };
+            string_field(fields, HEADER_FIELD_PATH, "o", path);
+            string_field(fields, HEADER_FIELD_INTERFACE, "s", interface);
+            string_field(fields, HEADER_FIELD_MEMBER, "s", member);
+            string_field(fields, HEADER_FIELD_DESTINATION, "s", destination);
+            if !signature.is_empty() {
+                fields.structure(|field| {
+                    field.byte(HEADER_FIELD_SIGNATURE);
+                    field.variant("g", |v| v.signature(signature));
+                });
+            }
+        });
+        header.align(8);
+
+        let mut message = header.into_bytes();
+        message.extend(body);
+        self.stream.write_all(&message)?;
+
+        // Signals and replies to other calls may arrive first; skip them.
+        loop {
+            let reply = self.receive()?;
+            if reply.reply_serial != Some(serial) {
+                continue;
+            }
+
+            match reply.kind {
+                MESSAGE_TYPE_METHOD_RETURN => return Ok(()),
+                MESSAGE_TYPE_ERROR => bail!(
+                    "{member} failed: {}: {}",
+                    reply.error_name.as_deref().unwrap_or("unknown error"),
+                    reply.first_string().unwrap_or_default()
+                ),
+                kind => bail!("unexpected D-Bus message type {kind} in reply to {member}"),
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Result<Message> {
+        let mut fixed = [0u8; 16];
+        self.stream.read_exact(&mut fixed)?;
+
+        let little_endian = match fixed[0] {
+            b'l' => true,
+            b'B' => false,
+            other => bail!("invalid D-Bus byte order marker {other:#x}"),
+        };
+        let mut decoder = Decoder {
+            buf: &fixed,
+            pos: 4,
+            little_endian,
+        };
+        let body_len = decoder.uint32()? as usize;
+        decoder.uint32()?;
+        let fields_len = decoder.uint32()? as usize;
+
+        let mut rest = vec![0u8; fields_len.div_ceil(8) * 8 + body_len];
+        self.stream.read_exact(&mut rest)?;
+
+        let mut message = fixed.to_vec();
+        message.extend(rest);
+
+        let mut decoder = Decoder {
+            buf: &message[..16 + fields_len],
+            pos: 16,
+            little_endian,
+        };
+        let mut reply_serial = None;
+        let mut error_name = None;
+        let mut signature = String::new();
+        while decoder.pos < decoder.buf.len() {
+            decoder.align(8);
+            let code = decoder.byte()?;
+            let kind = decoder.signature()?;
+            match (code, kind.as_str()) {
+                (HEADER_FIELD_REPLY_SERIAL, "u") => reply_serial = Some(decoder.uint32()?),
+                (HEADER_FIELD_ERROR_NAME, "s") => error_name = Some(decoder.string()?),
+                (HEADER_FIELD_SIGNATURE, "g") => signature = decoder.signature()?,
+                (_, "s" | "o") => {
+                    decoder.string()?;
+                }
+                (_, "g") => {
+                    decoder.signature()?;
+                }
+                (_, "u") => {
+                    decoder.uint32()?;
+                }
+                (_, kind) => bail!("unexpected D-Bus header field type '{kind}'"),
+            }
+        }
+
+        let body = message.split_off(message.len() - body_len);
+
+        Ok(Message {
+            kind: fixed[1],
+            reply_serial,
+            error_name,
+            signature,
+            little_endian,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn array_length_excludes_leading_padding() {
+        let mut encoder = Encoder::new();
+        encoder.array(8, |elements| {
+            elements.structure(|element| element.uint64(7));
+        });
+        let bytes = encoder.into_bytes();
+        assert_eq!(&bytes[0..4], &8u32.to_le_bytes());
+        assert_eq!(bytes.len(), 16);
+    }
+
+    #[test]
+    fn variant_aligns_its_value() {
+        let mut encoder = Encoder::new();
+        encoder.variant("t", |v| v.uint64(1));
+        let bytes = encoder.into_bytes();
+        assert_eq!(&bytes[0..3], b"\x01t\0");
+        assert_eq!(bytes.len(), 16);
+    }
+}
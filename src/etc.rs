@@ -0,0 +1,235 @@
+//! Generated `/etc/hostname`, `/etc/hosts` and `/etc/resolv.conf`.
+//!
+//! Like other container engines, styrolite does not edit these files in the
+//! rootfs, which may be shared or read-only. Instead, they are rendered into a
+//! per-workload tmpfs and bind-mounted read-only over the rootfs paths.
+
+use std::fs;
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::config::{DnsConfig, HostEntry, MountSpec, Mountable};
+use crate::rootfs::Rootfs;
+
+const HEADER: &str = "# Generated by styrolite.\n";
+
+/// Where the host's resolver configuration is read from.
+pub const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Check that `word` can be written as a single field of a hosts or
+/// resolv.conf line.
+fn check_word(kind: &str, word: &str) -> Result<()> {
+    if word.is_empty() || word.contains(|c: char| c.is_whitespace() || c == '#' || c == ';') {
+        bail!("invalid {kind} '{word}'");
+    }
+    Ok(())
+}
+
+fn check_address(kind: &str, address: &str) -> Result<()> {
+    address
+        .parse::<IpAddr>()
+        .map(|_| ())
+        .map_err(|e| anyhow!("invalid {kind} '{address}': {e}"))
+}
+
+/// Render `/etc/hostname`.
+pub fn render_hostname(hostname: &str) -> Result<String> {
+    check_word("hostname", hostname)?;
+    Ok(format!("{hostname}\n"))
+}
+
+/// Render `/etc/hosts` with the localhost entries, an entry for `hostname`
+/// unless `entries` already contain one, and `entries`.
+pub fn render_hosts(hostname: &str, entries: &[HostEntry]) -> Result<String> {
+    check_word("hostname", hostname)?;
+
+    let mut hosts = String::from(HEADER);
+    hosts.push_str("127.0.0.1\tlocalhost\n");
+    hosts.push_str("::1\tlocalhost ip6-localhost ip6-loopback\n");
+
+    if !entries
+        .iter()
+        .any(|entry| entry.names.iter().any(|name| name == hostname))
+    {
+        hosts.push_str(&format!("127.0.1.1\t{hostname}\n"));
+    }
+
+    for entry in entries {
+        check_address("hosts address", &entry.address)?;
+        if entry.names.is_empty() {
+            bail!("hosts entry for {} has no names", entry.address);
+        }
+        for name in &entry.names {
+            check_word("host name", name)?;
+        }
+        hosts.push_str(&format!("{}\t{}\n", entry.address, entry.names.join(" ")));
+    }
+
+    Ok(hosts)
+}
+
+/// Render `/etc/resolv.conf` from `dns`, starting from `host`, the contents
+/// of the host's resolv.conf, if it is to be copied.
+pub fn render_resolv_conf(dns: &DnsConfig, host: Option<&str>) -> Result<String> {
+    for server in &dns.servers {
+        check_address("nameserver", server)?;
+    }
+    for domain in &dns.search {
+        check_word("search domain", domain)?;
+    }
+    for option in &dns.options {
+        check_word("resolver option", option)?;
+    }
+
+    let mut resolv = String::from(HEADER);
+
+    // Host lines are kept unless the request replaces them.
+    for line in host.unwrap_or_default().lines() {
+        let replaced = match line.split_whitespace().next() {
+            Some("nameserver") => !dns.servers.is_empty(),
+            Some("search" | "domain") => !dns.search.is_empty(),
+            Some("options") => !dns.options.is_empty(),
+            _ => false,
+        };
+        if !replaced {
+            resolv.push_str(line);
+            resolv.push('\n');
+        }
+    }
+
+    for server in &dns.servers {
+        resolv.push_str(&format!("nameserver {server}\n"));
+    }
+    if !dns.search.is_empty() {
+        resolv.push_str(&format!("search {}\n", dns.search.join(" ")));
+    }
+    if !dns.options.is_empty() {
+        resolv.push_str(&format!("options {}\n", dns.options.join(" ")));
+    }
+
+    Ok(resolv)
+}
+
+/// Write `files`, pairs of an `/etc` file name and its contents, into a
+/// tmpfs mounted at `dir`, and bind-mount each read-only over the same path
+/// in `rootfs`. The mountpoint is created if missing, so `dir` must already be
+/// on a tmpfs of the container's own.
+pub fn mount_etc_files(rootfs: &Rootfs, dir: &str, files: &[(&str, String)]) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let tmpfs = MountSpec {
+        source: Some("tmpfs".to_string()),
+        target: dir.to_string(),
+        fstype: Some("tmpfs".to_string()),
+        safe: true,
+        create_mountpoint: true,
+        data: Some("mode=755,size=1m".to_string()),
+        ..Default::default()
+    };
+    tmpfs
+        .mount()
+        .map_err(|e| anyhow!("failed to mount tmpfs for /etc files: {e}"))?;
+
+    for (name, contents) in files {
+        let source = format!("{dir}/{name}");
+        fs::write(&source, contents)
+            .and_then(|_| fs::set_permissions(&source, fs::Permissions::from_mode(0o644)))
+            .map_err(|e| anyhow!("failed to write {source}: {e}"))?;
+
+        let bind = MountSpec {
+            source: Some(source),
+            target: format!("/etc/{name}"),
+            bind: true,
+            safe: true,
+            create_mountpoint: true,
+            read_only: true,
+            ..Default::default()
+        };
+        bind.mount_in(rootfs)
+            .map_err(|e| anyhow!("failed to mount /etc/{name}: {e}"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_contain_localhost_and_the_hostname() {
+        let hosts = render_hosts(
+            "box",
+            &[HostEntry {
+                address: "10.0.0.2".to_string(),
+                names: vec!["db".to_string(), "db.internal".to_string()],
+            }],
+        )
+        .unwrap();
+        assert_eq!(
+            hosts,
+            "# Generated by styrolite.\n\
+             127.0.0.1\tlocalhost\n\
+             ::1\tlocalhost ip6-localhost ip6-loopback\n\
+             127.0.1.1\tbox\n\
+             10.0.0.2\tdb db.internal\n"
+        );
+
+        let hosts = render_hosts(
+            "box",
+            &[HostEntry {
+                address: "fd00::2".to_string(),
+                names: vec!["box".to_string()],
+            }],
+        )
+        .unwrap();
+        assert!(!hosts.contains("127.0.1.1"));
+        assert!(hosts.ends_with("fd00::2\tbox\n"));
+
+        let bad = HostEntry {
+            address: "10.0.0.300".to_string(),
+            names: vec!["db".to_string()],
+        };
+        assert!(render_hosts("box", &[bad]).is_err());
+        assert!(render_hosts("two words", &[]).is_err());
+    }
+
+    #[test]
+    fn copied_resolv_conf_lines_are_replaced_per_keyword() {
+        let host = "# from dhcp\nnameserver 192.0.2.1\ndomain lan\noptions edns0\n";
+        let dns = DnsConfig {
+            servers: vec!["1.1.1.1".to_string(), "2606:4700:4700::1111".to_string()],
+            search: vec!["svc.local".to_string()],
+            copy_from_host: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            render_resolv_conf(&dns, Some(host)).unwrap(),
+            "# Generated by styrolite.\n\
+             # from dhcp\n\
+             options edns0\n\
+             nameserver 1.1.1.1\n\
+             nameserver 2606:4700:4700::1111\n\
+             search svc.local\n"
+        );
+
+        let dns = DnsConfig {
+            options: vec!["ndots:2".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            render_resolv_conf(&dns, None).unwrap(),
+            "# Generated by styrolite.\noptions ndots:2\n"
+        );
+
+        let dns = DnsConfig {
+            servers: vec!["dns.example".to_string()],
+            ..Default::default()
+        };
+        assert!(render_resolv_conf(&dns, None).is_err());
+    }
+}
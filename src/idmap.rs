@@ -0,0 +1,428 @@
+//! Rootless user namespace id mapping.
+//!
+//! An unprivileged process may only write a `uid_map`/`gid_map` mapping its
+//! own single id. To give a rootless container a full range of ids, the
+//! subordinate ranges delegated to the user in `/etc/subuid` and
+//! `/etc/subgid` are mapped through the setuid `newuidmap(1)` and
+//! `newgidmap(1)` helpers from shadow-utils, which check the requested
+//! mappings against those files.
+//!
+//! The helpers have to run with the host's view of the filesystem (they read
+//! `/etc/subuid` and `/proc/<pid>` relative to their root), but by the time
+//! the mappings can be written, the supervisor may already share the
+//! workload's pivoted mount namespace or have joined its user namespace. So
+//! an [`IdMapHelper`] process is forked before any namespaces are unshared,
+//! and runs the helpers on the supervisor's behalf once it is told the
+//! workload's pid.
+//!
+//! Idmapped mounts also need a user namespace carrying the mappings to
+//! apply, which [`userns_fd`] creates on demand.
+
+use std::ffi::CStr;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::OwnedFd;
+use std::process::{self, Command};
+
+use anyhow::{Result, anyhow, bail};
+use log::debug;
+use nix::sys::wait::waitpid;
+use nix::unistd::{ForkResult, Pid, fork};
+
+use crate::config::IdMapping;
+use crate::namespace::Namespace;
+use crate::unshare::unshare;
+
+/// The number of ids a rootless container gets, including the caller's own
+/// id mapped to root.
+pub const ROOTLESS_ID_COUNT: u32 = 65536;
+
+/// Parse the subordinate id ranges delegated to a user from the contents of
+/// `/etc/subuid` or `/etc/subgid`. Entries may name the user either by name
+/// or by numeric uid.
+fn parse_subordinate_ranges(contents: &str, user: &str, uid: u32) -> Vec<(u32, u32)> {
+    let uid = uid.to_string();
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let owner = fields.next()?;
+            let start = fields.next()?.parse().ok()?;
+            let count = fields.next()?.parse().ok()?;
+            (owner == user || owner == uid).then_some((start, count))
+        })
+        .collect()
+}
+
+/// Build mappings which map root in the container to `id`, followed by the
+/// subordinate `ranges`, up to [`ROOTLESS_ID_COUNT`] ids in total.
+fn build_rootless_mappings(id: u32, ranges: &[(u32, u32)]) -> Vec<IdMapping> {
+    let mut mappings = vec![IdMapping {
+        base_nsid: 0,
+        base_hostid: id,
+        remap_count: 1,
+    }];
+
+    let mut next_nsid = 1;
+    for &(start, count) in ranges {
+        let count = count.min(ROOTLESS_ID_COUNT - next_nsid);
+        if count == 0 {
+            break;
+        }
+
+        mappings.push(IdMapping {
+            base_nsid: next_nsid,
+            base_hostid: start,
+            remap_count: count,
+        });
+        next_nsid += count;
+    }
+
+    mappings
+}
+
+/// The login name of `uid`, from the password database.
+fn user_name(uid: libc::uid_t) -> Result<String> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    let rc = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if rc != 0 {
+        bail!(
+            "failed to look up user {uid}: {}",
+            io::Error::from_raw_os_error(rc)
+        );
+    }
+    if result.is_null() {
+        bail!("user {uid} has no password database entry");
+    }
+
+    Ok(unsafe { CStr::from_ptr(pwd.pw_name) }
+        .to_string_lossy()
+        .into_owned())
+}
+
+fn rootless_mappings_from(path: &str, user: &str, uid: u32, id: u32) -> Result<Vec<IdMapping>> {
+    let contents = fs::read_to_string(path).map_err(|e| anyhow!("failed to read {path}: {e}"))?;
+    let ranges = parse_subordinate_ranges(&contents, user, uid);
+    if ranges.is_empty() {
+        bail!("no subordinate ids are delegated to user '{user}' in {path}");
+    }
+
+    Ok(build_rootless_mappings(id, &ranges))
+}
+
+/// Build the uid and gid mappings for a rootless container owned by the
+/// calling user: root in the container maps to the caller, and ids from 1
+/// upwards map to the caller's subordinate ranges. The mappings must be
+/// applied with `CreateRequest::id_map_helpers` set.
+pub fn rootless_mappings() -> Result<(Vec<IdMapping>, Vec<IdMapping>)> {
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let user = user_name(uid)?;
+
+    let uid_mappings = rootless_mappings_from("/etc/subuid", &user, uid, uid)?;
+    let gid_mappings = rootless_mappings_from("/etc/subgid", &user, uid, gid)?;
+
+    Ok((uid_mappings, gid_mappings))
+}
+
+/// Render mappings in the format expected by `/proc/<pid>/uid_map` and
+/// `/proc/<pid>/gid_map`.
+pub(crate) fn render_mappings(mappings: &[IdMapping]) -> String {
+    mappings
+        .iter()
+        .map(|mapping| {
+            format!(
+                "{} {} {}",
+                mapping.base_nsid, mapping.base_hostid, mapping.remap_count
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Translates ids inside a user namespace into the ids outside of it, for
+/// changing file ownership from outside of the container's user namespace.
+/// Without mappings, ids are left as they are.
+#[derive(Clone, Debug, Default)]
+pub struct IdTranslator {
+    uid_mappings: Vec<IdMapping>,
+    gid_mappings: Vec<IdMapping>,
+}
+
+impl IdTranslator {
+    pub fn new(uid_mappings: &[IdMapping], gid_mappings: &[IdMapping]) -> IdTranslator {
+        IdTranslator {
+            uid_mappings: uid_mappings.to_vec(),
+            gid_mappings: gid_mappings.to_vec(),
+        }
+    }
+
+    fn translate(mappings: &[IdMapping], id: u32) -> Option<u32> {
+        if mappings.is_empty() {
+            return Some(id);
+        }
+
+        mappings.iter().find_map(|m| {
+            let offset = id.checked_sub(m.base_nsid)?;
+            (offset < m.remap_count).then(|| m.base_hostid + offset)
+        })
+    }
+
+    pub fn uid(&self, uid: u32) -> Result<u32> {
+        Self::translate(&self.uid_mappings, uid)
+            .ok_or_else(|| anyhow!("uid {uid} is not mapped into the container"))
+    }
+
+    pub fn gid(&self, gid: u32) -> Result<u32> {
+        Self::translate(&self.gid_mappings, gid)
+            .ok_or_else(|| anyhow!("gid {gid} is not mapped into the container"))
+    }
+}
+
+/// Create a user namespace with the given mappings and return a file
+/// descriptor referring to it, e.g. for `MOUNT_ATTR_IDMAP`. The namespace is
+/// created by a short-lived child, which exits once the descriptor has been
+/// opened; the descriptor alone keeps the namespace alive.
+///
+/// The caller needs `CAP_SETUID` and `CAP_SETGID` over the ids being mapped,
+/// and a `/proc` which shows the child.
+pub fn userns_fd(uid_mappings: &[IdMapping], gid_mappings: &[IdMapping]) -> Result<OwnedFd> {
+    let (ready_rx, mut ready_tx) = io::pipe()?;
+    let (mut release_rx, release_tx) = io::pipe()?;
+
+    let child = match unsafe { fork() }? {
+        ForkResult::Parent { child } => child,
+        ForkResult::Child => {
+            drop(ready_rx);
+            drop(release_tx);
+
+            // Report our pid as seen by /proc, which is not necessarily the
+            // pid fork() returned if we are in a child pid namespace.
+            let code = match unshare(&[Namespace::User])
+                .and_then(|_| Ok(fs::read_link("/proc/self")?))
+                .and_then(|pid| Ok(writeln!(ready_tx, "{}", pid.display())?))
+            {
+                Ok(()) => {
+                    let _ = release_rx.read(&mut [0]);
+                    0
+                }
+                Err(_) => 1,
+            };
+            process::exit(code)
+        }
+    };
+
+    drop(ready_tx);
+    drop(release_rx);
+
+    let result = (|| {
+        let mut pid = String::new();
+        BufReader::new(ready_rx).read_line(&mut pid)?;
+        let pid = pid.trim();
+        if pid.is_empty() {
+            bail!("failed to create a user namespace for id mapping");
+        }
+
+        fs::write(
+            format!("/proc/{pid}/uid_map"),
+            render_mappings(uid_mappings),
+        )
+        .map_err(|e| anyhow!("failed to write uid map: {e}"))?;
+        fs::write(
+            format!("/proc/{pid}/gid_map"),
+            render_mappings(gid_mappings),
+        )
+        .map_err(|e| anyhow!("failed to write gid map: {e}"))?;
+
+        let userns = fs::File::open(format!("/proc/{pid}/ns/user"))?;
+        Ok(OwnedFd::from(userns))
+    })();
+
+    drop(release_tx);
+    waitpid(child, None)?;
+
+    result
+}
+
+fn helper_arguments(pid: libc::pid_t, mappings: &[IdMapping]) -> Vec<String> {
+    let mut args = vec![pid.to_string()];
+    for mapping in mappings {
+        args.push(mapping.base_nsid.to_string());
+        args.push(mapping.base_hostid.to_string());
+        args.push(mapping.remap_count.to_string());
+    }
+    args
+}
+
+fn run_helper(helper: &str, pid: libc::pid_t, mappings: &[IdMapping]) -> Result<()> {
+    let output = Command::new(helper)
+        .args(helper_arguments(pid, mappings))
+        .output()
+        .map_err(|e| anyhow!("failed to run {helper}: {e}"))?;
+
+    if !output.status.success() {
+        bail!(
+            "{helper} failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// A process, forked before any namespaces are unshared, which applies id
+/// mappings with `newuidmap(1)` and `newgidmap(1)` once given the pid of the
+/// process owning the target user namespace.
+pub struct IdMapHelper {
+    pid: Pid,
+    request: io::PipeWriter,
+    response: io::PipeReader,
+}
+
+impl IdMapHelper {
+    /// Fork the helper process.
+    pub fn spawn(
+        uid_mappings: Option<&[IdMapping]>,
+        gid_mappings: Option<&[IdMapping]>,
+    ) -> Result<IdMapHelper> {
+        let (mut request_rx, request) = io::pipe()?;
+        let (response, mut response_tx) = io::pipe()?;
+
+        match unsafe { fork() }? {
+            ForkResult::Parent { child } => {
+                drop(request_rx);
+                drop(response_tx);
+
+                debug!("id map helper pid = {child}");
+                Ok(IdMapHelper {
+                    pid: child,
+                    request,
+                    response,
+                })
+            }
+            ForkResult::Child => {
+                drop(request);
+                drop(response);
+
+                // The supervisor sends the target pid as a single line. Its
+                // end of the pipe is also inherited by the workload, so EOF
+                // can't be used to delimit the request.
+                let mut target = String::new();
+                let result = BufReader::new(&mut request_rx)
+                    .read_line(&mut target)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| Ok(target.trim().parse::<libc::pid_t>()?))
+                    .and_then(|pid| {
+                        if let Some(mappings) = uid_mappings {
+                            run_helper("newuidmap", pid, mappings)?;
+                        }
+                        if let Some(mappings) = gid_mappings {
+                            run_helper("newgidmap", pid, mappings)?;
+                        }
+                        Ok(())
+                    });
+
+                let code = match result {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        let _ = response_tx.write_all(e.to_string().as_bytes());
+                        1
+                    }
+                };
+                process::exit(code)
+            }
+        }
+    }
+
+    /// Apply the mappings to the user namespace of `pid`, a pid in the
+    /// helper's (the host's) pid namespace.
+    pub fn map(self, pid: libc::pid_t) -> Result<()> {
+        let IdMapHelper {
+            pid: helper,
+            mut request,
+            mut response,
+        } = self;
+
+        request.write_all(format!("{pid}\n").as_bytes())?;
+        drop(request);
+
+        let mut error = String::new();
+        response.read_to_string(&mut error)?;
+        waitpid(helper, None)?;
+
+        if !error.is_empty() {
+            bail!("failed to map ids for pid {pid}: {error}");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subordinate_ranges_match_name_or_uid() {
+        let contents = "\
+# comment
+alice:100000:65536
+1000:300000:10
+bob:200000:65536
+";
+        assert_eq!(
+            parse_subordinate_ranges(contents, "alice", 1000),
+            vec![(100000, 65536), (300000, 10)]
+        );
+        assert_eq!(
+            parse_subordinate_ranges(contents, "carol", 1001),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn rootless_mappings_are_capped_at_full_range() {
+        let mappings = build_rootless_mappings(1000, &[(100000, 65536), (300000, 10)]);
+        let rendered: Vec<_> = mappings
+            .iter()
+            .map(|m| (m.base_nsid, m.base_hostid, m.remap_count))
+            .collect();
+        assert_eq!(rendered, vec![(0, 1000, 1), (1, 100000, 65535)]);
+
+        let mappings = build_rootless_mappings(1000, &[(100000, 100), (300000, 10)]);
+        let rendered: Vec<_> = mappings
+            .iter()
+            .map(|m| (m.base_nsid, m.base_hostid, m.remap_count))
+            .collect();
+        assert_eq!(
+            rendered,
+            vec![(0, 1000, 1), (1, 100000, 100), (101, 300000, 10)]
+        );
+    }
+
+    #[test]
+    fn translator_maps_container_ids_to_host_ids() {
+        let mapping = |base_nsid, base_hostid, remap_count| IdMapping {
+            base_nsid,
+            base_hostid,
+            remap_count,
+        };
+        let ids = IdTranslator::new(
+            &[mapping(0, 1000, 1), mapping(1, 100000, 65535)],
+            &[mapping(0, 2000, 1)],
+        );
+
+        assert_eq!(ids.uid(0).unwrap(), 1000);
+        assert_eq!(ids.uid(33).unwrap(), 100032);
+        assert!(ids.uid(65536).is_err());
+        assert_eq!(ids.gid(0).unwrap(), 2000);
+        assert!(ids.gid(1).is_err());
+        assert_eq!(IdTranslator::default().uid(42).unwrap(), 42);
+    }
+}
@@ -0,0 +1,1207 @@
+//! OCI image unpacking.
+//!
+//! An [`Image`] is opened from a local OCI image layout directory (as
+//! produced by e.g. `skopeo copy ... oci:DIR`) or from a `docker save`
+//! tarball, in either the legacy format or the OCI layout format written by
+//! newer Docker releases. The manifest for the host platform is resolved,
+//! and [`Image::unpack`] extracts its layers in order into a rootfs
+//! directory, applying whiteouts.
+//!
+//! Every blob is verified against its digest before use, and uncompressed
+//! layers against the image config's `diff_ids`. Layer entries are created
+//! relative to a directory file descriptor with `openat2(RESOLVE_IN_ROOT)`,
+//! so neither `..` components nor symlinks in the image can make extraction
+//! escape the rootfs: they are resolved as if the rootfs was `/`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Result, anyhow, bail};
+use flate2::read::MultiGzDecoder;
+use log::{debug, warn};
+use ruzstd::decoding::errors::{FrameDecoderError, ReadFrameHeaderError};
+use ruzstd::decoding::{BlockDecodingStrategy, FrameDecoder};
+use serde::Deserialize;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::config::ExecutableSpec;
+use crate::rootfs::{check, ensure_dir, fd_path, lstat_at, open_in_root, remove_at};
+
+const INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: Option<String>,
+    digest: String,
+    size: Option<u64>,
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+/// An entry in the `manifest.json` of a legacy `docker save` tarball.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    layers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    config: Option<ImageConfig>,
+    #[serde(default)]
+    rootfs: Option<RootFs>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RootFs {
+    #[serde(default)]
+    diff_ids: Vec<String>,
+}
+
+/// The runtime configuration recorded in an image.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageConfig {
+    /// The command prefix which `cmd` is appended to.
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+
+    /// The default command, or arguments to the entrypoint.
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+
+    /// Environment variables, as `KEY=value` strings.
+    #[serde(default)]
+    pub env: Option<Vec<String>>,
+
+    /// The working directory of the initial process.
+    #[serde(default)]
+    pub working_dir: Option<String>,
+
+    /// The user (and optionally group) to run as, by name or by id.
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+/// Where the blobs of an image are read from.
+enum Source {
+    Directory(PathBuf),
+
+    /// A tarball, with the offset and size of every file in it.
+    Archive {
+        path: PathBuf,
+        files: HashMap<String, (u64, u64)>,
+    },
+}
+
+impl Source {
+    fn open_archive(path: &Path) -> Result<Source> {
+        let mut archive = tar::Archive::new(File::open(path)?);
+        let mut files = HashMap::new();
+        let mut links = Vec::new();
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let name = normalize(&entry.path()?).to_string_lossy().into_owned();
+            match entry.header().entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    files.insert(name, (entry.raw_file_position(), entry.size()));
+                }
+                // Layers shared between images are stored once and linked.
+                kind @ (tar::EntryType::Symlink | tar::EntryType::Link) => {
+                    if let Some(target) = entry.link_name()? {
+                        let target = if kind == tar::EntryType::Symlink {
+                            Path::new(&name)
+                                .parent()
+                                .unwrap_or(Path::new(""))
+                                .join(target)
+                        } else {
+                            target.into_owned()
+                        };
+                        links.push((name, normalize(&target).to_string_lossy().into_owned()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (name, target) in links {
+            if let Some(&file) = files.get(&target) {
+                files.insert(name, file);
+            }
+        }
+
+        Ok(Source::Archive {
+            path: path.to_path_buf(),
+            files,
+        })
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        match self {
+            Source::Directory(dir) => dir.join(name).is_file(),
+            Source::Archive { files, .. } => files.contains_key(name),
+        }
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn Read>> {
+        match self {
+            Source::Directory(dir) => {
+                let path = dir.join(name);
+                let file = File::open(&path)
+                    .map_err(|e| anyhow!("failed to open {}: {e}", path.display()))?;
+                Ok(Box::new(file))
+            }
+            Source::Archive { path, files } => {
+                let &(offset, size) = files
+                    .get(name)
+                    .ok_or_else(|| anyhow!("{} does not contain {name}", path.display()))?;
+                let mut file = File::open(path)?;
+                io::Seek::seek(&mut file, io::SeekFrom::Start(offset))?;
+                Ok(Box::new(file.take(size)))
+            }
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.open(name)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+}
+
+/// A hash function named by a digest's algorithm.
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn new(algorithm: &str) -> Result<Hasher> {
+        match algorithm {
+            "sha256" => Ok(Hasher::Sha256(Sha256::new())),
+            "sha512" => Ok(Hasher::Sha512(Sha512::new())),
+            _ => bail!("unsupported digest algorithm {algorithm}"),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(h) => format!("sha256:{:x}", h.finalize()),
+            Hasher::Sha512(h) => format!("sha512:{:x}", h.finalize()),
+        }
+    }
+}
+
+/// A reader which hashes everything read through it, to be checked against
+/// `digest` once it is exhausted. Without a digest, it reads through.
+struct DigestReader<'a, R> {
+    inner: R,
+    digest: Option<&'a str>,
+    hasher: Option<Hasher>,
+}
+
+impl<'a, R: Read> DigestReader<'a, R> {
+    fn new(inner: R, digest: Option<&'a str>) -> Result<Self> {
+        let hasher = digest
+            .map(|digest| Hasher::new(parse_digest(digest)?.0))
+            .transpose()?;
+        Ok(DigestReader {
+            inner,
+            digest,
+            hasher,
+        })
+    }
+
+    /// Read the rest of the stream, and check it against the digest.
+    fn verify(mut self, what: &str) -> Result<()> {
+        io::copy(&mut self, &mut io::sink())?;
+        match (self.digest, self.hasher) {
+            (Some(digest), Some(hasher)) => verify_digest(what, digest, hasher.finalize()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> Read for DigestReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Split a digest into its algorithm and hex encoded value, making sure it
+/// is safe to use as a path component.
+fn parse_digest(digest: &str) -> Result<(&str, &str)> {
+    let (algorithm, encoded) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("malformed digest {digest:?}"))?;
+
+    let algorithm_ok = !algorithm.is_empty()
+        && algorithm
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"+._-".contains(&b));
+    let encoded_ok = !encoded.is_empty() && encoded.bytes().all(|b| b.is_ascii_hexdigit());
+    if !algorithm_ok || !encoded_ok {
+        bail!("malformed digest {digest:?}");
+    }
+
+    Ok((algorithm, encoded))
+}
+
+fn blob_path(digest: &str) -> Result<String> {
+    let (algorithm, encoded) = parse_digest(digest)?;
+    Ok(format!("blobs/{algorithm}/{encoded}"))
+}
+
+fn verify_digest(what: &str, expected: &str, actual: String) -> Result<()> {
+    if actual != expected {
+        bail!("digest mismatch for {what}: expected {expected}, got {actual}");
+    }
+    Ok(())
+}
+
+/// Hash the rest of `reader` and check it against `digest`.
+fn verify_stream(what: &str, digest: &str, reader: impl Read) -> Result<()> {
+    DigestReader::new(reader, Some(digest))?.verify(what)
+}
+
+/// The OCI name of the host's architecture.
+fn host_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+        "loongarch64" => "loong64",
+        arch => arch,
+    }
+}
+
+/// Pick the descriptor for the host platform. A lone descriptor without a
+/// platform is taken as is.
+fn select_platform(descriptors: &[Descriptor]) -> Result<&Descriptor> {
+    let architecture = host_architecture();
+
+    if let Some(descriptor) = descriptors.iter().find(|d| {
+        d.platform
+            .as_ref()
+            .is_some_and(|p| p.os == "linux" && p.architecture == architecture)
+    }) {
+        return Ok(descriptor);
+    }
+
+    match descriptors {
+        [descriptor] if descriptor.platform.is_none() => Ok(descriptor),
+        _ => bail!("image has no manifest for linux/{architecture}"),
+    }
+}
+
+/// Decodes a stream of concatenated zstd frames, as written by parallel
+/// compressors, skipping any skippable frames.
+struct ZstdReader<R> {
+    source: R,
+    decoder: FrameDecoder,
+    in_frame: bool,
+}
+
+impl<R: BufRead> Read for ZstdReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.in_frame {
+                if self.source.fill_buf()?.is_empty() {
+                    return Ok(0);
+                }
+                match self.decoder.reset(&mut self.source) {
+                    Ok(()) => self.in_frame = true,
+                    Err(FrameDecoderError::ReadFrameHeaderError(
+                        ReadFrameHeaderError::SkipFrame { length, .. },
+                    )) => {
+                        io::copy(&mut (&mut self.source).take(length.into()), &mut io::sink())?;
+                        continue;
+                    }
+                    Err(e) => return Err(io::Error::other(e)),
+                }
+            }
+
+            while self.decoder.can_collect() < buf.len() && !self.decoder.is_finished() {
+                let wanted = buf.len() - self.decoder.can_collect();
+                self.decoder
+                    .decode_blocks(&mut self.source, BlockDecodingStrategy::UptoBytes(wanted))
+                    .map_err(io::Error::other)?;
+            }
+
+            let n = self.decoder.read(buf)?;
+            if n == 0 && self.decoder.is_finished() {
+                self.in_frame = false;
+                continue;
+            }
+            return Ok(n);
+        }
+    }
+}
+
+/// Wrap a layer blob in the decompressor its magic number calls for.
+fn decompress<'a>(reader: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?;
+
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Ok(Box::new(ZstdReader {
+            source: reader,
+            decoder: FrameDecoder::new(),
+            in_frame: false,
+        }))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Remove everything in the directory `dir`.
+fn clear_dir(dir: &OwnedFd) -> io::Result<()> {
+    for entry in fs::read_dir(fd_path(dir))? {
+        remove_at(dir, &entry?.file_name())?;
+    }
+    Ok(())
+}
+
+/// Normalize an archive path to a relative path, resolving `..` lexically
+/// without going above the root.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized
+}
+
+fn cstring(name: &OsStr) -> io::Result<CString> {
+    Ok(CString::new(name.as_bytes())?)
+}
+
+fn is_dir(st: &libc::stat) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+/// Apply a `.wh.<name>` whiteout for `path`.
+fn apply_whiteout(root: &OwnedFd, path: &Path) -> io::Result<()> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(());
+    };
+    match open_in_root(root, parent, libc::O_PATH | libc::O_DIRECTORY) {
+        Ok(dir) => remove_at(&dir, name),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Apply an opaque whiteout for the directory at `path`: remove everything
+/// in it which did not come from the current layer.
+fn apply_opaque_whiteout(root: &OwnedFd, path: &Path, layer: &HashSet<PathBuf>) -> io::Result<()> {
+    let dir = match open_in_root(root, path, libc::O_PATH | libc::O_DIRECTORY) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for child in fs::read_dir(format!("/proc/self/fd/{}", dir.as_raw_fd()))? {
+        let name = child?.file_name();
+        let child = path.join(&name);
+
+        if layer.iter().any(|p| p.starts_with(&child)) {
+            if lstat_at(&dir, &name)?.is_some_and(|st| is_dir(&st)) {
+                apply_opaque_whiteout(root, &child, layer)?;
+            }
+        } else {
+            remove_at(&dir, &name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Ignore errors from ownership and xattr changes we may not be privileged
+/// enough to make, e.g. in a user namespace.
+fn tolerate_eperm(what: &str, path: &Path, result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EPERM | libc::EINVAL)) => {
+            debug!("unable to {what} {}: {e}", path.display());
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Extract a single layer entry at `path`.
+fn extract_entry<R: Read>(
+    root: &OwnedFd,
+    path: &Path,
+    entry: &mut tar::Entry<'_, R>,
+) -> Result<()> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(());
+    };
+    let dir = ensure_dir(root, parent)?;
+    let c_name = cstring(name)?;
+    let header = entry.header().clone();
+    let kind = header.entry_type();
+    let mode = header.mode()? & 0o7777;
+
+    let xattrs: Vec<(String, Vec<u8>)> = match entry.pax_extensions()? {
+        Some(extensions) => extensions
+            .filter_map(|ext| ext.ok())
+            .filter_map(|ext| {
+                let key = ext.key().ok()?.strip_prefix("SCHILY.xattr.")?;
+                Some((key.to_string(), ext.value_bytes().to_vec()))
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Replace whatever a lower layer had here, except that directories are
+    // merged.
+    let existing = lstat_at(&dir, name)?;
+    if !(kind == tar::EntryType::Directory && existing.is_some_and(|st| is_dir(&st))) {
+        remove_at(&dir, name)?;
+    }
+
+    match kind {
+        tar::EntryType::Directory => {
+            if unsafe { libc::mkdirat(dir.as_raw_fd(), c_name.as_ptr(), 0o700) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(err.into());
+                }
+            }
+        }
+        tar::EntryType::Regular | tar::EntryType::Continuous => {
+            let fd = unsafe {
+                libc::openat(
+                    dir.as_raw_fd(),
+                    c_name.as_ptr(),
+                    libc::O_WRONLY
+                        | libc::O_CREAT
+                        | libc::O_EXCL
+                        | libc::O_NOFOLLOW
+                        | libc::O_CLOEXEC,
+                    0o600,
+                )
+            };
+            check(fd)?;
+            let mut file = unsafe { File::from_raw_fd(fd) };
+            io::copy(entry, &mut file)?;
+        }
+        tar::EntryType::Symlink => {
+            let target = entry
+                .link_name()?
+                .ok_or_else(|| anyhow!("symlink {} has no target", path.display()))?;
+            let c_target = cstring(target.as_os_str())?;
+            check(unsafe { libc::symlinkat(c_target.as_ptr(), dir.as_raw_fd(), c_name.as_ptr()) })?;
+        }
+        tar::EntryType::Link => {
+            let target = normalize(
+                &entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("hard link {} has no target", path.display()))?,
+            );
+            let (Some(target_parent), Some(target_name)) = (target.parent(), target.file_name())
+            else {
+                bail!("hard link {} has an invalid target", path.display());
+            };
+            let target_dir = open_in_root(root, target_parent, libc::O_PATH | libc::O_DIRECTORY)?;
+            let c_target_name = cstring(target_name)?;
+            check(unsafe {
+                libc::linkat(
+                    target_dir.as_raw_fd(),
+                    c_target_name.as_ptr(),
+                    dir.as_raw_fd(),
+                    c_name.as_ptr(),
+                    0,
+                )
+            })?;
+            // The link shares the target's inode and metadata.
+            return Ok(());
+        }
+        tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+            let file_type = match kind {
+                tar::EntryType::Char => libc::S_IFCHR,
+                tar::EntryType::Block => libc::S_IFBLK,
+                _ => libc::S_IFIFO,
+            };
+            let dev = libc::makedev(
+                header.device_major()?.unwrap_or(0),
+                header.device_minor()?.unwrap_or(0),
+            );
+            if unsafe { libc::mknodat(dir.as_raw_fd(), c_name.as_ptr(), file_type | mode, dev) } < 0
+            {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EPERM) && kind != tar::EntryType::Fifo {
+                    warn!("skipping device node {}: {err}", path.display());
+                    return Ok(());
+                }
+                return Err(err.into());
+            }
+        }
+        tar::EntryType::GNUSparse => {
+            bail!("sparse file {} is not supported", path.display());
+        }
+        other => {
+            debug!("skipping {} of unsupported type {other:?}", path.display());
+            return Ok(());
+        }
+    }
+
+    tolerate_eperm(
+        "chown",
+        path,
+        check(unsafe {
+            libc::fchownat(
+                dir.as_raw_fd(),
+                c_name.as_ptr(),
+                header.uid()? as libc::uid_t,
+                header.gid()? as libc::gid_t,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        }),
+    )?;
+
+    // Symlink permissions are meaningless, and chmod would follow them.
+    if kind != tar::EntryType::Symlink {
+        check(unsafe { libc::fchmodat(dir.as_raw_fd(), c_name.as_ptr(), mode, 0) })?;
+    }
+
+    for (key, value) in xattrs {
+        let c_path = cstring(Path::new(&fd_path(&dir)).join(name).as_os_str())?;
+        let c_key = CString::new(key)?;
+        tolerate_eperm(
+            "set xattrs on",
+            path,
+            check(unsafe {
+                libc::lsetxattr(
+                    c_path.as_ptr(),
+                    c_key.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    0,
+                )
+            }),
+        )?;
+    }
+
+    let mtime = libc::timespec {
+        tv_sec: header.mtime()? as libc::time_t,
+        tv_nsec: 0,
+    };
+    check(unsafe {
+        libc::utimensat(
+            dir.as_raw_fd(),
+            c_name.as_ptr(),
+            [mtime, mtime].as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Extract an uncompressed layer tarball on top of `root`.
+fn apply_layer(root: &OwnedFd, layer: impl Read) -> Result<()> {
+    let mut archive = tar::Archive::new(layer);
+    let mut created = HashSet::new();
+    let mut opaque = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = normalize(&entry.path()?);
+        let Some(name) = path.file_name().map(OsStrExt::as_bytes) else {
+            // The root directory itself.
+            continue;
+        };
+        let parent = path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+        if name == OPAQUE_WHITEOUT.as_bytes() {
+            opaque.push(parent);
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+            apply_whiteout(root, &parent.join(OsStr::from_bytes(hidden)))?;
+        } else {
+            extract_entry(root, &path, &mut entry)
+                .map_err(|e| anyhow!("failed to extract {}: {e}", path.display()))?;
+            created.insert(path);
+        }
+    }
+
+    // Opaque whiteouts only hide lower layers, so they are applied once
+    // everything in this layer is known.
+    for dir in opaque {
+        apply_opaque_whiteout(root, &dir, &created)?;
+    }
+
+    Ok(())
+}
+
+struct Layer {
+    /// The path of the layer blob within the image source.
+    path: String,
+
+    /// The digest of the (possibly compressed) blob.
+    digest: Option<String>,
+
+    /// The digest of the uncompressed layer tarball.
+    diff_id: Option<String>,
+}
+
+/// An OCI image which can be unpacked into a rootfs.
+pub struct Image {
+    source: Source,
+    layers: Vec<Layer>,
+    config: ImageConfig,
+}
+
+impl Image {
+    /// Open an OCI image layout directory or a `docker save` tarball at
+    /// `path`, resolving the manifest for the host platform.
+    pub fn open(path: impl AsRef<Path>) -> Result<Image> {
+        let path = path.as_ref();
+        let source = if path.is_dir() {
+            Source::Directory(path.to_path_buf())
+        } else {
+            Source::open_archive(path)
+                .map_err(|e| anyhow!("failed to read image archive {}: {e}", path.display()))?
+        };
+
+        if source.exists("index.json") {
+            Image::open_layout(source)
+        } else if source.exists("manifest.json") {
+            Image::open_docker(source)
+        } else {
+            bail!(
+                "{} is neither an OCI image layout nor a docker save archive",
+                path.display()
+            )
+        }
+    }
+
+    /// Read a blob referenced by `descriptor` and verify it.
+    fn read_blob(source: &Source, descriptor: &Descriptor) -> Result<Vec<u8>> {
+        let contents = source.read(&blob_path(&descriptor.digest)?)?;
+        if let Some(size) = descriptor.size
+            && contents.len() as u64 != size
+        {
+            bail!(
+                "size mismatch for blob {}: expected {size}, got {}",
+                descriptor.digest,
+                contents.len()
+            );
+        }
+        verify_stream(&descriptor.digest, &descriptor.digest, &contents[..])?;
+        Ok(contents)
+    }
+
+    fn resolve_manifest(source: &Source, descriptors: &[Descriptor]) -> Result<Manifest> {
+        let descriptor = select_platform(descriptors)?;
+        let contents = Image::read_blob(source, descriptor)?;
+
+        let is_index = descriptor
+            .media_type
+            .as_deref()
+            .is_some_and(|t| INDEX_MEDIA_TYPES.contains(&t));
+        if is_index {
+            let index: Index = serde_json::from_slice(&contents)?;
+            return Image::resolve_manifest(source, &index.manifests);
+        }
+
+        Ok(serde_json::from_slice(&contents)?)
+    }
+
+    fn open_layout(source: Source) -> Result<Image> {
+        let index: Index = serde_json::from_slice(&source.read("index.json")?)?;
+        let manifest = Image::resolve_manifest(&source, &index.manifests)?;
+        let config: ConfigFile =
+            serde_json::from_slice(&Image::read_blob(&source, &manifest.config)?)?;
+        let diff_ids = config.rootfs.map(|r| r.diff_ids).unwrap_or_default();
+
+        let layers = manifest
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                Ok(Layer {
+                    path: blob_path(&layer.digest)?,
+                    digest: Some(layer.digest.clone()),
+                    diff_id: diff_ids.get(i).cloned(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Image {
+            source,
+            layers,
+            config: config.config.unwrap_or_default(),
+        })
+    }
+
+    fn open_docker(source: Source) -> Result<Image> {
+        let manifests: Vec<DockerManifest> =
+            serde_json::from_slice(&source.read("manifest.json")?)?;
+        let manifest = match &manifests[..] {
+            [manifest] => manifest,
+            [] => bail!("docker archive contains no images"),
+            _ => bail!("docker archive contains more than one image"),
+        };
+
+        // Legacy archives carry no blob digests; the config is named after
+        // its digest, and layers are verified against its diff_ids.
+        let config_path = normalize(Path::new(&manifest.config));
+        let config_path = config_path.to_string_lossy();
+        let contents = source.read(&config_path)?;
+        let config_digest = Path::new(config_path.as_ref())
+            .file_name()
+            .and_then(OsStr::to_str)
+            .map(|name| format!("sha256:{}", name.trim_end_matches(".json")));
+        if let Some(digest) = config_digest {
+            verify_stream(&config_path, &digest, &contents[..])?;
+        }
+
+        let config: ConfigFile = serde_json::from_slice(&contents)?;
+        let diff_ids = config.rootfs.map(|r| r.diff_ids).unwrap_or_default();
+        if diff_ids.len() != manifest.layers.len() {
+            bail!(
+                "image config lists {} diff_ids for {} layers",
+                diff_ids.len(),
+                manifest.layers.len()
+            );
+        }
+
+        let layers = manifest
+            .layers
+            .iter()
+            .zip(diff_ids)
+            .map(|(path, diff_id)| Layer {
+                path: normalize(Path::new(path)).to_string_lossy().into_owned(),
+                digest: None,
+                diff_id: Some(diff_id),
+            })
+            .collect();
+
+        Ok(Image {
+            source,
+            layers,
+            config: config.config.unwrap_or_default(),
+        })
+    }
+
+    /// The runtime configuration recorded in the image.
+    pub fn config(&self) -> &ImageConfig {
+        &self.config
+    }
+
+    /// Extract the image's layers into `target`, which is created if
+    /// needed and should be empty. If this fails, the contents of `target`
+    /// are removed.
+    pub fn unpack(&self, target: impl AsRef<Path>) -> Result<()> {
+        let target = target.as_ref();
+        fs::create_dir_all(target)?;
+        let root: OwnedFd = File::open(target)?.into();
+
+        for layer in &self.layers {
+            debug!("unpacking layer {}", layer.path);
+
+            if let Err(e) = self.unpack_layer(&root, layer) {
+                if let Err(e) = clear_dir(&root) {
+                    warn!("unable to discard {}: {e}", target.display());
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Extract `layer` into `root`, checking both the blob and its
+    /// uncompressed contents against their digests as they are read.
+    fn unpack_layer(&self, root: &OwnedFd, layer: &Layer) -> Result<()> {
+        let mut blob = DigestReader::new(self.source.open(&layer.path)?, layer.digest.as_deref())?;
+        let mut diff = DigestReader::new(decompress(&mut blob)?, layer.diff_id.as_deref())?;
+        if let Err(e) = apply_layer(root, &mut diff) {
+            // A corrupted blob is likely to fail to extract; say so if it is.
+            drop(diff);
+            blob.verify(&layer.path)?;
+            return Err(e);
+        }
+
+        // The tar stream may continue past the end-of-archive marker, and
+        // the blob past the end of the compressed stream.
+        diff.verify(&layer.path)?;
+        blob.verify(&layer.path)
+    }
+
+    /// Build an executable spec from the image config. The user, if any, is
+    /// resolved in the container once its rootfs is in place.
+    pub fn executable_spec(&self) -> ExecutableSpec {
+        let config = &self.config;
+
+        let mut argv = config.entrypoint.clone().unwrap_or_default();
+        argv.extend(config.cmd.clone().unwrap_or_default());
+        let mut argv = argv.into_iter();
+
+        let environment = config.env.as_ref().map(|env| {
+            env.iter()
+                .map(|var| match var.split_once('=') {
+                    Some((key, value)) => (key.to_string(), value.to_string()),
+                    None => (var.clone(), String::new()),
+                })
+                .collect::<BTreeMap<_, _>>()
+        });
+
+        ExecutableSpec {
+            executable: argv.next(),
+            arguments: Some(argv.collect()),
+            working_directory: config.working_dir.clone().filter(|wd| !wd.is_empty()),
+            environment,
+            user: config.user.clone().filter(|user| !user.is_empty()),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    enum Node<'a> {
+        Dir(&'a str),
+        File(&'a str, &'a str),
+        Symlink(&'a str, &'a str),
+    }
+
+    fn layer_tar(nodes: &[Node<'_>]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for node in nodes {
+            let mut header = tar::Header::new_gnu();
+            header.set_uid(0);
+            header.set_gid(0);
+            match node {
+                Node::Dir(path) => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+                Node::File(path, contents) => {
+                    header.set_mode(0o644);
+                    header.set_size(contents.len() as u64);
+                    builder
+                        .append_data(&mut header, path, contents.as_bytes())
+                        .unwrap();
+                }
+                Node::Symlink(path, target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_mode(0o777);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path, target).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("sha256:{:x}", Sha256::digest(data))
+    }
+
+    fn write_blob(layout: &Path, data: &[u8]) -> String {
+        let digest = sha256(data);
+        let path = layout.join(blob_path(&digest).unwrap());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+        digest
+    }
+
+    fn layers() -> Vec<Vec<u8>> {
+        vec![
+            layer_tar(&[
+                Node::Dir("etc"),
+                Node::File(
+                    "etc/passwd",
+                    "root:x:0:0:root:/root:/bin/sh\napp:x:1000:1000::/home/app:/bin/sh\n",
+                ),
+                Node::File("etc/group", "root:x:0:\napp:x:1000:\nwheel:x:10:app\n"),
+                Node::Dir("data"),
+                Node::File("data/old", "old"),
+                Node::File("removed", "removed"),
+                Node::Symlink("escape", "/"),
+            ]),
+            layer_tar(&[
+                Node::Dir("data"),
+                Node::File("data/.wh..wh..opq", ""),
+                Node::File("data/new", "new"),
+                Node::File(".wh.removed", ""),
+                Node::File("escape/escaped", "escaped"),
+            ]),
+        ]
+    }
+
+    fn config_json(diff_ids: &[String]) -> Vec<u8> {
+        serde_json::json!({
+            "architecture": host_architecture(),
+            "os": "linux",
+            "config": {
+                "Entrypoint": ["/bin/sh"],
+                "Cmd": ["-c", "true"],
+                "Env": ["PATH=/bin", "EMPTY="],
+                "WorkingDir": "/data",
+                "User": "app",
+            },
+            "rootfs": { "type": "layers", "diff_ids": diff_ids },
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    /// Write an OCI layout with an index pointing at a per-platform
+    /// manifest, and return the gzipped layer blob paths.
+    fn write_layout(layout: &Path) -> Vec<PathBuf> {
+        let layers = layers();
+        let diff_ids: Vec<_> = layers.iter().map(|l| sha256(l)).collect();
+        let blobs: Vec<_> = layers.iter().map(|l| gzip(l)).collect();
+        let layer_digests: Vec<_> = blobs.iter().map(|b| write_blob(layout, b)).collect();
+
+        let config = config_json(&diff_ids);
+        let config_digest = write_blob(layout, &config);
+
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": config_digest,
+                "size": config.len(),
+            },
+            "layers": layer_digests.iter().zip(&blobs).map(|(digest, blob)| serde_json::json!({
+                "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                "digest": digest,
+                "size": blob.len(),
+            })).collect::<Vec<_>>(),
+        })
+        .to_string()
+        .into_bytes();
+        let manifest_digest = write_blob(layout, &manifest);
+
+        let nested = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": sha256(b"other"),
+                    "size": 5,
+                    "platform": { "architecture": "not-a-real-arch", "os": "linux" },
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": manifest_digest,
+                    "size": manifest.len(),
+                    "platform": { "architecture": host_architecture(), "os": "linux" },
+                },
+            ],
+        })
+        .to_string()
+        .into_bytes();
+        let nested_digest = write_blob(layout, &nested);
+
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [{
+                "mediaType": "application/vnd.oci.image.index.v1+json",
+                "digest": nested_digest,
+                "size": nested.len(),
+            }],
+        });
+        fs::write(layout.join("index.json"), index.to_string()).unwrap();
+        fs::write(
+            layout.join("oci-layout"),
+            r#"{"imageLayoutVersion":"1.0.0"}"#,
+        )
+        .unwrap();
+
+        layer_digests
+            .iter()
+            .map(|d| layout.join(blob_path(d).unwrap()))
+            .collect()
+    }
+
+    fn assert_unpacked(rootfs: &Path) {
+        assert!(rootfs.join("etc/passwd").is_file());
+        assert!(!rootfs.join("data/old").exists());
+        assert_eq!(fs::read_to_string(rootfs.join("data/new")).unwrap(), "new");
+        assert!(!rootfs.join("removed").exists());
+        // Writing through the absolute symlink stays inside the rootfs.
+        assert!(rootfs.join("escaped").is_file());
+        assert!(!Path::new("/escaped").exists());
+    }
+
+    #[test]
+    fn oci_layout_is_unpacked_with_whiteouts() {
+        let layout = tempfile::TempDir::new().unwrap();
+        let rootfs = tempfile::TempDir::new().unwrap();
+        write_layout(layout.path());
+
+        let image = Image::open(layout.path()).unwrap();
+        image.unpack(rootfs.path()).unwrap();
+        assert_unpacked(rootfs.path());
+
+        let spec = image.executable_spec();
+        assert_eq!(spec.executable.as_deref(), Some("/bin/sh"));
+        assert_eq!(
+            spec.arguments,
+            Some(vec!["-c".to_string(), "true".to_string()])
+        );
+        assert_eq!(spec.working_directory.as_deref(), Some("/data"));
+        let env = spec.environment.unwrap();
+        assert_eq!(env.get("PATH").map(String::as_str), Some("/bin"));
+        assert_eq!(env.get("EMPTY").map(String::as_str), Some(""));
+        assert_eq!(spec.user.as_deref(), Some("app"));
+        assert_eq!((spec.uid, spec.gid), (None, None));
+    }
+
+    #[test]
+    fn corrupted_layer_is_rejected() {
+        let layout = tempfile::TempDir::new().unwrap();
+        let rootfs = tempfile::TempDir::new().unwrap();
+        let blobs = write_layout(layout.path());
+        fs::write(&blobs[1], gzip(b"not the layer")).unwrap();
+
+        let image = Image::open(layout.path()).unwrap();
+        let err = image.unpack(rootfs.path()).unwrap_err();
+        assert!(err.to_string().contains("digest mismatch"), "{err}");
+        // Whatever was extracted before the mismatch is discarded.
+        assert_eq!(fs::read_dir(rootfs.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn legacy_docker_archive_is_unpacked() {
+        let layers = layers();
+        let diff_ids: Vec<_> = layers.iter().map(|l| sha256(l)).collect();
+        let config = config_json(&diff_ids);
+        let config_name = format!("{}.json", &sha256(&config)["sha256:".len()..]);
+        let manifest = serde_json::json!([{
+            "Config": config_name,
+            "RepoTags": ["test:latest"],
+            "Layers": ["one/layer.tar", "two/layer.tar"],
+        }])
+        .to_string();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        append("manifest.json", manifest.as_bytes());
+        append(&config_name, &config);
+        append("one/layer.tar", &layers[0]);
+        // Layers may be compressed even in legacy archives.
+        append(
+            "two/layer.tar",
+            &ruzstd::encoding::compress_to_vec(
+                &layers[1][..],
+                ruzstd::encoding::CompressionLevel::Fastest,
+            ),
+        );
+        let archive = builder.into_inner().unwrap();
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let archive_path = dir.path().join("image.tar");
+        fs::write(&archive_path, archive).unwrap();
+        let rootfs = dir.path().join("rootfs");
+
+        let image = Image::open(&archive_path).unwrap();
+        image.unpack(&rootfs).unwrap();
+        assert_unpacked(&rootfs);
+    }
+
+    #[test]
+    fn docker_config_cannot_escape_the_image() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let image = dir.path().join("image");
+        fs::create_dir(&image).unwrap();
+
+        let config = config_json(&[]);
+        let config_name = format!("{}.json", &sha256(&config)["sha256:".len()..]);
+        fs::write(dir.path().join(&config_name), &config).unwrap();
+        let manifest = serde_json::json!([{
+            "Config": format!("../{config_name}"),
+            "Layers": [],
+        }]);
+        fs::write(image.join("manifest.json"), manifest.to_string()).unwrap();
+
+        assert!(Image::open(&image).is_err());
+        fs::write(image.join(&config_name), &config).unwrap();
+        assert!(Image::open(&image).is_ok());
+    }
+
+    #[test]
+    fn digests_cannot_escape_blob_directory() {
+        assert!(blob_path("sha256:../../etc/passwd").is_err());
+        assert!(blob_path("../x:abcd").is_err());
+        assert_eq!(blob_path("sha256:abcd").unwrap(), "blobs/sha256/abcd");
+    }
+}
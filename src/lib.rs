@@ -2,10 +2,25 @@ pub mod apparmor;
 pub mod caps;
 pub mod cgroup;
 pub mod config;
+mod dbus;
+pub mod devices;
+pub mod etc;
+pub mod idmap;
+#[cfg(feature = "image")]
+pub mod image;
 pub mod mount;
+pub mod mutation;
 pub mod namespace;
+pub mod network;
+pub mod rootfs;
 pub mod runner;
+pub mod sched;
 pub mod seccomp;
 pub mod signal;
+#[cfg(feature = "slirp")]
+pub mod slirp;
+pub mod sysctl;
+pub mod systemd;
 pub mod unshare;
+pub mod user;
 pub mod wrap;
@@ -8,10 +8,18 @@ use std::ptr;
 
 use anyhow::{Result, anyhow, bail};
 use libc;
+use log::{debug, warn};
 
-use crate::config::{MountSpec, Mountable};
+use crate::config::{IdMap, MountSpec, Mountable, Propagation};
+use crate::rootfs::{Rootfs, fd_path};
 
 const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x4;
+const MOVE_MOUNT_T_SYMLINKS: c_uint = 0x10;
+const FSOPEN_CLOEXEC: c_uint = 0x1;
+const FSMOUNT_CLOEXEC: c_uint = 0x1;
+const FSCONFIG_SET_FLAG: c_uint = 0;
+const FSCONFIG_SET_STRING: c_uint = 1;
+const FSCONFIG_CMD_CREATE: c_uint = 6;
 
 /// open_tree(2)
 pub fn open_tree(dfd: c_int, path: &str, flags: c_uint) -> io::Result<OwnedFd> {
@@ -25,6 +33,78 @@ pub fn open_tree(dfd: c_int, path: &str, flags: c_uint) -> io::Result<OwnedFd> {
     Ok(unsafe { OwnedFd::from_raw_fd(ret as c_int) })
 }
 
+/// fsopen(2)
+pub fn fsopen(fstype: &str, flags: c_uint) -> io::Result<OwnedFd> {
+    let c_fstype = CString::new(fstype)?;
+    let ret = unsafe { libc::syscall(libc::SYS_fsopen, c_fstype.as_ptr(), flags) };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(ret as c_int) })
+}
+
+/// fsconfig(2), for the commands that take a string key and value.
+pub fn fsconfig(
+    fd: &OwnedFd,
+    cmd: c_uint,
+    key: Option<&str>,
+    value: Option<&str>,
+) -> io::Result<()> {
+    let c_key = key.map(CString::new).transpose()?;
+    let c_value = value.map(CString::new).transpose()?;
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_fsconfig,
+            fd.as_raw_fd(),
+            cmd,
+            c_key.as_ref().map_or(ptr::null(), |k| k.as_ptr()),
+            c_value.as_ref().map_or(ptr::null(), |v| v.as_ptr()),
+            0,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// fsmount(2)
+pub fn fsmount(fd: &OwnedFd, flags: c_uint, attr_flags: c_uint) -> io::Result<OwnedFd> {
+    let ret = unsafe { libc::syscall(libc::SYS_fsmount, fd.as_raw_fd(), flags, attr_flags) };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(ret as c_int) })
+}
+
+/// Drain the messages the filesystem logged to an fs context, e.g. why an
+/// option was rejected. Each message is prefixed with its severity.
+fn fs_context_log(fd: &OwnedFd) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if n <= 0 {
+            break;
+        }
+        messages.push(
+            String::from_utf8_lossy(&buf[..n as usize])
+                .trim_end()
+                .to_string(),
+        );
+    }
+
+    messages
+}
+
 /// move_mount(2)
 pub fn move_mount(
     from_dfd: c_int,
@@ -97,7 +177,9 @@ pub fn move_mount_fd_to(fd: &OwnedFd, target: &str) -> io::Result<()> {
         "",
         libc::AT_FDCWD,
         target,
-        MOVE_MOUNT_F_EMPTY_PATH as c_uint,
+        // Like mount(2), follow the target if it is a symlink, so that mounts
+        // can be attached through /proc/self/fd.
+        MOVE_MOUNT_F_EMPTY_PATH | MOVE_MOUNT_T_SYMLINKS,
     )
 }
 
@@ -110,14 +192,175 @@ pub fn mount_setattr_fd(fd: &OwnedFd, recursive: bool, attr: &libc::mount_attr)
     mount_setattr(fd.as_raw_fd(), "", flags, attr)
 }
 
-/// Join a container-absolute `path` under `rootfs`, avoiding a doubled
-/// separator. `path` is treated as rooted at the container's `/`.
-fn join_rootfs(rootfs: &str, path: &str) -> String {
-    format!(
-        "{}/{}",
-        rootfs.trim_end_matches('/'),
-        path.trim_start_matches('/')
-    )
+/// Change the propagation type of the mount at `target`.
+pub fn set_propagation(target: &str, propagation: Propagation) -> Result<()> {
+    let flags = match propagation {
+        Propagation::Shared => libc::MS_SHARED,
+        Propagation::RShared => libc::MS_SHARED | libc::MS_REC,
+        Propagation::Slave => libc::MS_SLAVE,
+        Propagation::RSlave => libc::MS_SLAVE | libc::MS_REC,
+        Propagation::Private => libc::MS_PRIVATE,
+        Propagation::RPrivate => libc::MS_PRIVATE | libc::MS_REC,
+        Propagation::Unbindable => libc::MS_UNBINDABLE,
+        Propagation::RUnbindable => libc::MS_UNBINDABLE | libc::MS_REC,
+    };
+
+    let target_c = CString::new(target)?;
+    let rc = unsafe {
+        libc::mount(
+            ptr::null(),
+            target_c.as_ptr(),
+            ptr::null(),
+            flags,
+            ptr::null(),
+        )
+    };
+    if rc < 0 {
+        bail!(
+            "unable to make {target} {propagation:?}: {}",
+            io::Error::last_os_error()
+        );
+    }
+
+    Ok(())
+}
+
+/// A pair of mount attributes to set and clear with mount_setattr(2).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct MountAttrs {
+    set: u64,
+    clr: u64,
+}
+
+impl MountAttrs {
+    fn set(&mut self, attr: u64) {
+        self.set |= attr;
+        self.clr &= !attr;
+    }
+
+    fn clear(&mut self, attr: u64) {
+        self.clr |= attr;
+        self.set &= !attr;
+    }
+
+    /// The atime modes are a field rather than flags, and must be cleared to
+    /// be changed. `MOUNT_ATTR_RELATIME` is zero.
+    fn set_atime(&mut self, mode: u64) {
+        self.set = (self.set & !libc::MOUNT_ATTR__ATIME) | mode;
+        self.clr |= libc::MOUNT_ATTR__ATIME;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.set == 0 && self.clr == 0
+    }
+
+    fn apply(&self, target: &str, recursive: bool) -> Result<()> {
+        let mut attr: libc::mount_attr = unsafe { std::mem::zeroed() };
+        attr.attr_set = self.set;
+        attr.attr_clr = self.clr;
+
+        let mut flags: c_uint = 0;
+        if recursive {
+            flags |= libc::AT_RECURSIVE as c_uint;
+        }
+
+        mount_setattr(libc::AT_FDCWD, target, flags, &attr)
+            .map_err(|e| anyhow!("unable to set mount attributes on {target}: {e}"))
+    }
+}
+
+/// fstab/OCI-style mount options, split by how they are applied.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct MountOptions {
+    /// Flags passed to mount(2) when the mount is created.
+    flags: c_ulong,
+    bind: bool,
+    recurse: bool,
+    /// Attributes applied to the mount point, recursively if the mount is.
+    attrs: MountAttrs,
+    /// Attributes applied recursively, from the `r`-prefixed options.
+    recursive_attrs: MountAttrs,
+    propagation: Option<Propagation>,
+    /// Everything else is handed to the filesystem as mount data.
+    data: Vec<String>,
+}
+
+impl MountOptions {
+    fn parse(options: &[String]) -> MountOptions {
+        let mut parsed = MountOptions::default();
+
+        for option in options {
+            let option = option.as_str();
+            if parsed.parse_attr(option, false) {
+                continue;
+            }
+            if let Some(attr) = option.strip_prefix('r')
+                && parsed.parse_attr(attr, true)
+            {
+                continue;
+            }
+
+            match option {
+                "defaults" => {}
+                "bind" => parsed.bind = true,
+                "rbind" => {
+                    parsed.bind = true;
+                    parsed.recurse = true;
+                }
+                "sync" => parsed.flags |= libc::MS_SYNCHRONOUS,
+                "async" => parsed.flags &= !libc::MS_SYNCHRONOUS,
+                "dirsync" => parsed.flags |= libc::MS_DIRSYNC,
+                "mand" => parsed.flags |= libc::MS_MANDLOCK,
+                "nomand" => parsed.flags &= !libc::MS_MANDLOCK,
+                "lazytime" => parsed.flags |= libc::MS_LAZYTIME,
+                "nolazytime" => parsed.flags &= !libc::MS_LAZYTIME,
+                "silent" => parsed.flags |= libc::MS_SILENT,
+                "loud" => parsed.flags &= !libc::MS_SILENT,
+                "shared" => parsed.propagation = Some(Propagation::Shared),
+                "rshared" => parsed.propagation = Some(Propagation::RShared),
+                "slave" => parsed.propagation = Some(Propagation::Slave),
+                "rslave" => parsed.propagation = Some(Propagation::RSlave),
+                "private" => parsed.propagation = Some(Propagation::Private),
+                "rprivate" => parsed.propagation = Some(Propagation::RPrivate),
+                "unbindable" => parsed.propagation = Some(Propagation::Unbindable),
+                "runbindable" => parsed.propagation = Some(Propagation::RUnbindable),
+                data => parsed.data.push(data.to_string()),
+            }
+        }
+
+        parsed
+    }
+
+    /// Parse an option that maps to a mount attribute, returning whether it
+    /// did.
+    fn parse_attr(&mut self, option: &str, recursive: bool) -> bool {
+        let attrs = if recursive {
+            &mut self.recursive_attrs
+        } else {
+            &mut self.attrs
+        };
+
+        match option {
+            "ro" => attrs.set(libc::MOUNT_ATTR_RDONLY),
+            "rw" => attrs.clear(libc::MOUNT_ATTR_RDONLY),
+            "nosuid" => attrs.set(libc::MOUNT_ATTR_NOSUID),
+            "suid" => attrs.clear(libc::MOUNT_ATTR_NOSUID),
+            "nodev" => attrs.set(libc::MOUNT_ATTR_NODEV),
+            "dev" => attrs.clear(libc::MOUNT_ATTR_NODEV),
+            "noexec" => attrs.set(libc::MOUNT_ATTR_NOEXEC),
+            "exec" => attrs.clear(libc::MOUNT_ATTR_NOEXEC),
+            "nodiratime" => attrs.set(libc::MOUNT_ATTR_NODIRATIME),
+            "diratime" => attrs.clear(libc::MOUNT_ATTR_NODIRATIME),
+            "nosymfollow" => attrs.set(libc::MOUNT_ATTR_NOSYMFOLLOW),
+            "symfollow" => attrs.clear(libc::MOUNT_ATTR_NOSYMFOLLOW),
+            "noatime" => attrs.set_atime(libc::MOUNT_ATTR_NOATIME),
+            "strictatime" | "norelatime" => attrs.set_atime(libc::MOUNT_ATTR_STRICTATIME),
+            "relatime" | "atime" | "nostrictatime" => attrs.set_atime(libc::MOUNT_ATTR_RELATIME),
+            _ => return false,
+        }
+
+        true
+    }
 }
 
 /// Mask a single container path (OCI `maskedPaths` semantics): cover a file
@@ -128,13 +371,15 @@ fn join_rootfs(rootfs: &str, path: &str) -> String {
 ///
 /// Must be called before pivot, while the original `/dev/null` is still
 /// reachable at its normal path.
-pub fn mask_path(rootfs: &str, path: &str) -> Result<()> {
-    let target = join_rootfs(rootfs, path);
-    let meta = match fs::symlink_metadata(&target) {
-        Ok(m) => m,
-        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
-        Err(e) => return Err(anyhow!("stat {target}: {e}")),
+pub fn mask_path(rootfs: &Rootfs, path: &str) -> Result<()> {
+    let meta = match rootfs.lookup(path) {
+        Ok(Some(fd)) => fs::File::from(fd)
+            .metadata()
+            .map_err(|e| anyhow!("stat {path}: {e}"))?,
+        Ok(None) => return Ok(()),
+        Err(e) => return Err(anyhow!("stat {path}: {e}")),
     };
+    let target = path.to_string();
 
     let spec = if meta.is_dir() {
         // Empty read-only tmpfs over the directory (nosuid/nodev/noexec).
@@ -142,13 +387,10 @@ pub fn mask_path(rootfs: &str, path: &str) -> Result<()> {
             source: Some("tmpfs".to_string()),
             target,
             fstype: Some("tmpfs".to_string()),
-            bind: false,
-            recurse: false,
-            unshare: false,
             safe: true,
-            create_mountpoint: false,
             read_only: true,
             data: Some("size=0k".to_string()),
+            ..Default::default()
         }
     } else {
         // Bind /dev/null over the file. Not marked read-only or `safe`: the
@@ -157,66 +399,379 @@ pub fn mask_path(rootfs: &str, path: &str) -> Result<()> {
         MountSpec {
             source: Some("/dev/null".to_string()),
             target,
-            fstype: None,
             bind: true,
-            recurse: false,
-            unshare: false,
-            safe: false,
-            create_mountpoint: false,
-            read_only: false,
-            data: None,
+            ..Default::default()
         }
     };
 
-    spec.mount()
+    spec.mount_in(rootfs)
 }
 
 /// Make a single container path read-only (OCI `readonlyPaths` semantics)
 /// while leaving its contents readable: bind the target onto itself and
 /// recursively remount read-only. `path` is resolved under `rootfs`; a target
 /// that does not exist is skipped.
-pub fn make_readonly(rootfs: &str, path: &str) -> Result<()> {
-    let target = join_rootfs(rootfs, path);
-    match fs::symlink_metadata(&target) {
-        Ok(_) => {}
-        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
-        Err(e) => return Err(anyhow!("stat {target}: {e}")),
-    }
+pub fn make_readonly(rootfs: &Rootfs, path: &str) -> Result<()> {
+    let source = match rootfs.lookup(path) {
+        Ok(Some(fd)) => fd,
+        Ok(None) => return Ok(()),
+        Err(e) => return Err(anyhow!("stat {path}: {e}")),
+    };
 
     let spec = MountSpec {
-        source: Some(target.clone()),
-        target,
+        source: Some(fd_path(&source)),
+        target: path.to_string(),
         fstype: Some("none".to_string()),
         bind: true,
         recurse: true,
-        unshare: false,
-        safe: false,
-        create_mountpoint: false,
         read_only: true,
-        data: None,
+        ..Default::default()
+    };
+
+    spec.mount_in(rootfs)
+}
+
+/// The device nodes of a standard `/dev`, as (name, major, minor).
+const STANDARD_DEVICES: &[(&str, u32, u32)] = &[
+    ("null", 1, 3),
+    ("zero", 1, 5),
+    ("full", 1, 7),
+    ("random", 1, 8),
+    ("urandom", 1, 9),
+    ("tty", 5, 0),
+];
+
+/// The symlinks of a standard `/dev`, as (name, target).
+const STANDARD_SYMLINKS: &[(&str, &str)] = &[
+    ("ptmx", "pts/ptmx"),
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+];
+
+const SAFE_OPTIONS: &[&str] = &["nosuid", "nodev", "noexec"];
+const SAFE_READONLY_OPTIONS: &[&str] = &["nosuid", "nodev", "noexec", "ro"];
+
+fn fs_mount(fstype: &str, target: &str, options: &[&str]) -> MountSpec {
+    MountSpec {
+        source: Some(fstype.to_string()),
+        target: target.to_string(),
+        fstype: Some(fstype.to_string()),
+        create_mountpoint: true,
+        options: options.iter().map(|o| o.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+/// Create the device node `name` in the container's `/dev`. Where device
+/// nodes can't be created, e.g. in a user namespace, the host's node is bind
+/// mounted instead.
+fn create_device(rootfs: &Rootfs, name: &str, major: u32, minor: u32) -> Result<()> {
+    let target = format!("/dev/{name}");
+    let (dev, c_name) = rootfs.open_parent(&target)?;
+
+    let rc = unsafe {
+        libc::mknodat(
+            dev.as_raw_fd(),
+            c_name.as_ptr(),
+            libc::S_IFCHR | 0o666,
+            libc::makedev(major, minor),
+        )
+    };
+    if rc == 0 {
+        // mknod(2) is subject to the umask.
+        if unsafe { libc::fchmodat(dev.as_raw_fd(), c_name.as_ptr(), 0o666, 0) } < 0 {
+            bail!(
+                "unable to chmod device node {target}: {}",
+                io::Error::last_os_error()
+            );
+        }
+        return Ok(());
+    }
+
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EPERM) {
+        bail!("unable to create device node {target}: {err}");
+    }
+
+    let spec = MountSpec {
+        source: Some(target.clone()),
+        target,
+        bind: true,
+        create_mountpoint: true,
+        ..Default::default()
+    };
+    spec.mount_in(rootfs)
+}
+
+/// Mount a standard set of filesystems under `rootfs`: a tmpfs `/dev` with
+/// the usual device nodes and symlinks, a private devpts instance, `/dev/shm`,
+/// `/dev/mqueue` if the container has its own IPC namespace, a read-only
+/// `/sys`, and the cgroup2 hierarchy at `/sys/fs/cgroup`.
+///
+/// Must be called before pivot, while the host's `/dev` and `/sys` are still
+/// reachable as fallbacks.
+pub fn mount_standard_filesystems(rootfs: &Rootfs, ipc_namespace: bool) -> Result<()> {
+    fs_mount(
+        "tmpfs",
+        "/dev",
+        &["nosuid", "strictatime", "mode=755", "size=65536k"],
+    )
+    .mount_in(rootfs)?;
+
+    for &(name, major, minor) in STANDARD_DEVICES {
+        create_device(rootfs, name, major, minor)?;
+    }
+
+    // The tty group only exists if gid 5 is mapped into the container.
+    let devpts = [
+        "nosuid",
+        "noexec",
+        "newinstance",
+        "ptmxmode=0666",
+        "mode=0620",
+    ];
+    if fs_mount("devpts", "/dev/pts", &[&devpts[..], &["gid=5"]].concat())
+        .mount_in(rootfs)
+        .is_err()
+    {
+        fs_mount("devpts", "/dev/pts", &devpts).mount_in(rootfs)?;
+    }
+
+    let dev = rootfs.open_at("/dev", libc::O_PATH | libc::O_DIRECTORY)?;
+    for &(name, target) in STANDARD_SYMLINKS {
+        let (c_target, c_name) = (CString::new(target)?, CString::new(name)?);
+        if unsafe { libc::symlinkat(c_target.as_ptr(), dev.as_raw_fd(), c_name.as_ptr()) } < 0 {
+            bail!(
+                "unable to create symlink /dev/{name}: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    fs_mount(
+        "tmpfs",
+        "/dev/shm",
+        &["nosuid", "nodev", "noexec", "mode=1777", "size=65536k"],
+    )
+    .mount_in(rootfs)?;
+
+    if ipc_namespace {
+        fs_mount("mqueue", "/dev/mqueue", SAFE_OPTIONS).mount_in(rootfs)?;
+    }
+
+    // sysfs can only be mounted by the owner of the network namespace;
+    // otherwise fall back to a read-only view of the host's.
+    if let Err(e) = fs_mount("sysfs", "/sys", SAFE_READONLY_OPTIONS).mount_in(rootfs) {
+        debug!("unable to mount sysfs, binding /sys instead: {e}");
+        let spec = MountSpec {
+            source: Some("/sys".to_string()),
+            target: "/sys".to_string(),
+            bind: true,
+            recurse: true,
+            safe: true,
+            create_mountpoint: true,
+            read_only: true,
+            ..Default::default()
+        };
+        spec.mount_in(rootfs)?;
+    }
+
+    if let Err(e) = fs_mount("cgroup2", "/sys/fs/cgroup", SAFE_READONLY_OPTIONS).mount_in(rootfs) {
+        warn!("unable to mount cgroup2 at /sys/fs/cgroup: {e}");
+    }
+
+    Ok(())
+}
+
+/// Build the mount data for an overlayfs from `lower` layers (top-most
+/// first) and an optional `(upperdir, workdir)` pair.
+fn overlay_options(
+    lower: &[String],
+    upper: Option<(&str, &str)>,
+    userxattr: bool,
+) -> Result<String> {
+    if lower.is_empty() {
+        bail!("an overlay needs at least one lower layer");
+    }
+
+    // The legacy mount data format has no way to escape these.
+    let paths = lower
+        .iter()
+        .map(String::as_str)
+        .chain(upper.iter().flat_map(|(u, w)| [*u, *w]));
+    for path in paths {
+        if path.contains([',', ':']) {
+            bail!("overlay layer path {path:?} must not contain ',' or ':'");
+        }
+    }
+
+    let mut options = format!("lowerdir={}", lower.join(":"));
+    if let Some((upperdir, workdir)) = upper {
+        options.push_str(&format!(",upperdir={upperdir},workdir={workdir}"));
+    }
+    if userxattr {
+        options.push_str(",userxattr");
+    }
+
+    Ok(options)
+}
+
+/// Whether we are in the initial user namespace, whose uid map covers every
+/// id.
+fn in_initial_userns() -> bool {
+    fs::read_to_string("/proc/self/uid_map")
+        .map(|map| map.split_whitespace().collect::<Vec<_>>() == ["0", "0", "4294967295"])
+        .unwrap_or(false)
+}
+
+/// Mount an overlayfs at `target` from `lower` layers (top-most first) and an
+/// optional `(upperdir, workdir)` pair. Outside the initial user namespace,
+/// the overlay is mounted with `userxattr`, which unprivileged overlay mounts
+/// require.
+pub fn mount_overlay(target: &str, lower: &[String], upper: Option<(&str, &str)>) -> Result<()> {
+    let spec = MountSpec {
+        source: Some("overlay".to_string()),
+        target: target.to_string(),
+        fstype: Some("overlay".to_string()),
+        create_mountpoint: true,
+        data: Some(overlay_options(lower, upper, !in_initial_userns())?),
+        ..Default::default()
     };
 
     spec.mount()
 }
 
-impl Mountable for MountSpec {
-    fn seal(&self) -> Result<()> {
-        let tree = open_tree(
-            libc::AT_FDCWD,
-            self.source
-                .as_deref()
-                .ok_or_else(|| anyhow!("source missing"))?,
-            libc::OPEN_TREE_CLOEXEC,
-        )?;
+impl MountSpec {
+    /// The mount options requested by this mount spec. The dedicated fields
+    /// take precedence over `options`.
+    fn mount_options(&self) -> MountOptions {
+        let mut options = MountOptions::parse(&self.options);
 
-        let mut attr: libc::mount_attr = unsafe { std::mem::zeroed() };
-        attr.attr_set |= libc::MOUNT_ATTR_RDONLY;
-        mount_setattr_fd(&tree, false, &attr)?;
+        options.bind |= self.bind;
+        options.recurse |= self.recurse;
 
-        Ok(())
+        if self.safe {
+            options.attrs.set(libc::MOUNT_ATTR_NOSUID);
+            options.attrs.set(libc::MOUNT_ATTR_NODEV);
+            options.attrs.set(libc::MOUNT_ATTR_NOEXEC);
+        }
+
+        if self.read_only {
+            options.attrs.set(libc::MOUNT_ATTR_RDONLY);
+        }
+
+        if self.propagation.is_some() {
+            options.propagation = self.propagation;
+        }
+
+        if let Some(data) = &self.data {
+            let data = data
+                .split(',')
+                .filter(|d| !d.is_empty())
+                .map(str::to_string);
+            options.data.splice(0..0, data);
+        }
+
+        options
     }
 
-    fn mount(&self) -> Result<()> {
+    /// Create a new filesystem instance through an fs context and attach it
+    /// at the target. Unlike mount(2), each option is passed on its own, so
+    /// values may contain commas, and the filesystem's reasons for rejecting
+    /// one are reported. Returns false if the new mount API is unavailable.
+    fn mount_fs_context(&self, fstype: &str, target: &str, options: &MountOptions) -> Result<bool> {
+        let fs = match fsopen(fstype, FSOPEN_CLOEXEC) {
+            Ok(fs) => fs,
+            // Seccomp profiles unaware of the new mount API reject it with
+            // either of these.
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) => {
+                debug!("fsopen({fstype}) unavailable, falling back to mount(2): {e}");
+                return Ok(false);
+            }
+            Err(e) => bail!("unable to open {fstype} fs context: {e}"),
+        };
+
+        let configure = |cmd: c_uint, key: Option<&str>, value: Option<&str>| {
+            fsconfig(&fs, cmd, key, value).map_err(|e| {
+                let log = fs_context_log(&fs);
+                let what = key.map_or("create".to_string(), |k| format!("option {k:?}"));
+                if log.is_empty() {
+                    anyhow!("unable to mount {fstype} at {}: {what}: {e}", self.target)
+                } else {
+                    anyhow!(
+                        "unable to mount {fstype} at {}: {what}: {e} ({})",
+                        self.target,
+                        log.join("; ")
+                    )
+                }
+            })
+        };
+
+        if let Some(source) = &self.source {
+            configure(FSCONFIG_SET_STRING, Some("source"), Some(source))?;
+        }
+
+        let sb_flags = [
+            (libc::MS_SYNCHRONOUS, "sync"),
+            (libc::MS_DIRSYNC, "dirsync"),
+            (libc::MS_LAZYTIME, "lazytime"),
+            (libc::MS_MANDLOCK, "mand"),
+        ];
+        for (flag, key) in sb_flags {
+            if options.flags & flag != 0 {
+                configure(FSCONFIG_SET_FLAG, Some(key), None)?;
+            }
+        }
+        if options.attrs.set & libc::MOUNT_ATTR_RDONLY != 0 {
+            configure(FSCONFIG_SET_FLAG, Some("ro"), None)?;
+        }
+
+        for option in &options.data {
+            match option.split_once('=') {
+                Some((key, value)) => configure(FSCONFIG_SET_STRING, Some(key), Some(value))?,
+                None => configure(FSCONFIG_SET_FLAG, Some(option), None)?,
+            }
+        }
+
+        configure(FSCONFIG_CMD_CREATE, None, None)?;
+
+        let mnt = fsmount(&fs, FSMOUNT_CLOEXEC, 0)
+            .map_err(|e| anyhow!("unable to mount {fstype} at {}: {e}", self.target))?;
+        move_mount_fd_to(&mnt, target)
+            .map_err(|e| anyhow!("unable to attach {fstype} mount at {}: {e}", self.target))?;
+
+        Ok(true)
+    }
+
+    /// Whether this is a bind mount of a file rather than a directory.
+    fn source_is_file(&self, options: &MountOptions) -> bool {
+        options.bind
+            && self
+                .source
+                .as_deref()
+                .and_then(|s| std::path::Path::new(s).metadata().ok())
+                .map(|m| !m.is_dir())
+                .unwrap_or(false)
+    }
+
+    /// Mount at `target`, without applying the mount attributes and
+    /// propagation type yet.
+    fn attach(&self, target: &str, options: &MountOptions) -> Result<()> {
+        if let Some(idmap) = &self.idmap {
+            return self.mount_idmapped(idmap, target, options);
+        }
+
+        if let Some(fstype) = self
+            .fstype
+            .as_deref()
+            .filter(|_| !options.bind && !self.unshare)
+            && self.mount_fs_context(fstype, target, options)?
+        {
+            return Ok(());
+        }
+
         let source = unpack(self.source.clone());
         let source_p = if self.source.is_none() {
             ptr::null()
@@ -225,50 +780,35 @@ impl Mountable for MountSpec {
         };
 
         let fstype = unpack(self.fstype.clone());
-        let fstype_p = if self.fstype.is_none() || self.bind {
+        let fstype_p = if self.fstype.is_none() || options.bind {
             ptr::null()
         } else {
             fstype.as_ptr()
         };
 
-        let target = CString::new(self.target.clone())?;
+        let target = CString::new(target)?;
         let target_p = target.as_ptr();
 
-        if self.create_mountpoint {
-            let source_is_file = self.bind
-                && self
-                    .source
-                    .as_deref()
-                    .and_then(|s| std::path::Path::new(s).metadata().ok())
-                    .map(|m| !m.is_dir())
-                    .unwrap_or(false);
-
-            if source_is_file {
-                if let Some(parent) = std::path::Path::new(&self.target).parent() {
-                    fs::create_dir_all(parent)?;
-                }
-                fs::File::create(&self.target)?;
-            } else {
-                fs::create_dir_all(&self.target)?;
-            }
-        }
-
-        let mut flags: c_ulong = libc::MS_SILENT;
+        let mut flags: c_ulong = libc::MS_SILENT | options.flags;
 
-        if self.bind {
+        if options.bind {
             flags |= libc::MS_BIND;
+        } else if options.attrs.set & libc::MOUNT_ATTR_RDONLY != 0 {
+            // Some filesystems can only be mounted read-only in the first
+            // place, e.g. on a read-only block device.
+            flags |= libc::MS_RDONLY;
         }
 
         if self.unshare {
             flags |= libc::MS_PRIVATE;
         }
 
-        if self.recurse {
+        if options.recurse {
             flags |= libc::MS_REC;
         }
 
-        let data_cstr = self
-            .data
+        let data = (!options.data.is_empty()).then(|| options.data.join(","));
+        let data_cstr = data
             .as_ref()
             .map(|d| {
                 CString::new(d.as_str()).map_err(|e| {
@@ -293,42 +833,138 @@ impl Mountable for MountSpec {
                     self.source,
                     self.target,
                     self.fstype,
-                    self.bind,
+                    options.bind,
                     flags,
                     err
                 );
             }
         }
 
-        let mut set: c_ulong = 0;
+        Ok(())
+    }
 
-        if self.safe {
-            set |= libc::MOUNT_ATTR_NOSUID as c_ulong;
-            set |= libc::MOUNT_ATTR_NODEV as c_ulong;
-            set |= libc::MOUNT_ATTR_NOEXEC as c_ulong;
+    /// Mount inside `rootfs`, resolving the target as a container path
+    /// without letting symlinks escape the rootfs.
+    pub fn mount_in(&self, rootfs: &Rootfs) -> Result<()> {
+        let options = self.mount_options();
+
+        let target = if !self.create_mountpoint {
+            rootfs.open_at(&self.target, libc::O_PATH)
+        } else if self.source_is_file(&options) {
+            rootfs.create_file(&self.target)
+        } else {
+            rootfs.create_dir_all(&self.target)
         }
+        .map_err(|e| anyhow!("unable to resolve mount target {}: {e}", self.target))?;
 
-        if self.read_only {
-            set |= libc::MOUNT_ATTR_RDONLY as c_ulong;
+        self.attach(&fd_path(&target), &options)?;
+
+        // The file descriptor still refers to what is now covered by the
+        // mount, so the target is resolved again to reach the mount itself.
+        let mounted = rootfs
+            .open_at(&self.target, libc::O_PATH)
+            .map_err(|e| anyhow!("unable to resolve mount {}: {e}", self.target))?;
+        self.apply_options(&fd_path(&mounted), &options)
+    }
+
+    /// Apply the mount attributes and propagation type to the mount at
+    /// `target`, once it is attached.
+    fn apply_options(&self, target: &str, options: &MountOptions) -> Result<()> {
+        if !options.attrs.is_empty() {
+            options.attrs.apply(target, options.recurse)?;
         }
 
-        if set != 0 {
-            let mut attr: libc::mount_attr = unsafe { std::mem::zeroed() };
-            attr.attr_set = set as u64;
-            attr.attr_clr = 0;
-            attr.propagation = 0;
-            attr.userns_fd = 0;
+        if !options.recursive_attrs.is_empty() {
+            options.recursive_attrs.apply(target, true)?;
+        }
 
-            let mut msaflags: c_uint = 0;
-            if self.recurse {
-                msaflags |= libc::AT_RECURSIVE as c_uint;
-            }
+        match options.propagation {
+            Some(propagation) => set_propagation(target, propagation),
+            None => Ok(()),
+        }
+    }
+
+    /// Clone the source tree into a detached mount, idmap it, and attach it
+    /// at the target.
+    fn mount_idmapped(&self, idmap: &IdMap, target: &str, options: &MountOptions) -> Result<()> {
+        let IdMap::Mappings {
+            uid_mappings,
+            gid_mappings,
+        } = idmap
+        else {
+            bail!(
+                "idmap for {} must be resolved to explicit mappings before mounting",
+                self.target
+            );
+        };
+
+        if !options.bind {
+            bail!("idmapped mount {} must be a bind mount", self.target);
+        }
+
+        let source = self
+            .source
+            .as_deref()
+            .ok_or_else(|| anyhow!("source missing"))?;
 
-            mount_setattr(libc::AT_FDCWD, &self.target, msaflags, &attr).map_err(|e| anyhow!(e))?;
+        let userns = crate::idmap::userns_fd(uid_mappings, gid_mappings)
+            .map_err(|e| anyhow!("unable to create user namespace for idmapping: {e}"))?;
+
+        let mut flags = libc::OPEN_TREE_CLONE | libc::OPEN_TREE_CLOEXEC;
+        if options.recurse {
+            flags |= libc::AT_RECURSIVE as c_uint;
         }
 
+        let tree = open_tree(libc::AT_FDCWD, source, flags)
+            .map_err(|e| anyhow!("unable to clone mount tree at {source}: {e}"))?;
+
+        let mut attr: libc::mount_attr = unsafe { std::mem::zeroed() };
+        attr.attr_set = libc::MOUNT_ATTR_IDMAP;
+        attr.userns_fd = userns.as_raw_fd() as u64;
+        mount_setattr_fd(&tree, options.recurse, &attr)
+            .map_err(|e| anyhow!("unable to idmap mount of {source}: {e}"))?;
+
+        move_mount_fd_to(&tree, target)
+            .map_err(|e| anyhow!("unable to attach idmapped mount at {}: {e}", self.target))?;
+
         Ok(())
     }
+}
+
+impl Mountable for MountSpec {
+    fn seal(&self) -> Result<()> {
+        let tree = open_tree(
+            libc::AT_FDCWD,
+            self.source
+                .as_deref()
+                .ok_or_else(|| anyhow!("source missing"))?,
+            libc::OPEN_TREE_CLOEXEC,
+        )?;
+
+        let mut attr: libc::mount_attr = unsafe { std::mem::zeroed() };
+        attr.attr_set |= libc::MOUNT_ATTR_RDONLY;
+        mount_setattr_fd(&tree, false, &attr)?;
+
+        Ok(())
+    }
+
+    fn mount(&self) -> Result<()> {
+        let options = self.mount_options();
+
+        if self.create_mountpoint {
+            if self.source_is_file(&options) {
+                if let Some(parent) = std::path::Path::new(&self.target).parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::File::create(&self.target)?;
+            } else {
+                fs::create_dir_all(&self.target)?;
+            }
+        }
+
+        self.attach(&self.target, &options)?;
+        self.apply_options(&self.target, &options)
+    }
 
     fn pivot(&self) -> Result<()> {
         let dot = CString::from(c".");
@@ -354,20 +990,75 @@ impl Mountable for MountSpec {
 
 #[cfg(test)]
 mod tests {
-    use super::join_rootfs;
+    use super::{MountAttrs, MountOptions, overlay_options};
+    use crate::config::Propagation;
 
     #[test]
-    fn join_rootfs_avoids_double_separators() {
+    fn overlay_options_are_rendered_in_layer_order() {
+        let lower = vec!["/layers/top".to_string(), "/layers/base".to_string()];
+        assert_eq!(
+            overlay_options(&lower, None, false).unwrap(),
+            "lowerdir=/layers/top:/layers/base"
+        );
         assert_eq!(
-            join_rootfs("/run/root", "/proc/kcore"),
-            "/run/root/proc/kcore"
+            overlay_options(&lower, Some(("/rw/upper", "/rw/work")), true).unwrap(),
+            "lowerdir=/layers/top:/layers/base,upperdir=/rw/upper,workdir=/rw/work,userxattr"
         );
-        // Trailing slash on rootfs and missing leading slash on path.
-        assert_eq!(join_rootfs("/run/root/", "proc/sys"), "/run/root/proc/sys");
-        // rootfs of "/" stays single-separator.
+        assert!(overlay_options(&[], None, false).is_err());
+        assert!(overlay_options(&["/a:b".to_string()], None, false).is_err());
+    }
+
+    #[test]
+    fn mount_options_are_split_like_oci() {
+        let options: Vec<String> = [
+            "rbind",
+            "ro",
+            "nosuid",
+            "dev",
+            "relatime",
+            "rnosymfollow",
+            "dirsync",
+            "rslave",
+            "size=64m",
+            "mode=755",
+        ]
+        .iter()
+        .map(|o| o.to_string())
+        .collect();
+
+        let parsed = MountOptions::parse(&options);
+        assert!(parsed.bind && parsed.recurse);
+        assert_eq!(parsed.flags, libc::MS_DIRSYNC);
+        assert_eq!(
+            parsed.attrs,
+            MountAttrs {
+                set: libc::MOUNT_ATTR_RDONLY | libc::MOUNT_ATTR_NOSUID,
+                clr: libc::MOUNT_ATTR_NODEV | libc::MOUNT_ATTR__ATIME,
+            }
+        );
+        assert_eq!(
+            parsed.recursive_attrs,
+            MountAttrs {
+                set: libc::MOUNT_ATTR_NOSYMFOLLOW,
+                clr: 0,
+            }
+        );
+        assert_eq!(parsed.propagation, Some(Propagation::RSlave));
+        assert_eq!(parsed.data, ["size=64m", "mode=755"]);
+    }
+
+    #[test]
+    fn later_mount_options_override_earlier_ones() {
+        let options: Vec<String> = ["ro", "noatime", "rw", "strictatime"]
+            .iter()
+            .map(|o| o.to_string())
+            .collect();
+
+        let parsed = MountOptions::parse(&options);
+        assert_eq!(parsed.attrs.set, libc::MOUNT_ATTR_STRICTATIME);
         assert_eq!(
-            join_rootfs("/", "/proc/sysrq-trigger"),
-            "/proc/sysrq-trigger"
+            parsed.attrs.clr,
+            libc::MOUNT_ATTR_RDONLY | libc::MOUNT_ATTR__ATIME
         );
     }
 }
@@ -0,0 +1,439 @@
+//! Mutations of the container rootfs, applied before pivot.
+//!
+//! Mutations inject files such as configuration, CA bundles or entrypoint
+//! shims into an existing rootfs, without preparing a custom rootfs per run.
+//! Every container path is resolved through a [`Rootfs`] handle, so symlinks
+//! in the rootfs cannot redirect a mutation onto the host.
+//!
+//! Ownership is given as ids inside the container. In the two-stage path,
+//! mutations happen before the container's user namespace is entered, so an
+//! [`IdTranslator`] maps them to the ids outside of it.
+
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::config::{
+    ChmodMutation, ChownMutation, CopyFromHostMutation, CreateDirMutation, MknodMutation,
+    Mutatable, Mutation, NodeType, RemoveMutation, SymlinkMutation, WriteFileMutation,
+};
+use crate::idmap::IdTranslator;
+use crate::rootfs::{Rootfs, check, fd_path, lstat_at, remove_at};
+
+/// Open the parent directory of the container path `target`, creating it if
+/// needed, and return it along with the final component of `target`.
+fn create_parent(rootfs: &Rootfs, target: &str) -> Result<(OwnedFd, CString)> {
+    let path = Path::new(target);
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("{target} does not name a file");
+    };
+
+    let dir = rootfs.create_dir_all(parent)?;
+    Ok((dir, CString::new(name.as_bytes())?))
+}
+
+/// Make way for a new file at `name` in `dir`, removing any existing file
+/// other than a directory.
+fn replace_at(dir: &OwnedFd, name: &CStr) -> Result<()> {
+    let name = OsStr::from_bytes(name.to_bytes());
+    match lstat_at(dir, name)? {
+        Some(st) if st.st_mode & libc::S_IFMT == libc::S_IFDIR => {
+            bail!("{} is a directory", name.display())
+        }
+        Some(_) => Ok(remove_at(dir, name)?),
+        None => Ok(()),
+    }
+}
+
+/// Create the regular file `name` in `dir`, which must not exist.
+fn create_at(dir: &OwnedFd, name: &CStr, mode: u32) -> io::Result<File> {
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            mode,
+        )
+    };
+    check(fd)?;
+
+    let file = unsafe { File::from_raw_fd(fd) };
+    // The permission bits given to open(2) are subject to the umask.
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    Ok(file)
+}
+
+/// Change the owner of `name` in `dir` to the container ids `uid` and `gid`,
+/// where set. An empty `name` with `AT_EMPTY_PATH` changes `dir` itself.
+fn chown_at(
+    dir: &OwnedFd,
+    name: &CStr,
+    flags: libc::c_int,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    ids: &IdTranslator,
+) -> Result<()> {
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+
+    // -1 leaves an id unchanged.
+    let uid = uid.map(|uid| ids.uid(uid)).transpose()?.unwrap_or(u32::MAX);
+    let gid = gid.map(|gid| ids.gid(gid)).transpose()?.unwrap_or(u32::MAX);
+
+    check(unsafe { libc::fchownat(dir.as_raw_fd(), name.as_ptr(), uid, gid, flags) })?;
+    Ok(())
+}
+
+/// Decode standard or URL-safe base64, with or without padding. Input which
+/// isn't canonical, e.g. with data after the padding or stray bits in the
+/// last character, is rejected.
+fn decode_base64(input: &str) -> Result<Vec<u8>> {
+    let input: Vec<u8> = input.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    let data = input
+        .strip_suffix(b"==")
+        .or_else(|| input.strip_suffix(b"="));
+    let padded = data.is_some();
+    let data = data.unwrap_or(&input);
+
+    if data.len() % 4 == 1 || (padded && !input.len().is_multiple_of(4)) {
+        bail!("invalid base64 length {}", input.len());
+    }
+
+    let mut decoded = Vec::with_capacity(data.len() / 4 * 3);
+    let (mut acc, mut bits) = (0u32, 0);
+
+    for &c in data {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => bail!("invalid base64 padding"),
+            _ => bail!("invalid base64 character {:?}", c as char),
+        };
+
+        acc = (acc << 6 | value as u32) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+        }
+    }
+
+    if acc & ((1 << bits) - 1) != 0 {
+        bail!("invalid base64 trailing bits");
+    }
+
+    Ok(decoded)
+}
+
+impl Mutatable for Mutation {
+    fn mutate(&self, rootfs: &Rootfs, ids: &IdTranslator) -> Result<()> {
+        match self {
+            Mutation::CreateDir(m) => m.mutate(rootfs, ids),
+            Mutation::WriteFile(m) => m.mutate(rootfs, ids),
+            Mutation::Symlink(m) => m.mutate(rootfs, ids),
+            Mutation::Chmod(m) => m.mutate(rootfs, ids),
+            Mutation::Chown(m) => m.mutate(rootfs, ids),
+            Mutation::CopyFromHost(m) => m.mutate(rootfs, ids),
+            Mutation::Remove(m) => m.mutate(rootfs, ids),
+            Mutation::Mknod(m) => m.mutate(rootfs, ids),
+        }
+    }
+}
+
+impl Mutatable for CreateDirMutation {
+    fn mutate(&self, rootfs: &Rootfs, _ids: &IdTranslator) -> Result<()> {
+        rootfs
+            .create_dir_all(&self.target)
+            .map_err(|e| anyhow!("unable to create directory {}: {e}", self.target))?;
+        Ok(())
+    }
+}
+
+impl Mutatable for WriteFileMutation {
+    fn mutate(&self, rootfs: &Rootfs, ids: &IdTranslator) -> Result<()> {
+        let content = match (&self.content, &self.content_base64) {
+            (Some(_), Some(_)) => bail!("content and content_base64 are mutually exclusive"),
+            (Some(content), None) => content.as_bytes().to_vec(),
+            (None, Some(encoded)) => decode_base64(encoded)
+                .map_err(|e| anyhow!("content_base64 of {} is invalid: {e}", self.target))?,
+            (None, None) => Vec::new(),
+        };
+
+        let write = || -> Result<()> {
+            let (dir, name) = create_parent(rootfs, &self.target)?;
+            replace_at(&dir, &name)?;
+            create_at(&dir, &name, self.mode.unwrap_or(0o644))?.write_all(&content)?;
+            chown_at(
+                &dir,
+                &name,
+                libc::AT_SYMLINK_NOFOLLOW,
+                self.uid,
+                self.gid,
+                ids,
+            )
+        };
+        write().map_err(|e| anyhow!("unable to write {}: {e}", self.target))
+    }
+}
+
+impl Mutatable for SymlinkMutation {
+    fn mutate(&self, rootfs: &Rootfs, _ids: &IdTranslator) -> Result<()> {
+        let link = || -> Result<()> {
+            let (dir, name) = create_parent(rootfs, &self.target)?;
+            replace_at(&dir, &name)?;
+            let source = CString::new(self.source.as_str())?;
+            check(unsafe { libc::symlinkat(source.as_ptr(), dir.as_raw_fd(), name.as_ptr()) })?;
+            Ok(())
+        };
+        link().map_err(|e| anyhow!("unable to create symlink {}: {e}", self.target))
+    }
+}
+
+impl Mutatable for ChmodMutation {
+    fn mutate(&self, rootfs: &Rootfs, _ids: &IdTranslator) -> Result<()> {
+        let chmod = || -> Result<()> {
+            let fd = rootfs.open_at(&self.target, libc::O_PATH)?;
+            fs::set_permissions(fd_path(&fd), fs::Permissions::from_mode(self.mode))?;
+            Ok(())
+        };
+        chmod().map_err(|e| anyhow!("unable to chmod {}: {e}", self.target))
+    }
+}
+
+impl Mutatable for ChownMutation {
+    fn mutate(&self, rootfs: &Rootfs, ids: &IdTranslator) -> Result<()> {
+        let chown = || -> Result<()> {
+            let fd = rootfs.open_at(&self.target, libc::O_PATH)?;
+            chown_at(&fd, c"", libc::AT_EMPTY_PATH, self.uid, self.gid, ids)
+        };
+        chown().map_err(|e| anyhow!("unable to chown {}: {e}", self.target))
+    }
+}
+
+impl CopyFromHostMutation {
+    /// Copy the host file `source` to `name` in `dir`.
+    fn copy(&self, source: &Path, dir: &OwnedFd, name: &CStr, ids: &IdTranslator) -> Result<()> {
+        let meta = fs::symlink_metadata(source)
+            .map_err(|e| anyhow!("unable to stat {}: {e}", source.display()))?;
+        let mode = meta.mode() & 0o7777;
+        let file_type = meta.file_type();
+
+        if file_type.is_dir() {
+            let existing = lstat_at(dir, OsStr::from_bytes(name.to_bytes()))?;
+            if existing.is_none_or(|st| st.st_mode & libc::S_IFMT != libc::S_IFDIR) {
+                replace_at(dir, name)?;
+                check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o700) })?;
+            }
+
+            let fd = unsafe {
+                libc::openat(
+                    dir.as_raw_fd(),
+                    name.as_ptr(),
+                    libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                )
+            };
+            check(fd)?;
+            let subdir = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            for entry in fs::read_dir(source)? {
+                let entry = entry?;
+                let child = CString::new(entry.file_name().as_bytes())?;
+                self.copy(&entry.path(), &subdir, &child, ids)?;
+            }
+
+            fs::set_permissions(fd_path(&subdir), fs::Permissions::from_mode(mode))?;
+        } else if file_type.is_file() {
+            replace_at(dir, name)?;
+            let mut target = create_at(dir, name, mode)?;
+            io::copy(&mut File::open(source)?, &mut target)?;
+        } else if file_type.is_symlink() {
+            replace_at(dir, name)?;
+            let link = CString::new(fs::read_link(source)?.as_os_str().as_bytes())?;
+            check(unsafe { libc::symlinkat(link.as_ptr(), dir.as_raw_fd(), name.as_ptr()) })?;
+        } else {
+            bail!("{} is not a file, directory or symlink", source.display());
+        }
+
+        chown_at(
+            dir,
+            name,
+            libc::AT_SYMLINK_NOFOLLOW,
+            self.uid,
+            self.gid,
+            ids,
+        )
+    }
+}
+
+impl Mutatable for CopyFromHostMutation {
+    fn mutate(&self, rootfs: &Rootfs, ids: &IdTranslator) -> Result<()> {
+        let copy = || -> Result<()> {
+            let (dir, name) = create_parent(rootfs, &self.target)?;
+            self.copy(Path::new(&self.source), &dir, &name, ids)
+        };
+        copy().map_err(|e| anyhow!("unable to copy {} to {}: {e}", self.source, self.target))
+    }
+}
+
+impl Mutatable for RemoveMutation {
+    fn mutate(&self, rootfs: &Rootfs, _ids: &IdTranslator) -> Result<()> {
+        let remove = || -> Result<()> {
+            let (dir, name) = match rootfs.open_parent(&self.target) {
+                Ok(parent) => parent,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            Ok(remove_at(&dir, OsStr::from_bytes(name.to_bytes()))?)
+        };
+        remove().map_err(|e| anyhow!("unable to remove {}: {e}", self.target))
+    }
+}
+
+impl Mutatable for MknodMutation {
+    fn mutate(&self, rootfs: &Rootfs, ids: &IdTranslator) -> Result<()> {
+        let (kind, dev) = match self.kind {
+            NodeType::Char => (libc::S_IFCHR, libc::makedev(self.major, self.minor)),
+            NodeType::Block => (libc::S_IFBLK, libc::makedev(self.major, self.minor)),
+            NodeType::Fifo => (libc::S_IFIFO, 0),
+        };
+        let mode = self.mode.unwrap_or(0o666);
+
+        let mknod = || -> Result<()> {
+            let (dir, name) = create_parent(rootfs, &self.target)?;
+            replace_at(&dir, &name)?;
+            check(unsafe { libc::mknodat(dir.as_raw_fd(), name.as_ptr(), kind | mode, dev) })?;
+            // mknod(2) is subject to the umask.
+            check(unsafe { libc::fchmodat(dir.as_raw_fd(), name.as_ptr(), mode, 0) })?;
+            chown_at(
+                &dir,
+                &name,
+                libc::AT_SYMLINK_NOFOLLOW,
+                self.uid,
+                self.gid,
+                ids,
+            )
+        };
+        mknod().map_err(|e| anyhow!("unable to create {}: {e}", self.target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_base64;
+    use crate::config::{
+        CopyFromHostMutation, MknodMutation, Mutatable, Mutation, NodeType, RemoveMutation,
+        SymlinkMutation, WriteFileMutation,
+    };
+    use crate::idmap::IdTranslator;
+    use crate::rootfs::Rootfs;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    #[test]
+    fn base64_is_decoded_with_either_alphabet() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("aGVsbG8").unwrap(), b"hello");
+        assert_eq!(
+            decode_base64("+/8=\n").unwrap(),
+            decode_base64("-_8").unwrap()
+        );
+        assert_eq!(decode_base64("+/8=").unwrap(), [0xfb, 0xff]);
+        assert!(decode_base64("a*b=").is_err());
+        assert_eq!(decode_base64("").unwrap(), b"");
+
+        // Only canonical encodings are accepted.
+        assert!(decode_base64("aGVsbG8=aGVs").is_err());
+        assert!(decode_base64("aGVsbG8==").is_err());
+        assert!(decode_base64("aGVsb").is_err());
+        assert!(decode_base64("aGVsbG9=").is_err());
+        assert!(decode_base64("aGk").is_ok());
+        assert!(decode_base64("aGl").is_err());
+        assert!(decode_base64("=").is_err());
+    }
+
+    #[test]
+    fn mutations_are_applied_inside_the_rootfs() {
+        let (Ok(host), Ok(root)) = (tempfile::TempDir::new(), tempfile::TempDir::new()) else {
+            return;
+        };
+        let prepared = std::fs::create_dir_all(host.path().join("certs/extra"))
+            .and_then(|_| std::fs::write(host.path().join("certs/extra/ca.pem"), "ca"))
+            .and_then(|_| {
+                std::os::unix::fs::symlink("ca.pem", host.path().join("certs/extra/link"))
+            })
+            .and_then(|_| std::fs::create_dir(root.path().join("etc")))
+            .and_then(|_| std::fs::write(root.path().join("stale"), "stale"))
+            .and_then(|_| std::os::unix::fs::symlink("/etc", root.path().join("escape")));
+        if prepared.is_err() {
+            return;
+        }
+        let rootfs = Rootfs::open(&root.path().to_string_lossy()).expect("open rootfs");
+
+        let mutations = vec![
+            Mutation::WriteFile(WriteFileMutation {
+                target: "/escape/motd".to_string(),
+                content_base64: Some("aGVsbG8=".to_string()),
+                mode: Some(0o600),
+                ..Default::default()
+            }),
+            Mutation::Symlink(SymlinkMutation {
+                source: "/usr/bin/busybox".to_string(),
+                target: "/bin/sh".to_string(),
+            }),
+            Mutation::CopyFromHost(CopyFromHostMutation {
+                source: host.path().join("certs").to_string_lossy().into_owned(),
+                target: "/usr/share/certs".to_string(),
+                ..Default::default()
+            }),
+            Mutation::Mknod(MknodMutation {
+                target: "/run/fifo".to_string(),
+                kind: NodeType::Fifo,
+                ..Default::default()
+            }),
+            Mutation::Remove(RemoveMutation {
+                target: "/stale".to_string(),
+            }),
+            Mutation::Remove(RemoveMutation {
+                target: "/missing/file".to_string(),
+            }),
+        ];
+        for mutation in &mutations {
+            mutation
+                .mutate(&rootfs, &IdTranslator::default())
+                .expect("mutation");
+        }
+
+        let motd = root.path().join("etc/motd");
+        assert_eq!(std::fs::read_to_string(&motd).unwrap(), "hello");
+        assert_eq!(
+            std::fs::metadata(&motd).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            std::fs::read_link(root.path().join("bin/sh")).unwrap(),
+            std::path::Path::new("/usr/bin/busybox")
+        );
+        let certs = root.path().join("usr/share/certs/extra");
+        assert_eq!(std::fs::read_to_string(certs.join("ca.pem")).unwrap(), "ca");
+        assert_eq!(
+            std::fs::read_link(certs.join("link")).unwrap(),
+            std::path::Path::new("ca.pem")
+        );
+        assert!(
+            std::fs::symlink_metadata(root.path().join("run/fifo"))
+                .unwrap()
+                .file_type()
+                .is_fifo()
+        );
+        assert!(!root.path().join("stale").exists());
+    }
+}
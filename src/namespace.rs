@@ -1,5 +1,23 @@
-use std::ffi::c_int;
+//! Namespace types, and pinning namespaces to paths so that they outlive
+//! the processes in them.
+//!
+//! A namespace is pinned by bind-mounting its nsfs file,
+//! `/proc/<pid>/ns/<type>`, like `ip netns` does. The bind mount must be made
+//! in the host's mount namespace, but by the time the workload is in its
+//! namespaces, the supervisor is in the workload's own. So a [`PinHelper`]
+//! process is forked before any namespaces are unshared, and makes the bind
+//! mounts once it is told the workload's pid.
 
+use std::ffi::{CString, c_int};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process;
+
+use anyhow::{Result, anyhow, bail};
+use log::debug;
+use nix::sys::wait::waitpid;
+use nix::unistd::{ForkResult, Pid, fork};
 use serde::{Deserialize, Serialize};
 
 /// Unshare the time namespace, so that the calling process has a new time
@@ -109,6 +127,29 @@ pub enum Namespace {
     Time,
 }
 
+/// Where an existing namespace is joined from.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind")]
+pub enum NamespaceSource {
+    /// A namespace file, either `/proc/<pid>/ns/<type>` or a bind mount of
+    /// one.
+    Path { path: String },
+
+    /// The namespace of a running process.
+    Pid { pid: libc::pid_t },
+}
+
+/// An existing namespace to join instead of creating a new one.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NamespaceJoin {
+    /// The type of the namespace. It is checked against the namespace
+    /// referred to by `source` when joining.
+    pub namespace: Namespace,
+
+    /// The namespace to join.
+    pub source: NamespaceSource,
+}
+
 pub fn to_clone_flags(ns: Namespace) -> c_int {
     match ns {
         Namespace::Mount => CLONE_NEWNS,
@@ -121,3 +162,265 @@ pub fn to_clone_flags(ns: Namespace) -> c_int {
         Namespace::Time => CLONE_NEWTIME,
     }
 }
+
+/// The name of a namespace's file in `/proc/<pid>/ns`.
+pub fn proc_name(ns: Namespace) -> &'static str {
+    match ns {
+        Namespace::Mount => "mnt",
+        Namespace::Uts => "uts",
+        Namespace::Ipc => "ipc",
+        Namespace::User => "user",
+        Namespace::Pid => "pid",
+        Namespace::Net => "net",
+        Namespace::Cgroup => "cgroup",
+        Namespace::Time => "time",
+    }
+}
+
+/// A namespace of the workload to pin to a path.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NamespacePin {
+    /// The namespace to pin.
+    pub namespace: Namespace,
+
+    /// The path to bind-mount the namespace onto. The file is created if it
+    /// does not exist. It can later be joined with
+    /// [`NamespaceSource::Path`], and released with [`unpin`].
+    pub path: String,
+}
+
+/// The filesystem type of nsfs files, from <linux/magic.h>.
+const NSFS_MAGIC: libc::c_long = 0x6e736673;
+
+fn is_pinned(path: &str) -> Result<bool> {
+    let cpath = CString::new(path)?;
+    let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(cpath.as_ptr(), &mut st) } < 0 {
+        bail!("unable to stat {path}: {}", io::Error::last_os_error());
+    }
+    Ok(st.f_type as libc::c_long == NSFS_MAGIC)
+}
+
+/// Pin the namespaces of `pid` to paths by bind-mounting their nsfs files.
+/// If any of them fails, those already pinned are unpinned again.
+pub fn pin(pid: libc::pid_t, pins: &[NamespacePin]) -> Result<()> {
+    for (i, pin) in pins.iter().enumerate() {
+        if let Err(e) = pin_one(pid, pin) {
+            for pinned in &pins[..i] {
+                let _ = unpin(&pinned.path);
+            }
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+fn pin_one(pid: libc::pid_t, pin: &NamespacePin) -> Result<()> {
+    let source = format!("/proc/{pid}/ns/{}", proc_name(pin.namespace));
+    debug!("pinning {source} to {}", pin.path);
+
+    let created = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o444)
+        .open(&pin.path)
+    {
+        Ok(_) => true,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            if is_pinned(&pin.path)? {
+                bail!("{} already pins a namespace", pin.path);
+            }
+            false
+        }
+        Err(e) => bail!("unable to create {}: {e}", pin.path),
+    };
+
+    let csource = CString::new(source.as_str())?;
+    let ctarget = CString::new(pin.path.as_str())?;
+    if unsafe {
+        libc::mount(
+            csource.as_ptr(),
+            ctarget.as_ptr(),
+            std::ptr::null(),
+            libc::MS_BIND,
+            std::ptr::null(),
+        )
+    } < 0
+    {
+        let err = io::Error::last_os_error();
+        if created {
+            let _ = fs::remove_file(&pin.path);
+        }
+        bail!("unable to pin {source} to {}: {err}", pin.path);
+    }
+
+    Ok(())
+}
+
+/// Release a namespace pinned to `path`, and remove the file it was pinned
+/// onto. The namespace is destroyed once no process or other pin uses it.
+pub fn unpin(path: &str) -> Result<()> {
+    if !is_pinned(path)? {
+        bail!("{path} is not a pinned namespace");
+    }
+
+    let cpath = CString::new(path)?;
+    if unsafe { libc::umount2(cpath.as_ptr(), libc::MNT_DETACH) } < 0 {
+        bail!("unable to unpin {path}: {}", io::Error::last_os_error());
+    }
+    fs::remove_file(path).map_err(|e| anyhow!("unable to remove {path}: {e}"))?;
+
+    Ok(())
+}
+
+/// A process, forked before any namespaces are unshared, which pins the
+/// namespaces of the workload once given its pid.
+pub struct PinHelper {
+    pid: Pid,
+    request: io::PipeWriter,
+    response: io::PipeReader,
+}
+
+impl PinHelper {
+    /// Fork the helper process.
+    pub fn spawn(pins: &[NamespacePin]) -> Result<PinHelper> {
+        let (mut request_rx, request) = io::pipe()?;
+        let (response, mut response_tx) = io::pipe()?;
+
+        match unsafe { fork() }? {
+            ForkResult::Parent { child } => {
+                drop(request_rx);
+                drop(response_tx);
+
+                debug!("namespace pin helper pid = {child}");
+                Ok(PinHelper {
+                    pid: child,
+                    request,
+                    response,
+                })
+            }
+            ForkResult::Child => {
+                drop(request);
+                drop(response);
+
+                // As with the id map helper, the request is a single line,
+                // since the workload also inherits the supervisor's end.
+                let mut target = String::new();
+                let result = BufReader::new(&mut request_rx)
+                    .read_line(&mut target)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| Ok(target.trim().parse::<libc::pid_t>()?))
+                    .and_then(|pid| pin(pid, pins));
+
+                let code = match result {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        let _ = response_tx.write_all(e.to_string().as_bytes());
+                        1
+                    }
+                };
+                process::exit(code)
+            }
+        }
+    }
+
+    /// Pin the namespaces of `pid`, a pid in the helper's (the host's) pid
+    /// namespace.
+    pub fn pin(self, pid: libc::pid_t) -> Result<()> {
+        let PinHelper {
+            pid: helper,
+            mut request,
+            mut response,
+        } = self;
+
+        request.write_all(format!("{pid}\n").as_bytes())?;
+        drop(request);
+
+        let mut error = String::new();
+        response.read_to_string(&mut error)?;
+        waitpid(helper, None)?;
+
+        if !error.is_empty() {
+            bail!("failed to pin namespaces of pid {pid}: {error}");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unshare::{join, unshare};
+    use nix::sys::signal::{Signal, kill};
+    use nix::sys::wait::WaitStatus;
+    use nix::unistd::geteuid;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn only_pinned_namespaces_are_unpinned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("net");
+        fs::write(&path, "").unwrap();
+        assert!(unpin(path.to_str().unwrap()).is_err());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn root_only_pinned_namespace_outlives_its_process() {
+        if !geteuid().is_root() {
+            return;
+        }
+
+        let (mut ready, mut owner) = UnixStream::pair().unwrap();
+        let target = match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => {
+                let ok = unshare(&[Namespace::Uts]).is_ok()
+                    && unsafe { libc::sethostname(c"styropinned".as_ptr(), 11) } == 0;
+                let _ = owner.write_all(if ok { b"y" } else { b"n" });
+                let _ = owner.read(&mut [0]);
+                unsafe { libc::_exit(0) }
+            }
+            ForkResult::Parent { child } => child,
+        };
+        let mut status = [0];
+        ready.read_exact(&mut status).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("uts").to_str().unwrap().to_string();
+        let pins = [NamespacePin {
+            namespace: Namespace::Uts,
+            path: path.clone(),
+        }];
+        let pinned = pin(target.as_raw(), &pins);
+        let pinned_twice = pin(target.as_raw(), &pins);
+        let _ = kill(target, Signal::SIGKILL);
+        let _ = waitpid(target, None);
+
+        let joined = match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => {
+                let source = NamespaceSource::Path { path: path.clone() };
+                let ok = join(&[NamespaceJoin {
+                    namespace: Namespace::Uts,
+                    source,
+                }])
+                .is_ok()
+                    && fs::read_to_string("/proc/sys/kernel/hostname")
+                        .is_ok_and(|name| name == "styropinned\n");
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            ForkResult::Parent { child } => {
+                matches!(waitpid(child, None), Ok(WaitStatus::Exited(_, 0)))
+            }
+        };
+        let unpinned = unpin(&path);
+
+        assert_eq!(&status, b"y");
+        assert!(pinned.is_ok(), "{pinned:?}");
+        assert!(pinned_twice.is_err());
+        assert!(joined);
+        assert!(unpinned.is_ok(), "{unpinned:?}");
+        assert!(!fs::exists(&path).unwrap());
+    }
+}
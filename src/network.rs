@@ -0,0 +1,946 @@
+//! Network namespace setup over rtnetlink.
+//!
+//! A new network namespace starts out with only a loopback interface, which
+//! is down. The supervisor brings it up and, if asked to, connects the
+//! workload to the host with a veth pair: one end is created directly inside
+//! the workload's namespace and configured with addresses, routes and an
+//! MTU, the other stays on the host, optionally attached to a bridge.
+//!
+//! Everything is done with `NETLINK_ROUTE` requests on raw sockets, without
+//! external binaries. A netlink socket operates on the network namespace it
+//! was created in, so the supervisor opens one for the host before it
+//! unshares the workload's namespace, and one for the workload afterwards.
+
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use anyhow::{Result, anyhow, bail};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+/// The network configuration of a workload with its own network namespace.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    /// Whether to bring up the loopback interface. Defaults to true.
+    #[serde(default)]
+    pub loopback: Option<bool>,
+
+    /// An optional veth pair connecting the workload to the host.
+    #[serde(default)]
+    pub veth: Option<VethConfig>,
+
+    /// An optional user-mode network stack connecting the workload to the
+    /// host without privileges, instead of a veth pair.
+    #[serde(default)]
+    pub slirp: Option<SlirpConfig>,
+}
+
+/// The user-mode network. The workload gets a TAP device, `tap0`, with the
+/// address [`SLIRP_GUEST_ADDRESS`] and a default route through
+/// [`SLIRP_GATEWAY`]. Its TCP connections and UDP datagrams are re-created
+/// as sockets on the host by a process which stays in the host's network
+/// namespace; see the `slirp` module.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SlirpConfig {
+    /// The MTU of the TAP device. Defaults to 1500.
+    #[serde(default)]
+    pub mtu: Option<u32>,
+
+    /// Whether connections to [`SLIRP_GATEWAY`] reach the host's loopback
+    /// interface. Defaults to true.
+    #[serde(default)]
+    pub host_loopback: Option<bool>,
+
+    /// Ports on the host forwarded to the workload.
+    #[serde(default)]
+    pub port_forwards: Vec<PortForward>,
+}
+
+/// A transport protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+/// A port on the host forwarded to a port of the workload.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PortForward {
+    /// The protocol to forward. Defaults to TCP.
+    #[serde(default)]
+    pub protocol: Protocol,
+
+    /// The host address to listen on. Defaults to `127.0.0.1`.
+    #[serde(default)]
+    pub host_address: Option<String>,
+
+    /// The host port to listen on.
+    pub host_port: u16,
+
+    /// The port in the workload to forward to.
+    pub guest_port: u16,
+}
+
+/// The name of the user-mode network's TAP device.
+pub const SLIRP_TAP_NAME: &str = "tap0";
+
+/// The address of the workload on the user-mode network.
+pub const SLIRP_GUEST_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 100);
+
+/// The gateway of the user-mode network, which is also the host.
+pub const SLIRP_GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+
+/// The DNS server of the user-mode network, forwarding to the host's.
+pub const SLIRP_DNS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+
+/// The prefix length of the user-mode network.
+pub const SLIRP_PREFIX_LEN: u8 = 24;
+
+/// A veth pair with one end in the workload's network namespace.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct VethConfig {
+    /// The name of the end inside the workload. Defaults to `eth0`.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// The name of the end on the host. Defaults to `veth` followed by the
+    /// pid of the workload's first process.
+    #[serde(default)]
+    pub host_name: Option<String>,
+
+    /// A bridge on the host to attach the host end to.
+    #[serde(default)]
+    pub bridge: Option<String>,
+
+    /// Addresses of the end inside the workload, in CIDR notation, like
+    /// `10.88.0.2/16` or `fd00::2/64`.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+
+    /// Routes through the end inside the workload.
+    #[serde(default)]
+    pub routes: Vec<RouteSpec>,
+
+    /// The MTU of both ends. Defaults to the kernel's default.
+    #[serde(default)]
+    pub mtu: Option<u32>,
+}
+
+/// A route through an interface.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RouteSpec {
+    /// The destination in CIDR notation, or `default` for the default route
+    /// of the gateway's address family.
+    pub destination: String,
+
+    /// The gateway. If unset, the destination is directly reachable
+    /// through the interface.
+    #[serde(default)]
+    pub gateway: Option<String>,
+}
+
+/// An address and prefix length.
+type Cidr = (IpAddr, u8);
+
+/// Parse an address in CIDR notation.
+fn parse_cidr(cidr: &str) -> Result<Cidr> {
+    let (address, prefix) = cidr
+        .split_once('/')
+        .ok_or_else(|| anyhow!("address {cidr} has no prefix length"))?;
+    let address: IpAddr = address
+        .parse()
+        .map_err(|e| anyhow!("invalid address {cidr}: {e}"))?;
+    let prefix: u8 = prefix
+        .parse()
+        .map_err(|e| anyhow!("invalid prefix length in {cidr}: {e}"))?;
+
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    if prefix > max {
+        bail!("prefix length of {cidr} is longer than {max}");
+    }
+
+    Ok((address, prefix))
+}
+
+fn check_ifname(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() >= libc::IFNAMSIZ
+        || name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace())
+    {
+        bail!("invalid interface name '{name}'");
+    }
+    Ok(())
+}
+
+impl RouteSpec {
+    fn parse(&self) -> Result<(Option<Cidr>, Option<IpAddr>)> {
+        let destination = match self.destination.as_str() {
+            "default" => None,
+            cidr => Some(parse_cidr(cidr)?),
+        };
+        let gateway = self
+            .gateway
+            .as_deref()
+            .map(|gw| {
+                gw.parse::<IpAddr>()
+                    .map_err(|e| anyhow!("invalid gateway {gw}: {e}"))
+            })
+            .transpose()?;
+
+        if destination.is_none() && gateway.is_none() {
+            bail!("default route needs a gateway");
+        }
+        if let (Some((dst, _)), Some(gw)) = (destination, gateway)
+            && dst.is_ipv4() != gw.is_ipv4()
+        {
+            bail!("route to {} mixes address families", self.destination);
+        }
+
+        Ok((destination, gateway))
+    }
+}
+
+impl NetworkConfig {
+    /// Check the configuration, so mistakes are reported before any
+    /// namespace is set up.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(slirp) = &self.slirp {
+            if self.veth.is_some() {
+                bail!("a veth pair and the user-mode network are mutually exclusive");
+            }
+            for forward in &slirp.port_forwards {
+                if let Some(address) = &forward.host_address {
+                    address
+                        .parse::<IpAddr>()
+                        .map_err(|e| anyhow!("invalid port forward address {address}: {e}"))?;
+                }
+            }
+        }
+
+        let Some(veth) = &self.veth else {
+            return Ok(());
+        };
+
+        for name in [&veth.name, &veth.host_name, &veth.bridge]
+            .into_iter()
+            .flatten()
+        {
+            check_ifname(name)?;
+        }
+        for address in &veth.addresses {
+            parse_cidr(address)?;
+        }
+        for route in &veth.routes {
+            route.parse()?;
+        }
+
+        Ok(())
+    }
+}
+
+// Not exported by the libc crate.
+const VETH_INFO_PEER: u16 = 1;
+const NLMSGERR_ATTR_MSG: u16 = 1;
+
+/// `struct ifinfomsg`.
+#[repr(C)]
+#[derive(Default)]
+struct IfInfoMsg {
+    family: u8,
+    pad: u8,
+    kind: u16,
+    index: i32,
+    flags: u32,
+    change: u32,
+}
+
+/// `struct ifaddrmsg`.
+#[repr(C)]
+#[derive(Default)]
+struct IfAddrMsg {
+    family: u8,
+    prefixlen: u8,
+    flags: u8,
+    scope: u8,
+    index: u32,
+}
+
+/// `struct rtmsg`.
+#[repr(C)]
+#[derive(Default)]
+struct RtMsg {
+    family: u8,
+    dst_len: u8,
+    src_len: u8,
+    tos: u8,
+    table: u8,
+    protocol: u8,
+    scope: u8,
+    kind: u8,
+    flags: u32,
+}
+
+fn bytes_of<T>(value: &T) -> &[u8] {
+    // SAFETY: only used on the padding-free #[repr(C)] headers above.
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Round up to the 4 byte alignment of netlink messages and attributes.
+const fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn family(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn address_bytes(address: &IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    }
+}
+
+/// A netlink request under construction: a `struct nlmsghdr`, a family
+/// specific header and attributes.
+struct Request {
+    buf: Vec<u8>,
+}
+
+impl Request {
+    fn new<T>(kind: u16, flags: libc::c_int, header: &T) -> Request {
+        let nlmsghdr = libc::nlmsghdr {
+            nlmsg_len: 0,
+            nlmsg_type: kind,
+            nlmsg_flags: (libc::NLM_F_REQUEST | flags) as u16,
+            nlmsg_seq: 0,
+            nlmsg_pid: 0,
+        };
+
+        let mut request = Request { buf: Vec::new() };
+        request.put(bytes_of(&nlmsghdr));
+        request.put(bytes_of(header));
+        request
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    fn attr(&mut self, kind: u16, data: &[u8]) -> &mut Request {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.put(data);
+        self
+    }
+
+    fn attr_str(&mut self, kind: u16, value: &str) -> &mut Request {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(kind, &data)
+    }
+
+    fn attr_u32(&mut self, kind: u16, value: u32) -> &mut Request {
+        self.attr(kind, &value.to_ne_bytes())
+    }
+
+    /// Add an attribute containing whatever `f` adds.
+    fn nested(&mut self, kind: u16, f: impl FnOnce(&mut Request)) -> &mut Request {
+        let start = self.buf.len();
+        self.attr(kind, &[]);
+        f(self);
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        self
+    }
+}
+
+/// Iterate over the `(type, payload)` of the attributes in `buf`.
+fn attrs(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]);
+        if len < 4 || len > buf.len() {
+            return None;
+        }
+        let payload = &buf[4..len];
+        buf = &buf[align(len).min(buf.len())..];
+        Some((kind & !(libc::NLA_F_NESTED as u16), payload))
+    })
+}
+
+/// An interface, as reported by the kernel.
+#[derive(Debug)]
+pub struct Link {
+    pub index: u32,
+    pub flags: u32,
+    pub mtu: Option<u32>,
+    /// The index of the bridge the interface is attached to.
+    pub master: Option<u32>,
+}
+
+/// A `NETLINK_ROUTE` socket, bound to the network namespace it was opened
+/// in.
+#[derive(Debug)]
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    /// Open a socket on the current network namespace.
+    pub fn open() -> Result<Netlink> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            bail!(
+                "unable to open rtnetlink socket: {}",
+                io::Error::last_os_error()
+            );
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // Ask for error messages, without the request echoed back.
+        for option in [libc::NETLINK_EXT_ACK, libc::NETLINK_CAP_ACK] {
+            let one: libc::c_int = 1;
+            unsafe {
+                libc::setsockopt(
+                    fd.as_raw_fd(),
+                    libc::SOL_NETLINK,
+                    option,
+                    &one as *const libc::c_int as *const libc::c_void,
+                    size_of::<libc::c_int>() as libc::socklen_t,
+                );
+            }
+        }
+
+        Ok(Netlink { fd, seq: 0 })
+    }
+
+    /// Send `request`, and return the payload of the reply, or nothing if
+    /// the kernel only acknowledged it.
+    fn transact(&mut self, mut request: Request) -> Result<Vec<u8>> {
+        self.seq += 1;
+        let len = request.buf.len() as u32;
+        request.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        request.buf[8..12].copy_from_slice(&self.seq.to_ne_bytes());
+
+        let ret = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                request.buf.as_ptr() as *const libc::c_void,
+                request.buf.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            bail!("netlink send failed: {}", io::Error::last_os_error());
+        }
+
+        let hdrlen = size_of::<libc::nlmsghdr>();
+        let mut buf = vec![0u8; 32768];
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                bail!("netlink recv failed: {}", io::Error::last_os_error());
+            }
+
+            let mut msgs = &buf[..len as usize];
+            while msgs.len() >= hdrlen {
+                let msg_len = u32::from_ne_bytes(msgs[0..4].try_into()?) as usize;
+                let msg_type = u16::from_ne_bytes(msgs[4..6].try_into()?);
+                let msg_flags = u16::from_ne_bytes(msgs[6..8].try_into()?);
+                let msg_seq = u32::from_ne_bytes(msgs[8..12].try_into()?);
+                if msg_len < hdrlen || msg_len > msgs.len() {
+                    bail!("truncated netlink message");
+                }
+                let payload = &msgs[hdrlen..msg_len];
+                msgs = &msgs[align(msg_len).min(msgs.len())..];
+
+                if msg_seq != self.seq {
+                    continue;
+                }
+                if msg_type as libc::c_int != libc::NLMSG_ERROR {
+                    return Ok(payload.to_vec());
+                }
+
+                let errno = i32::from_ne_bytes(payload[0..4].try_into()?);
+                if errno == 0 {
+                    return Ok(Vec::new());
+                }
+
+                // The echoed request header is followed by the extended
+                // acknowledgement attributes.
+                let err = io::Error::from_raw_os_error(-errno);
+                let message = if msg_flags as libc::c_int & libc::NLM_F_ACK_TLVS != 0 {
+                    attrs(&payload[(4 + hdrlen).min(payload.len())..])
+                        .find(|(kind, _)| *kind == NLMSGERR_ATTR_MSG)
+                        .map(|(_, msg)| {
+                            String::from_utf8_lossy(msg)
+                                .trim_end_matches('\0')
+                                .to_string()
+                        })
+                } else {
+                    None
+                };
+                return Err(match message {
+                    Some(message) => anyhow!("{err} ({message})"),
+                    None => anyhow!("{err}"),
+                });
+            }
+        }
+    }
+
+    /// Send `request`, expecting only an acknowledgement.
+    fn execute(&mut self, request: Request) -> Result<()> {
+        self.transact(request).map(|_| ())
+    }
+
+    /// Look up the interface called `name`.
+    pub fn link(&mut self, name: &str) -> Result<Link> {
+        let mut request = Request::new(libc::RTM_GETLINK, 0, &IfInfoMsg::default());
+        request.attr_str(libc::IFLA_IFNAME, name);
+        let reply = self
+            .transact(request)
+            .map_err(|e| anyhow!("unable to find interface {name}: {e}"))?;
+
+        if reply.len() < size_of::<IfInfoMsg>() {
+            bail!("short reply looking up interface {name}");
+        }
+        let attr_u32 = |attr| {
+            attrs(&reply[size_of::<IfInfoMsg>()..])
+                .find(|(kind, _)| *kind == attr)
+                .and_then(|(_, value)| Some(u32::from_ne_bytes(value.try_into().ok()?)))
+        };
+
+        Ok(Link {
+            index: u32::from_ne_bytes(reply[4..8].try_into()?),
+            flags: u32::from_ne_bytes(reply[8..12].try_into()?),
+            mtu: attr_u32(libc::IFLA_MTU),
+            master: attr_u32(libc::IFLA_MASTER),
+        })
+    }
+
+    /// Bring the interface `index` up.
+    pub fn set_up(&mut self, index: u32) -> Result<()> {
+        let header = IfInfoMsg {
+            index: index as i32,
+            flags: libc::IFF_UP as u32,
+            change: libc::IFF_UP as u32,
+            ..Default::default()
+        };
+        self.execute(Request::new(libc::RTM_NEWLINK, libc::NLM_F_ACK, &header))
+    }
+
+    /// Set the MTU of the interface `index`.
+    pub fn set_mtu(&mut self, index: u32, mtu: u32) -> Result<()> {
+        let header = IfInfoMsg {
+            index: index as i32,
+            ..Default::default()
+        };
+        let mut request = Request::new(libc::RTM_NEWLINK, libc::NLM_F_ACK, &header);
+        request.attr_u32(libc::IFLA_MTU, mtu);
+        self.execute(request)
+    }
+
+    /// Attach the interface `index` to the bridge `master`.
+    pub fn set_master(&mut self, index: u32, master: u32) -> Result<()> {
+        let header = IfInfoMsg {
+            index: index as i32,
+            ..Default::default()
+        };
+        let mut request = Request::new(libc::RTM_NEWLINK, libc::NLM_F_ACK, &header);
+        request.attr_u32(libc::IFLA_MASTER, master);
+        self.execute(request)
+    }
+
+    /// Create a veth pair, with the end `peer` in the network namespace
+    /// `netns`.
+    pub fn create_veth(
+        &mut self,
+        name: &str,
+        peer: &str,
+        netns: &File,
+        mtu: Option<u32>,
+    ) -> Result<()> {
+        let flags = libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        let mut request = Request::new(libc::RTM_NEWLINK, flags, &IfInfoMsg::default());
+        request.attr_str(libc::IFLA_IFNAME, name);
+        if let Some(mtu) = mtu {
+            request.attr_u32(libc::IFLA_MTU, mtu);
+        }
+        request.nested(libc::IFLA_LINKINFO, |info| {
+            info.attr_str(libc::IFLA_INFO_KIND, "veth");
+            info.nested(libc::IFLA_INFO_DATA, |data| {
+                data.nested(VETH_INFO_PEER, |peer_info| {
+                    peer_info.put(bytes_of(&IfInfoMsg::default()));
+                    peer_info.attr_str(libc::IFLA_IFNAME, peer);
+                    peer_info.attr_u32(libc::IFLA_NET_NS_FD, netns.as_raw_fd() as u32);
+                    if let Some(mtu) = mtu {
+                        peer_info.attr_u32(libc::IFLA_MTU, mtu);
+                    }
+                });
+            });
+        });
+        self.execute(request)
+    }
+
+    /// Delete the interface `index`.
+    pub fn delete_link(&mut self, index: u32) -> Result<()> {
+        let header = IfInfoMsg {
+            index: index as i32,
+            ..Default::default()
+        };
+        self.execute(Request::new(libc::RTM_DELLINK, libc::NLM_F_ACK, &header))
+    }
+
+    /// Add `address`/`prefix` to the interface `index`.
+    pub fn add_address(&mut self, index: u32, address: IpAddr, prefix: u8) -> Result<()> {
+        let header = IfAddrMsg {
+            family: family(&address),
+            prefixlen: prefix,
+            // The address is usable right away, without duplicate address
+            // detection on a link nobody else is on yet.
+            flags: libc::IFA_F_NODAD as u8,
+            scope: libc::RT_SCOPE_UNIVERSE,
+            index,
+        };
+        let flags = libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        let mut request = Request::new(libc::RTM_NEWADDR, flags, &header);
+        request.attr(libc::IFA_LOCAL, &address_bytes(&address));
+        request.attr(libc::IFA_ADDRESS, &address_bytes(&address));
+        self.execute(request)
+    }
+
+    /// Add a route to `destination`, or a default route, through the
+    /// interface `index` and `gateway`.
+    pub fn add_route(
+        &mut self,
+        index: u32,
+        destination: Option<Cidr>,
+        gateway: Option<IpAddr>,
+    ) -> Result<()> {
+        let Some(address) = destination.map(|(dst, _)| dst).or(gateway) else {
+            bail!("route has neither a destination nor a gateway");
+        };
+        let header = RtMsg {
+            family: family(&address),
+            dst_len: destination.map(|(_, prefix)| prefix).unwrap_or(0),
+            table: libc::RT_TABLE_MAIN,
+            protocol: libc::RTPROT_BOOT,
+            scope: if gateway.is_some() {
+                libc::RT_SCOPE_UNIVERSE
+            } else {
+                libc::RT_SCOPE_LINK
+            },
+            kind: libc::RTN_UNICAST,
+            ..Default::default()
+        };
+        let flags = libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        let mut request = Request::new(libc::RTM_NEWROUTE, flags, &header);
+        if let Some((dst, _)) = destination {
+            request.attr(libc::RTA_DST, &address_bytes(&dst));
+        }
+        if let Some(gw) = gateway {
+            request.attr(libc::RTA_GATEWAY, &address_bytes(&gw));
+        }
+        request.attr_u32(libc::RTA_OIF, index);
+        self.execute(request)
+    }
+}
+
+/// The host side of a workload's network, removed by [`Network::teardown`].
+#[derive(Debug)]
+pub struct Network {
+    host: Option<(Netlink, String)>,
+}
+
+/// Handles on a workload's network namespace. The supervisor opens them as
+/// soon as it has unshared the namespace: once the workload has pivoted its
+/// root, `/proc` may no longer show the supervisor.
+#[derive(Debug)]
+pub struct WorkloadNetns {
+    host: Option<Netlink>,
+    netlink: Netlink,
+    file: File,
+}
+
+impl WorkloadNetns {
+    /// Open handles on the network namespace the calling process is in.
+    /// `host` must be a socket opened in the host's network namespace if a
+    /// veth pair is to be configured.
+    pub fn open(host: Option<Netlink>) -> Result<WorkloadNetns> {
+        let file = File::open("/proc/self/ns/net")
+            .map_err(|e| anyhow!("unable to open network namespace: {e}"))?;
+
+        Ok(WorkloadNetns {
+            host,
+            netlink: Netlink::open()?,
+            file,
+        })
+    }
+
+    /// Configure the namespace according to `config`. `default_host_name`
+    /// names the host end of a veth pair unless the configuration does.
+    pub fn setup(self, config: &NetworkConfig, default_host_name: &str) -> Result<Network> {
+        let WorkloadNetns {
+            host,
+            netlink: mut netns,
+            file,
+        } = self;
+
+        if config.loopback.unwrap_or(true) {
+            debug!("bringing up loopback interface");
+            let lo = netns.link("lo")?;
+            netns
+                .set_up(lo.index)
+                .map_err(|e| anyhow!("unable to bring up lo: {e}"))?;
+        }
+
+        if let Some(slirp) = &config.slirp {
+            configure_slirp_tap(&mut netns, slirp)?;
+        }
+
+        let Some(veth) = &config.veth else {
+            return Ok(Network { host: None });
+        };
+        let Some(mut host) = host else {
+            bail!("a veth pair needs a netlink socket in the host network namespace");
+        };
+
+        let name = veth.name.as_deref().unwrap_or("eth0");
+        let host_name = veth.host_name.as_deref().unwrap_or(default_host_name);
+
+        debug!("creating veth pair {host_name} <-> {name}");
+        host.create_veth(host_name, name, &file, veth.mtu)
+            .map_err(|e| anyhow!("unable to create veth pair {host_name}: {e}"))?;
+        let mut network = Network {
+            host: Some((host, host_name.to_string())),
+        };
+        if let Err(e) = network.configure_veth(veth, &mut netns, name) {
+            if let Err(e) = network.teardown() {
+                debug!("unable to remove veth pair after failing to configure it: {e}");
+            }
+            return Err(e);
+        }
+
+        Ok(network)
+    }
+}
+
+/// Configure the TAP device of the user-mode network, which must have been
+/// created in the namespace already.
+fn configure_slirp_tap(netns: &mut Netlink, slirp: &SlirpConfig) -> Result<()> {
+    let tap = netns.link(SLIRP_TAP_NAME)?;
+    netns
+        .set_mtu(tap.index, slirp.mtu.unwrap_or(1500))
+        .and_then(|_| netns.add_address(tap.index, SLIRP_GUEST_ADDRESS.into(), SLIRP_PREFIX_LEN))
+        .and_then(|_| netns.set_up(tap.index))
+        .and_then(|_| netns.add_route(tap.index, None, Some(SLIRP_GATEWAY.into())))
+        .map_err(|e| anyhow!("unable to configure {SLIRP_TAP_NAME}: {e}"))
+}
+
+impl Network {
+    fn configure_veth(&mut self, veth: &VethConfig, netns: &mut Netlink, name: &str) -> Result<()> {
+        let Some((host, host_name)) = &mut self.host else {
+            return Ok(());
+        };
+
+        let host_link = host.link(host_name)?;
+        if let Some(bridge) = &veth.bridge {
+            let bridge_link = host.link(bridge)?;
+            host.set_master(host_link.index, bridge_link.index)
+                .map_err(|e| anyhow!("unable to attach {host_name} to bridge {bridge}: {e}"))?;
+        }
+        host.set_up(host_link.index)
+            .map_err(|e| anyhow!("unable to bring up {host_name}: {e}"))?;
+
+        let link = netns.link(name)?;
+        for address in &veth.addresses {
+            let (address, prefix) = parse_cidr(address)?;
+            netns
+                .add_address(link.index, address, prefix)
+                .map_err(|e| anyhow!("unable to add address {address}/{prefix} to {name}: {e}"))?;
+        }
+        netns
+            .set_up(link.index)
+            .map_err(|e| anyhow!("unable to bring up {name}: {e}"))?;
+        for route in &veth.routes {
+            let (destination, gateway) = route.parse()?;
+            netns
+                .add_route(link.index, destination, gateway)
+                .map_err(|e| anyhow!("unable to add route to {}: {e}", route.destination))?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the veth pair, if one was created. The pair also goes away
+    /// with the workload's network namespace, so it is fine if it is gone.
+    pub fn teardown(self) -> Result<()> {
+        let Some((mut host, host_name)) = self.host else {
+            return Ok(());
+        };
+
+        // Deleting either end of a veth pair deletes both.
+        match host.link(&host_name) {
+            Ok(link) => host
+                .delete_link(link.index)
+                .map_err(|e| anyhow!("unable to delete {host_name}: {e}")),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace::Namespace;
+    use crate::unshare::unshare;
+    use nix::sys::wait::{WaitStatus, waitpid};
+    use nix::unistd::{ForkResult, fork, geteuid};
+
+    #[test]
+    fn nested_attributes_cover_their_contents() {
+        let mut request = Request::new(libc::RTM_NEWLINK, 0, &IfInfoMsg::default());
+        request.nested(libc::IFLA_LINKINFO, |info| {
+            info.attr_str(libc::IFLA_INFO_KIND, "veth");
+        });
+
+        let body = &request.buf[size_of::<libc::nlmsghdr>() + size_of::<IfInfoMsg>()..];
+        let (kind, linkinfo) = attrs(body).next().unwrap();
+        assert_eq!(kind, libc::IFLA_LINKINFO);
+        assert_eq!(linkinfo.len(), 12);
+        assert_eq!(
+            attrs(linkinfo).collect::<Vec<_>>(),
+            [(libc::IFLA_INFO_KIND, &b"veth\0"[..])]
+        );
+    }
+
+    #[test]
+    fn routes_and_addresses_are_validated() {
+        let route = |destination: &str, gateway: Option<&str>| RouteSpec {
+            destination: destination.to_string(),
+            gateway: gateway.map(str::to_string),
+        };
+
+        assert!(route("default", Some("10.0.0.1")).parse().is_ok());
+        assert!(route("fd00::/64", None).parse().is_ok());
+        assert!(route("default", None).parse().is_err());
+        assert!(route("10.1.0.0/16", Some("fd00::1")).parse().is_err());
+        assert!(parse_cidr("10.0.0.2").is_err());
+        assert!(parse_cidr("10.0.0.2/33").is_err());
+        assert_eq!(
+            parse_cidr("fd00::2/64").unwrap(),
+            ("fd00::2".parse().unwrap(), 64)
+        );
+    }
+
+    const TEST_BRIDGE: &str = "styrotestbr0";
+
+    #[test]
+    fn root_only_veth_pair_is_bridged_and_torn_down() {
+        if !geteuid().is_root() {
+            return;
+        }
+
+        let child = match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => unsafe { libc::_exit(veth_pair_test()) },
+            ForkResult::Parent { child } => child,
+        };
+        let status = waitpid(child, None).expect("waitpid failed");
+
+        if let Ok(mut host) = Netlink::open()
+            && let Ok(bridge) = host.link(TEST_BRIDGE)
+        {
+            host.delete_link(bridge.index).expect("delete bridge");
+        }
+        assert!(matches!(status, WaitStatus::Exited(_, 0)), "{status:?}");
+    }
+
+    fn veth_pair_test() -> i32 {
+        let (Ok(mut host), Ok(mut check)) = (Netlink::open(), Netlink::open()) else {
+            return 1;
+        };
+        let flags = libc::NLM_F_ACK | libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        let mut request = Request::new(libc::RTM_NEWLINK, flags, &IfInfoMsg::default());
+        request.attr_str(libc::IFLA_IFNAME, TEST_BRIDGE);
+        request.nested(libc::IFLA_LINKINFO, |info| {
+            info.attr_str(libc::IFLA_INFO_KIND, "bridge");
+        });
+        if host.execute(request).is_err() {
+            // No bridge support.
+            return 0;
+        }
+        let Ok(bridge) = check.link(TEST_BRIDGE) else {
+            return 2;
+        };
+
+        if unshare(&[Namespace::Net]).is_err() {
+            return 3;
+        }
+        let config = NetworkConfig {
+            loopback: None,
+            veth: Some(VethConfig {
+                host_name: Some("styrotestveth0".to_string()),
+                bridge: Some(TEST_BRIDGE.to_string()),
+                addresses: vec!["10.231.0.2/24".to_string()],
+                routes: vec![RouteSpec {
+                    destination: "default".to_string(),
+                    gateway: Some("10.231.0.1".to_string()),
+                }],
+                mtu: Some(1400),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let Ok(netns) = WorkloadNetns::open(Some(host)) else {
+            return 9;
+        };
+        let Ok(network) = netns.setup(&config, "unused") else {
+            return 4;
+        };
+
+        let Ok(mut netns) = Netlink::open() else {
+            return 5;
+        };
+        let up = |link: &Link| link.flags & libc::IFF_UP as u32 != 0;
+        match (netns.link("lo"), netns.link("eth0")) {
+            (Ok(lo), Ok(eth0)) if up(&lo) && up(&eth0) && eth0.mtu == Some(1400) => {}
+            _ => return 6,
+        }
+        match check.link("styrotestveth0") {
+            Ok(veth) if up(&veth) && veth.master == Some(bridge.index) => {}
+            _ => return 7,
+        }
+
+        if network.teardown().is_err() || check.link("styrotestveth0").is_ok() {
+            return 8;
+        }
+        0
+    }
+}
@@ -0,0 +1,269 @@
+//! Symlink-safe path resolution inside a container rootfs.
+//!
+//! A rootfs is untrusted: it may contain symlinks such as `/proc ->
+//! /host/etc` or `../../..`, and if container paths were resolved by joining
+//! strings, mounts and mutations could be redirected onto the host. A
+//! [`Rootfs`] holds a file descriptor for the root directory, and resolves
+//! every path with `openat2(2)` and `RESOLVE_IN_ROOT`, so symlinks and `..`
+//! are confined to the rootfs as if it was `/`. Magic links in `/proc` are
+//! refused as well.
+//!
+//! Operations which only take paths, like mount(2), are pointed at the
+//! resolved file descriptor through `/proc/self/fd/N` (see [`fd_path`]).
+
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+
+/// Open `path` relative to `root`, resolving it as if `root` was `/`.
+pub fn open_in_root(root: &OwnedFd, path: &Path, flags: c_int) -> io::Result<OwnedFd> {
+    let path = if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    };
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (flags | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_IN_ROOT | libc::RESOLVE_NO_MAGICLINKS;
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root.as_raw_fd(),
+            c_path.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(ret as c_int) })
+}
+
+/// Open the directory at `path` under `root`, creating it and any missing
+/// parents.
+pub fn ensure_dir(root: &OwnedFd, path: &Path) -> io::Result<OwnedFd> {
+    match open_in_root(root, path, libc::O_PATH | libc::O_DIRECTORY) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                return Err(e);
+            };
+            let parent = ensure_dir(root, parent)?;
+            let c_name = CString::new(name.as_bytes())?;
+            if unsafe { libc::mkdirat(parent.as_raw_fd(), c_name.as_ptr(), 0o755) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(err);
+                }
+            }
+            open_in_root(root, path, libc::O_PATH | libc::O_DIRECTORY)
+        }
+        result => result,
+    }
+}
+
+/// Stat `name` in the directory `dir` without following a symlink at `name`,
+/// returning `None` if it does not exist.
+pub fn lstat_at(dir: &OwnedFd, name: &OsStr) -> io::Result<Option<libc::stat>> {
+    let c_name = CString::new(name.as_bytes())?;
+    let mut st = MaybeUninit::<libc::stat>::uninit();
+    let ret = unsafe {
+        libc::fstatat(
+            dir.as_raw_fd(),
+            c_name.as_ptr(),
+            st.as_mut_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::NotFound {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(unsafe { st.assume_init() }))
+}
+
+/// Remove `name` from the directory `dir` if it exists, recursively if it is
+/// a directory.
+pub fn remove_at(dir: &OwnedFd, name: &OsStr) -> io::Result<()> {
+    match lstat_at(dir, name)? {
+        None => Ok(()),
+        Some(st) if st.st_mode & libc::S_IFMT == libc::S_IFDIR => {
+            fs::remove_dir_all(Path::new(&fd_path(dir)).join(name))
+        }
+        Some(_) => {
+            let c_name = CString::new(name.as_bytes())?;
+            if unsafe { libc::unlinkat(dir.as_raw_fd(), c_name.as_ptr(), 0) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Turn the return value of a libc call into an `io::Result`.
+pub fn check(ret: c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A path through which the file `fd` refers to can be reached by
+/// operations which only take paths.
+pub fn fd_path(fd: &OwnedFd) -> String {
+    format!("/proc/self/fd/{}", fd.as_raw_fd())
+}
+
+/// A handle on a container rootfs, resolving paths inside it as if it was
+/// `/`.
+#[derive(Debug)]
+pub struct Rootfs {
+    path: String,
+    fd: OwnedFd,
+}
+
+impl Rootfs {
+    /// Open the rootfs at `path`. Mounts made on `path` afterwards are not
+    /// seen through the handle, so it should be opened once the rootfs
+    /// itself is mounted.
+    pub fn open(path: &str) -> Result<Rootfs> {
+        let fd = File::open(path).map_err(|e| anyhow!("unable to open rootfs {path}: {e}"))?;
+
+        Ok(Rootfs {
+            path: path.to_string(),
+            fd: fd.into(),
+        })
+    }
+
+    /// The host path of the rootfs.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Open the container path `path` with `flags`.
+    pub fn open_at(&self, path: impl AsRef<Path>, flags: c_int) -> io::Result<OwnedFd> {
+        open_in_root(&self.fd, path.as_ref(), flags)
+    }
+
+    /// Open the container path `path` without following a trailing
+    /// symlink, returning `None` if it does not exist.
+    pub fn lookup(&self, path: impl AsRef<Path>) -> io::Result<Option<OwnedFd>> {
+        match self.open_at(path, libc::O_PATH | libc::O_NOFOLLOW) {
+            Ok(fd) => Ok(Some(fd)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Open the directory at the container path `path`, creating it and any
+    /// missing parents.
+    pub fn create_dir_all(&self, path: impl AsRef<Path>) -> io::Result<OwnedFd> {
+        ensure_dir(&self.fd, path.as_ref())
+    }
+
+    /// Open the file at the container path `path`, creating an empty one
+    /// and any missing parent directories if it does not exist.
+    pub fn create_file(&self, path: impl AsRef<Path>) -> io::Result<OwnedFd> {
+        let path = path.as_ref();
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        };
+
+        let dir = self.create_dir_all(parent)?;
+        let c_name = CString::new(name.as_bytes())?;
+        if unsafe { libc::mknodat(dir.as_raw_fd(), c_name.as_ptr(), libc::S_IFREG | 0o644, 0) } < 0
+        {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::AlreadyExists {
+                return Err(err);
+            }
+        }
+
+        self.open_at(path, libc::O_PATH)
+    }
+
+    /// Split the container path `path` into its parent directory, which is
+    /// opened, and its final component.
+    pub fn open_parent(&self, path: impl AsRef<Path>) -> io::Result<(OwnedFd, CString)> {
+        let path = path.as_ref();
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        };
+
+        let dir = self.open_at(parent, libc::O_PATH | libc::O_DIRECTORY)?;
+        Ok((dir, CString::new(name.as_bytes())?))
+    }
+
+    /// Read the file at the container path `path`, returning `None` if it
+    /// does not exist.
+    pub fn read_to_string(&self, path: impl AsRef<Path>) -> io::Result<Option<String>> {
+        match self.open_at(path, libc::O_RDONLY) {
+            Ok(fd) => {
+                let mut contents = String::new();
+                File::from(fd).read_to_string(&mut contents)?;
+                Ok(Some(contents))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rootfs, fd_path};
+
+    #[test]
+    fn symlinks_cannot_escape_the_rootfs() {
+        let (Ok(host), Ok(root)) = (tempfile::TempDir::new(), tempfile::TempDir::new()) else {
+            return;
+        };
+        let outside = host.path().to_string_lossy().into_owned();
+        let shadow = root.path().join(outside.trim_start_matches('/'));
+        let prepared = std::fs::create_dir_all(&shadow)
+            .and_then(|_| std::os::unix::fs::symlink(&outside, root.path().join("abs")))
+            .and_then(|_| std::os::unix::fs::symlink("../../../../..", root.path().join("rel")))
+            .and_then(|_| std::os::unix::fs::symlink("/proc/self/root", root.path().join("magic")));
+        if prepared.is_err() {
+            return;
+        }
+
+        let rootfs = Rootfs::open(&root.path().to_string_lossy()).expect("open rootfs");
+
+        // Absolute symlinks are resolved from the rootfs, and `..` stops at
+        // its top.
+        rootfs
+            .create_dir_all("/abs/etc")
+            .expect("create through abs");
+        rootfs
+            .create_dir_all("/rel/etc")
+            .expect("create through rel");
+        assert!(shadow.join("etc").is_dir());
+        assert!(root.path().join("etc").is_dir());
+        assert!(std::fs::read_dir(host.path()).unwrap().next().is_none());
+
+        assert!(rootfs.create_dir_all("/magic/etc").is_err());
+
+        let fd = rootfs
+            .create_file("/rel/etc/hostname")
+            .expect("create file");
+        assert_eq!(
+            std::fs::canonicalize(fd_path(&fd)).unwrap(),
+            std::fs::canonicalize(root.path().join("etc/hostname")).unwrap()
+        );
+    }
+}
@@ -1,17 +1,24 @@
 use std::collections::BTreeMap;
-use std::io::{BufWriter, Write};
+use std::io::{self, BufWriter, Write};
 use std::os::unix::process::CommandExt;
-use std::process::Command;
+use std::process::{self, Command};
 
 use anyhow::{Result, anyhow};
 use libc::{gid_t, pid_t, uid_t};
 use mktemp::TempFile;
+use nix::sys::signal::Signal;
 
+use crate::caps::SecureBit;
 use crate::config::{
-    AttachRequest, Capabilities, Configurable, CreateRequest, IdMapping, MountSpec, Mutation,
-    ProcessResourceLimits,
+    AttachRequest, Capabilities, Configurable, CreateRequest, DnsConfig, HostEntry, IdMap,
+    IdMapping, MountSpec, Mutation, OverlayRootfs, Personality, ProcessResourceLimits, Propagation,
+    TimeOffsets,
 };
-use crate::namespace::Namespace;
+use crate::devices::DeviceRule;
+use crate::namespace::{Namespace, NamespaceJoin, NamespacePin, NamespaceSource};
+use crate::network::NetworkConfig;
+use crate::sched::{IoPriority, Scheduler};
+use crate::systemd::CGroupDriver;
 
 fn add_to_cap_list(
     value: String,
@@ -80,6 +87,41 @@ impl AttachRequestBuilder {
         self
     }
 
+    pub fn set_user(mut self, user: &str) -> AttachRequestBuilder {
+        self.config.exec.user = Some(user.to_string());
+        self
+    }
+
+    pub fn set_scheduler(mut self, scheduler: Scheduler) -> AttachRequestBuilder {
+        self.config.exec.scheduler = Some(scheduler);
+        self
+    }
+
+    pub fn set_cpu_affinity(mut self, cpus: &str) -> AttachRequestBuilder {
+        self.config.exec.cpu_affinity = Some(cpus.to_string());
+        self
+    }
+
+    pub fn set_io_priority(mut self, io_priority: IoPriority) -> AttachRequestBuilder {
+        self.config.exec.io_priority = Some(io_priority);
+        self
+    }
+
+    pub fn set_umask(mut self, umask: u32) -> AttachRequestBuilder {
+        self.config.exec.umask = Some(umask);
+        self
+    }
+
+    pub fn set_personality(mut self, personality: Personality) -> AttachRequestBuilder {
+        self.config.exec.personality = Some(personality);
+        self
+    }
+
+    pub fn set_parent_death_signal(mut self, sig: Signal) -> AttachRequestBuilder {
+        self.config.parent_death_signal = Some(sig.as_str().to_string());
+        self
+    }
+
     pub fn set_supplemental_gids(mut self, gids: Vec<gid_t>) -> AttachRequestBuilder {
         self.config.exec.supplemental_gids = gids.into();
         self
@@ -146,6 +188,61 @@ impl AttachRequestBuilder {
         self
     }
 
+    pub fn push_bounding_capability(mut self, cap: impl AsRef<str>) -> Self {
+        add_to_cap_list(
+            cap.as_ref().to_string(),
+            &mut self.config.capabilities,
+            |caps| &mut caps.bounding,
+        );
+        self
+    }
+
+    pub fn push_effective_capability(mut self, cap: impl AsRef<str>) -> Self {
+        add_to_cap_list(
+            cap.as_ref().to_string(),
+            &mut self.config.capabilities,
+            |caps| &mut caps.effective,
+        );
+        self
+    }
+
+    pub fn push_permitted_capability(mut self, cap: impl AsRef<str>) -> Self {
+        add_to_cap_list(
+            cap.as_ref().to_string(),
+            &mut self.config.capabilities,
+            |caps| &mut caps.permitted,
+        );
+        self
+    }
+
+    pub fn push_inheritable_capability(mut self, cap: impl AsRef<str>) -> Self {
+        add_to_cap_list(
+            cap.as_ref().to_string(),
+            &mut self.config.capabilities,
+            |caps| &mut caps.inheritable,
+        );
+        self
+    }
+
+    pub fn push_ambient_capability(mut self, cap: impl AsRef<str>) -> Self {
+        add_to_cap_list(
+            cap.as_ref().to_string(),
+            &mut self.config.capabilities,
+            |caps| &mut caps.ambient,
+        );
+        self
+    }
+
+    pub fn push_securebit(mut self, bit: SecureBit) -> Self {
+        self.config
+            .capabilities
+            .get_or_insert_with(Capabilities::default)
+            .securebits
+            .get_or_insert_with(Vec::new)
+            .push(bit);
+        self
+    }
+
     pub fn to_request(self) -> AttachRequest {
         self.config
     }
@@ -167,11 +264,26 @@ impl CreateRequestBuilder {
         self
     }
 
+    pub fn set_rootfs_overlay(mut self, overlay: OverlayRootfs) -> CreateRequestBuilder {
+        self.config.rootfs_overlay = Some(overlay);
+        self
+    }
+
     pub fn set_rootfs_readonly(mut self, rootfs_readonly: bool) -> CreateRequestBuilder {
         self.config.rootfs_readonly = Some(rootfs_readonly);
         self
     }
 
+    pub fn set_rootfs_idmap(mut self, idmap: IdMap) -> CreateRequestBuilder {
+        self.config.rootfs_idmap = Some(idmap);
+        self
+    }
+
+    pub fn set_rootfs_propagation(mut self, propagation: Propagation) -> CreateRequestBuilder {
+        self.config.rootfs_propagation = Some(propagation);
+        self
+    }
+
     pub fn set_skip_two_stage_userns(
         mut self,
         skip_two_stage_userns: bool,
@@ -211,6 +323,41 @@ impl CreateRequestBuilder {
         self
     }
 
+    pub fn set_user(mut self, user: &str) -> CreateRequestBuilder {
+        self.config.exec.user = Some(user.to_string());
+        self
+    }
+
+    pub fn set_scheduler(mut self, scheduler: Scheduler) -> CreateRequestBuilder {
+        self.config.exec.scheduler = Some(scheduler);
+        self
+    }
+
+    pub fn set_cpu_affinity(mut self, cpus: &str) -> CreateRequestBuilder {
+        self.config.exec.cpu_affinity = Some(cpus.to_string());
+        self
+    }
+
+    pub fn set_io_priority(mut self, io_priority: IoPriority) -> CreateRequestBuilder {
+        self.config.exec.io_priority = Some(io_priority);
+        self
+    }
+
+    pub fn set_umask(mut self, umask: u32) -> CreateRequestBuilder {
+        self.config.exec.umask = Some(umask);
+        self
+    }
+
+    pub fn set_personality(mut self, personality: Personality) -> CreateRequestBuilder {
+        self.config.exec.personality = Some(personality);
+        self
+    }
+
+    pub fn set_parent_death_signal(mut self, sig: Signal) -> CreateRequestBuilder {
+        self.config.parent_death_signal = Some(sig.as_str().to_string());
+        self
+    }
+
     pub fn set_supplemental_gids(mut self, gids: Vec<gid_t>) -> CreateRequestBuilder {
         self.config.exec.supplemental_gids = gids.into();
         self
@@ -231,11 +378,49 @@ impl CreateRequestBuilder {
         self
     }
 
+    pub fn set_network(mut self, network: NetworkConfig) -> CreateRequestBuilder {
+        self.config.network = Some(network);
+        self
+    }
+
+    pub fn set_time_offsets(mut self, time_offsets: TimeOffsets) -> CreateRequestBuilder {
+        self.config.time_offsets = Some(time_offsets);
+        self
+    }
+
+    pub fn push_sysctl(mut self, key: &str, value: &str) -> CreateRequestBuilder {
+        self.config
+            .sysctls
+            .get_or_insert_with(BTreeMap::new)
+            .insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn set_hostname_file(mut self, hostname_file: bool) -> CreateRequestBuilder {
+        self.config.hostname_file = Some(hostname_file);
+        self
+    }
+
+    pub fn push_host_entry(mut self, entry: HostEntry) -> CreateRequestBuilder {
+        self.config.hosts.get_or_insert_with(Vec::new).push(entry);
+        self
+    }
+
+    pub fn set_dns(mut self, dns: DnsConfig) -> CreateRequestBuilder {
+        self.config.dns = Some(dns);
+        self
+    }
+
     pub fn set_setgroups_deny(mut self, setgroups_deny: bool) -> CreateRequestBuilder {
         self.config.setgroups_deny = setgroups_deny.into();
         self
     }
 
+    pub fn set_id_map_helpers(mut self, id_map_helpers: bool) -> CreateRequestBuilder {
+        self.config.id_map_helpers = Some(id_map_helpers);
+        self
+    }
+
     pub fn set_process_resource_limits(
         mut self,
         prlimits: ProcessResourceLimits,
@@ -256,6 +441,16 @@ impl CreateRequestBuilder {
         self
     }
 
+    pub fn set_cgroup_driver(mut self, driver: CGroupDriver) -> CreateRequestBuilder {
+        self.config.cgroup_driver = Some(driver);
+        self
+    }
+
+    pub fn push_device_rule(mut self, rule: DeviceRule) -> CreateRequestBuilder {
+        self.config.devices.get_or_insert_with(Vec::new).push(rule);
+        self
+    }
+
     pub fn push_environment(mut self, key: &str, value: &str) -> CreateRequestBuilder {
         if self.config.exec.environment.is_none() {
             self.config.exec.environment = BTreeMap::new().into();
@@ -280,6 +475,29 @@ impl CreateRequestBuilder {
         self
     }
 
+    pub fn push_namespace_join(
+        mut self,
+        namespace: Namespace,
+        source: NamespaceSource,
+    ) -> CreateRequestBuilder {
+        self.config
+            .join_namespaces
+            .get_or_insert_with(Vec::new)
+            .push(NamespaceJoin { namespace, source });
+        self
+    }
+
+    pub fn push_namespace_pin(mut self, namespace: Namespace, path: &str) -> CreateRequestBuilder {
+        self.config
+            .pin_namespaces
+            .get_or_insert_with(Vec::new)
+            .push(NamespacePin {
+                namespace,
+                path: path.to_string(),
+            });
+        self
+    }
+
     pub fn push_uid_mapping(mut self, mapping: IdMapping) -> CreateRequestBuilder {
         if self.config.uid_mappings.is_none() {
             self.config.uid_mappings = vec![].into();
@@ -304,6 +522,11 @@ impl CreateRequestBuilder {
         self
     }
 
+    pub fn set_standard_filesystems(mut self, standard_filesystems: bool) -> CreateRequestBuilder {
+        self.config.standard_filesystems = Some(standard_filesystems);
+        self
+    }
+
     pub fn push_mount(mut self, spec: MountSpec) -> CreateRequestBuilder {
         if self.config.mounts.is_none() {
             self.config.mounts = vec![].into();
@@ -371,6 +594,61 @@ impl CreateRequestBuilder {
         self
     }
 
+    pub fn push_bounding_capability(mut self, cap: impl AsRef<str>) -> Self {
+        add_to_cap_list(
+            cap.as_ref().to_string(),
+            &mut self.config.capabilities,
+            |caps| &mut caps.bounding,
+        );
+        self
+    }
+
+    pub fn push_effective_capability(mut self, cap: impl AsRef<str>) -> Self {
+        add_to_cap_list(
+            cap.as_ref().to_string(),
+            &mut self.config.capabilities,
+            |caps| &mut caps.effective,
+        );
+        self
+    }
+
+    pub fn push_permitted_capability(mut self, cap: impl AsRef<str>) -> Self {
+        add_to_cap_list(
+            cap.as_ref().to_string(),
+            &mut self.config.capabilities,
+            |caps| &mut caps.permitted,
+        );
+        self
+    }
+
+    pub fn push_inheritable_capability(mut self, cap: impl AsRef<str>) -> Self {
+        add_to_cap_list(
+            cap.as_ref().to_string(),
+            &mut self.config.capabilities,
+            |caps| &mut caps.inheritable,
+        );
+        self
+    }
+
+    pub fn push_ambient_capability(mut self, cap: impl AsRef<str>) -> Self {
+        add_to_cap_list(
+            cap.as_ref().to_string(),
+            &mut self.config.capabilities,
+            |caps| &mut caps.ambient,
+        );
+        self
+    }
+
+    pub fn push_securebit(mut self, bit: SecureBit) -> Self {
+        self.config
+            .capabilities
+            .get_or_insert_with(Capabilities::default)
+            .securebits
+            .get_or_insert_with(Vec::new)
+            .push(bit);
+        self
+    }
+
     pub fn to_request(self) -> CreateRequest {
         self.config
     }
@@ -381,6 +659,26 @@ pub struct Runner {
     executable: String,
 }
 
+/// A pre-exec hook which sets the parent death signal of the styrolite
+/// process, and fails if `parent` already exited, as the signal would then
+/// never be sent. The supervisor sets the signal again, but only this closes
+/// the race with the parent exiting. It runs between fork(2) and execve(2),
+/// so it may only make async-signal-safe calls.
+fn parent_death_hook(
+    sig: Signal,
+    parent: pid_t,
+) -> impl FnMut() -> io::Result<()> + Send + Sync + 'static {
+    move || {
+        if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, sig as libc::c_ulong) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::getppid() } != parent {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        Ok(())
+    }
+}
+
 impl Runner {
     pub fn new(executable: &str) -> Runner {
         Runner {
@@ -421,11 +719,19 @@ impl Runner {
 
     /// Run the specified container.
     /// Returns exit code on success, else error.
+    ///
+    /// With a parent death signal configured, the container is stopped if
+    /// the calling thread exits.
     pub fn run<T: Configurable>(&self, config: T) -> Result<i32> {
+        let parent_death_signal = config.parent_death_signal()?;
         let mut config_file = TempFile::new("styrolite-cfg-", ".json")?;
         self.write_config(config, &mut config_file)?;
 
-        let status = self.create_command(&config_file)?.status()?;
+        let mut command = self.create_command(&config_file)?;
+        if let Some(sig) = parent_death_signal {
+            unsafe { command.pre_exec(parent_death_hook(sig, process::id() as pid_t)) };
+        }
+        let status = command.status()?;
         if let Some(code) = status.code() {
             return Ok(code);
         }
@@ -438,10 +744,15 @@ impl Runner {
 
     #[cfg(feature = "async")]
     pub async fn run_async<T: Configurable>(&self, config: T) -> Result<i32> {
+        let parent_death_signal = config.parent_death_signal()?;
         let mut config_file = TempFile::new("styrolite-cfg-", ".json")?;
         self.write_config(config, &mut config_file)?;
 
-        let status = self.create_command_async(&config_file)?.status().await?;
+        let mut command = self.create_command_async(&config_file)?;
+        if let Some(sig) = parent_death_signal {
+            unsafe { command.pre_exec(parent_death_hook(sig, process::id() as pid_t)) };
+        }
+        let status = command.status().await?;
         if let Some(code) = status.code() {
             return Ok(code);
         }
@@ -453,13 +764,19 @@ impl Runner {
     }
 
     /// Replace the current process with the styrolite runner directly.
+    /// A parent death signal then applies to the caller's parent.
     #[cfg(unix)]
     pub fn exec<T: Configurable>(&self, config: T) -> Result<()> {
+        let parent_death_signal = config.parent_death_signal()?;
         let mut config_file = TempFile::new("styrolite-cfg-", ".json")?;
         self.write_config(config, &mut config_file)?;
 
         // Build the command like before
         let mut command = self.create_command(&config_file)?;
+        if let Some(sig) = parent_death_signal {
+            let parent = unsafe { libc::getppid() };
+            unsafe { command.pre_exec(parent_death_hook(sig, parent)) };
+        }
 
         // NOTE: If exec succeeds, this process image is replaced; no destructors run.
         // That means config_file won't be dropped, so a drop-based cleanup won't happen.
@@ -0,0 +1,377 @@
+//! Scheduling policy, CPU affinity and I/O priority of the workload, like
+//! the OCI `process.scheduler`, `execCPUAffinity` and `ioPriority` fields.
+//! They are applied to the workload process before capabilities are
+//! dropped, and inherited through `execve`.
+
+use std::io;
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+/// A scheduling policy, see sched(7).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SchedPolicy {
+    #[default]
+    #[serde(rename = "SCHED_OTHER")]
+    Other,
+    #[serde(rename = "SCHED_BATCH")]
+    Batch,
+    #[serde(rename = "SCHED_IDLE")]
+    Idle,
+    #[serde(rename = "SCHED_FIFO")]
+    Fifo,
+    #[serde(rename = "SCHED_RR")]
+    Rr,
+    #[serde(rename = "SCHED_DEADLINE")]
+    Deadline,
+}
+
+/// A flag of sched_setattr(2).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SchedFlag {
+    /// Children do not inherit a real-time policy or a negative nice value.
+    #[serde(rename = "SCHED_FLAG_RESET_ON_FORK")]
+    ResetOnFork,
+    /// SCHED_DEADLINE tasks may reclaim unused bandwidth.
+    #[serde(rename = "SCHED_FLAG_RECLAIM")]
+    Reclaim,
+    /// SCHED_DEADLINE tasks get SIGXCPU when they overrun their runtime.
+    #[serde(rename = "SCHED_FLAG_DL_OVERRUN")]
+    DlOverrun,
+}
+
+/// The scheduling attributes of the workload, see sched_setattr(2).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Scheduler {
+    pub policy: SchedPolicy,
+
+    /// The nice value, from -20 to 19, for `SCHED_OTHER` and `SCHED_BATCH`.
+    #[serde(default)]
+    pub nice: Option<i32>,
+
+    /// The static priority, from 1 to 99, for `SCHED_FIFO` and `SCHED_RR`.
+    #[serde(default)]
+    pub priority: Option<u32>,
+
+    #[serde(default)]
+    pub flags: Vec<SchedFlag>,
+
+    /// The runtime, deadline and period of `SCHED_DEADLINE`, in
+    /// nanoseconds. The runtime and deadline are required, and the period
+    /// defaults to the deadline.
+    #[serde(default)]
+    pub runtime: Option<u64>,
+    #[serde(default)]
+    pub deadline: Option<u64>,
+    #[serde(default)]
+    pub period: Option<u64>,
+}
+
+/// An I/O scheduling class, see ioprio_set(2).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IoPriorityClass {
+    #[serde(rename = "IOPRIO_CLASS_RT")]
+    RealTime,
+    #[serde(rename = "IOPRIO_CLASS_BE")]
+    BestEffort,
+    #[serde(rename = "IOPRIO_CLASS_IDLE")]
+    Idle,
+}
+
+/// The I/O priority of the workload.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IoPriority {
+    pub class: IoPriorityClass,
+
+    /// The level within the class, from 0 (highest) to 7. Ignored for the
+    /// idle class.
+    #[serde(default)]
+    pub priority: u8,
+}
+
+/// `struct sched_attr` from <linux/sched/types.h>, without the utilization
+/// clamps (`SCHED_ATTR_SIZE_VER0`).
+#[repr(C)]
+#[derive(Debug, Default, PartialEq, Eq)]
+struct SchedAttr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+}
+
+const SCHED_DEADLINE: u32 = 6;
+const SCHED_FLAG_RESET_ON_FORK: u64 = 0x01;
+const SCHED_FLAG_RECLAIM: u64 = 0x02;
+const SCHED_FLAG_DL_OVERRUN: u64 = 0x04;
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+impl Scheduler {
+    fn to_attr(&self) -> Result<SchedAttr> {
+        let policy = match self.policy {
+            SchedPolicy::Other => libc::SCHED_OTHER as u32,
+            SchedPolicy::Batch => libc::SCHED_BATCH as u32,
+            SchedPolicy::Idle => libc::SCHED_IDLE as u32,
+            SchedPolicy::Fifo => libc::SCHED_FIFO as u32,
+            SchedPolicy::Rr => libc::SCHED_RR as u32,
+            SchedPolicy::Deadline => SCHED_DEADLINE,
+        };
+        let realtime = matches!(self.policy, SchedPolicy::Fifo | SchedPolicy::Rr);
+        let deadline = self.policy == SchedPolicy::Deadline;
+
+        if let Some(nice) = self.nice {
+            if !(-20..=19).contains(&nice) {
+                bail!("nice value {nice} is out of range");
+            }
+            if !matches!(self.policy, SchedPolicy::Other | SchedPolicy::Batch) {
+                bail!("a nice value only applies to SCHED_OTHER and SCHED_BATCH");
+            }
+        }
+        let priority = self.priority.unwrap_or(0);
+        if realtime && !(1..=99).contains(&priority) {
+            bail!("{:?} requires a priority from 1 to 99", self.policy);
+        }
+        if !realtime && priority != 0 {
+            bail!("a priority only applies to SCHED_FIFO and SCHED_RR");
+        }
+        let timing = [self.runtime, self.deadline, self.period];
+        if deadline {
+            let (Some(runtime), Some(relative)) = (self.runtime, self.deadline) else {
+                bail!("SCHED_DEADLINE requires a runtime and a deadline");
+            };
+            // The period defaults to the deadline.
+            let period = self.period.unwrap_or(relative);
+            if !(runtime <= relative && relative <= period) {
+                bail!(
+                    "SCHED_DEADLINE requires runtime <= deadline <= period, got {runtime}, {relative} and {period}"
+                );
+            }
+        } else if timing.iter().any(Option::is_some) {
+            bail!("runtime, deadline and period only apply to SCHED_DEADLINE");
+        }
+
+        let sched_flags = self.flags.iter().fold(0, |flags, flag| {
+            flags
+                | match flag {
+                    SchedFlag::ResetOnFork => SCHED_FLAG_RESET_ON_FORK,
+                    SchedFlag::Reclaim => SCHED_FLAG_RECLAIM,
+                    SchedFlag::DlOverrun => SCHED_FLAG_DL_OVERRUN,
+                }
+        });
+
+        Ok(SchedAttr {
+            size: std::mem::size_of::<SchedAttr>() as u32,
+            sched_policy: policy,
+            sched_flags,
+            sched_nice: self.nice.unwrap_or(0),
+            sched_priority: priority,
+            sched_runtime: self.runtime.unwrap_or(0),
+            sched_deadline: self.deadline.unwrap_or(0),
+            sched_period: self.period.unwrap_or(0),
+        })
+    }
+
+    /// Apply the scheduling attributes to the calling process.
+    pub fn apply(&self) -> Result<()> {
+        let attr = self.to_attr()?;
+        let flags: libc::c_uint = 0;
+        if unsafe { libc::syscall(libc::SYS_sched_setattr, 0, &attr as *const SchedAttr, flags) }
+            < 0
+        {
+            bail!(
+                "unable to set scheduling policy {:?}: {}",
+                self.policy,
+                io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl IoPriority {
+    fn to_ioprio(&self) -> Result<libc::c_int> {
+        let (class, level) = match self.class {
+            IoPriorityClass::RealTime => (1, self.priority),
+            IoPriorityClass::BestEffort => (2, self.priority),
+            IoPriorityClass::Idle => (3, 0),
+        };
+        if level > 7 {
+            bail!("I/O priority level {level} is out of range");
+        }
+        Ok(((class << IOPRIO_CLASS_SHIFT) | level as u32) as libc::c_int)
+    }
+
+    /// Apply the I/O priority to the calling process.
+    pub fn apply(&self) -> Result<()> {
+        let ioprio = self.to_ioprio()?;
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } < 0 {
+            bail!("unable to set I/O priority: {}", io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Parse a CPU list like `0-3,8,10-11`, as in cpuset(7).
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let invalid = || anyhow!("invalid CPU list '{list}'");
+    let max = libc::CPU_SETSIZE as usize;
+
+    let mut cpus = Vec::new();
+    for range in list.split(',').map(str::trim) {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (range, range),
+        };
+        let first = first.trim().parse::<usize>().map_err(|_| invalid())?;
+        let last = last.trim().parse::<usize>().map_err(|_| invalid())?;
+        if first > last || last >= max {
+            return Err(invalid());
+        }
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
+
+/// Restrict the calling process to the CPUs in `list`, like `0-3,8`.
+pub fn set_cpu_affinity(list: &str) -> Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in parse_cpu_list(list)? {
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } < 0 {
+        bail!(
+            "unable to set CPU affinity to {list}: {}",
+            io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::wait::{WaitStatus, waitpid};
+    use nix::unistd::{ForkResult, fork, geteuid};
+
+    #[test]
+    fn cpu_lists_are_parsed() {
+        assert_eq!(parse_cpu_list("0-3,8").unwrap(), vec![0, 1, 2, 3, 8]);
+        assert_eq!(parse_cpu_list(" 5 ").unwrap(), vec![5]);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("0,,1").is_err());
+        assert!(parse_cpu_list("4096").is_err());
+    }
+
+    #[test]
+    fn scheduler_parameters_match_the_policy() {
+        let fifo = Scheduler {
+            policy: SchedPolicy::Fifo,
+            priority: Some(10),
+            flags: vec![SchedFlag::ResetOnFork],
+            ..Default::default()
+        };
+        let attr = fifo.to_attr().unwrap();
+        assert_eq!(attr.size, 48);
+        assert_eq!(attr.sched_policy, libc::SCHED_FIFO as u32);
+        assert_eq!(attr.sched_priority, 10);
+        assert_eq!(attr.sched_flags, SCHED_FLAG_RESET_ON_FORK);
+
+        let scheduler = |policy, nice, priority| Scheduler {
+            policy,
+            nice,
+            priority,
+            ..Default::default()
+        };
+        assert!(
+            scheduler(SchedPolicy::Batch, Some(5), None)
+                .to_attr()
+                .is_ok()
+        );
+        assert!(
+            scheduler(SchedPolicy::Other, Some(20), None)
+                .to_attr()
+                .is_err()
+        );
+        assert!(
+            scheduler(SchedPolicy::Fifo, Some(5), Some(1))
+                .to_attr()
+                .is_err()
+        );
+        assert!(scheduler(SchedPolicy::Rr, None, None).to_attr().is_err());
+        assert!(
+            scheduler(SchedPolicy::Other, None, Some(1))
+                .to_attr()
+                .is_err()
+        );
+        assert!(
+            scheduler(SchedPolicy::Deadline, None, None)
+                .to_attr()
+                .is_err()
+        );
+
+        let deadline = |runtime, deadline, period| Scheduler {
+            policy: SchedPolicy::Deadline,
+            runtime,
+            deadline,
+            period,
+            ..Default::default()
+        };
+        let attr = deadline(Some(10), Some(20), None).to_attr().unwrap();
+        assert_eq!(
+            (attr.sched_runtime, attr.sched_deadline, attr.sched_period),
+            (10, 20, 0)
+        );
+        assert!(deadline(Some(10), Some(20), Some(30)).to_attr().is_ok());
+        assert!(deadline(Some(10), None, Some(30)).to_attr().is_err());
+        assert!(deadline(Some(30), Some(20), None).to_attr().is_err());
+        assert!(deadline(Some(10), Some(30), Some(20)).to_attr().is_err());
+
+        let io = |class, priority| IoPriority { class, priority };
+        assert_eq!(
+            io(IoPriorityClass::BestEffort, 7).to_ioprio().unwrap(),
+            (2 << 13) | 7
+        );
+        assert_eq!(io(IoPriorityClass::Idle, 3).to_ioprio().unwrap(), 3 << 13);
+        assert!(io(IoPriorityClass::RealTime, 8).to_ioprio().is_err());
+    }
+
+    #[test]
+    fn root_only_scheduling_is_applied_to_the_caller() {
+        if !geteuid().is_root() {
+            return;
+        }
+
+        let child = match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => {
+                let scheduler = Scheduler {
+                    policy: SchedPolicy::Batch,
+                    nice: Some(5),
+                    ..Default::default()
+                };
+                let io = IoPriority {
+                    class: IoPriorityClass::BestEffort,
+                    priority: 6,
+                };
+                let ok = scheduler.apply().is_ok()
+                    && io.apply().is_ok()
+                    && set_cpu_affinity("0").is_ok()
+                    && unsafe { libc::sched_getscheduler(0) } == libc::SCHED_BATCH
+                    && unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) } == 5
+                    && unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) }
+                        == (2 << 13) | 6
+                    && unsafe { libc::sched_getcpu() } == 0;
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            ForkResult::Parent { child } => child,
+        };
+        let status = waitpid(child, None).expect("waitpid failed");
+        assert!(matches!(status, WaitStatus::Exited(_, 0)), "{status:?}");
+    }
+}
@@ -1,7 +1,8 @@
 use anyhow::{Result, anyhow};
 use log::debug;
 use nix::sys::signal::{self, SigHandler, Signal};
-use nix::unistd::Pid;
+use nix::unistd::{Pid, getpid, getppid};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 
 /// Set of signals forwarded from the parent to the child
 const FORWARDED_SIGNALS: &[Signal] = &[
@@ -87,6 +88,100 @@ pub fn reset_sigpipe() -> Result<()> {
     Ok(())
 }
 
+/// parse_signal parses a signal name like `SIGTERM` or `TERM`, or a signal
+/// number.
+pub fn parse_signal(name: &str) -> Result<Signal> {
+    if let Ok(signum) = name.parse::<i32>() {
+        return Signal::try_from(signum).map_err(|_| anyhow!("invalid signal number {signum}"));
+    }
+
+    let name = name.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{name}")
+    };
+    name.parse()
+        .map_err(|_| anyhow!("unknown signal name {name:?}"))
+}
+
+/// set_parent_death_signal arranges for the calling process to receive `sig`
+/// when the thread which created it exits, see `PR_SET_PDEATHSIG` in
+/// prctl(2). The setting is cleared by fork(2) and by credential changes,
+/// but kept across execve(2).
+pub fn set_parent_death_signal(sig: Signal) -> Result<()> {
+    debug!("Setting parent death signal to {sig}");
+    if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, sig as libc::c_ulong) } < 0 {
+        return Err(anyhow!(
+            "Failed to set parent death signal to {sig}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// ParentWatch lets a freshly forked child tell whether the process which
+/// forked it is still alive, so it can arm a parent death signal without
+/// racing against the parent's exit. Inside a new pid namespace getppid(2)
+/// returns 0 whether or not the parent is alive, so a pidfd for the parent
+/// is used where the kernel supports them.
+pub struct ParentWatch {
+    parent: Pid,
+    pidfd: Option<OwnedFd>,
+}
+
+impl ParentWatch {
+    /// Create a watch on the calling process. This must be called before
+    /// forking the child which uses it.
+    pub fn new() -> Self {
+        let parent = getpid();
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, parent.as_raw(), 0) };
+        let pidfd = if fd < 0 {
+            debug!(
+                "pidfd_open unavailable ({}), falling back to getppid(2)",
+                std::io::Error::last_os_error()
+            );
+            None
+        } else {
+            Some(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+        };
+        ParentWatch { parent, pidfd }
+    }
+
+    /// arm sets the parent death signal of the calling child to `sig`, then
+    /// fails if the parent already exited, as the signal would never come.
+    pub fn arm(&self, sig: Signal) -> Result<()> {
+        set_parent_death_signal(sig)?;
+        if self.parent_exited() {
+            return Err(anyhow!("Parent process {} has already exited", self.parent));
+        }
+        Ok(())
+    }
+
+    fn parent_exited(&self) -> bool {
+        if let Some(pidfd) = &self.pidfd {
+            // A pidfd becomes readable once the process has exited.
+            let mut pfd = libc::pollfd {
+                fd: pidfd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            return unsafe { libc::poll(&mut pfd, 1, 0) } > 0;
+        }
+
+        // Without a pidfd, a parent outside of our pid namespace can't be
+        // told apart from the one we were reparented to.
+        let ppid = getppid();
+        ppid.as_raw() != 0 && ppid != self.parent
+    }
+}
+
+impl Default for ParentWatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// store_child_pid stores a pid in the static variable which is used by the signal handler to
 /// forward signals.
 pub fn store_child_pid(pid: i32) {
@@ -126,4 +221,82 @@ mod tests {
         unsafe { signal::signal(Signal::SIGPIPE, original) }
             .expect("failed to restore original SIGPIPE disposition");
     }
+
+    #[test]
+    fn signals_parse_by_name_and_number() {
+        assert_eq!(parse_signal("SIGTERM").unwrap(), Signal::SIGTERM);
+        assert_eq!(parse_signal("kill").unwrap(), Signal::SIGKILL);
+        assert_eq!(parse_signal("9").unwrap(), Signal::SIGKILL);
+        assert!(parse_signal("SIGNOPE").is_err());
+        assert!(parse_signal("0").is_err());
+    }
+
+    /// A grandchild with a parent death signal dies with its parent. It holds
+    /// the write end of a pipe, which is closed once it is gone.
+    #[test]
+    fn parent_death_signal_follows_the_parent() {
+        use nix::sys::wait::{WaitStatus, waitpid};
+        use nix::unistd::{ForkResult, fork, getpid, getppid, pause};
+        use std::io::Read;
+
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let child = match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => {
+                let parent = getpid();
+                if let Ok(ForkResult::Child) = unsafe { fork() } {
+                    drop(reader);
+                    if set_parent_death_signal(Signal::SIGKILL).is_err() {
+                        unsafe { libc::_exit(1) }
+                    }
+                    // The parent may have exited before the signal was set.
+                    while getppid() == parent {
+                        pause();
+                    }
+                }
+                unsafe { libc::_exit(0) }
+            }
+            ForkResult::Parent { child } => child,
+        };
+        drop(writer);
+
+        let status = waitpid(child, None).expect("waitpid failed");
+        assert!(matches!(status, WaitStatus::Exited(_, 0)), "{status:?}");
+        assert_eq!(reader.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    /// A watch tells a live parent from one which has exited, and refuses to
+    /// arm a parent death signal which would never be sent.
+    #[test]
+    fn parent_watch_notices_an_exited_parent() {
+        use nix::sys::wait::{WaitStatus, waitpid};
+        use nix::unistd::{ForkResult, fork};
+        use std::io::{Read, Write};
+
+        let (mut reader, mut writer) = std::io::pipe().unwrap();
+        let child = match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => {
+                let watch = ParentWatch::new();
+                if let Ok(ForkResult::Child) = unsafe { fork() } {
+                    let mut waits = 0;
+                    while !watch.parent_exited() && waits < 500 {
+                        std::thread::sleep(std::time::Duration::from_millis(10));
+                        waits += 1;
+                    }
+                    let refused = watch.arm(Signal::SIGKILL).is_err();
+                    let _ = writer.write_all(&[refused as u8]);
+                    unsafe { libc::_exit(0) }
+                }
+                let alive = !watch.parent_exited() && watch.arm(Signal::SIGKILL).is_ok();
+                unsafe { libc::_exit(if alive { 0 } else { 1 }) }
+            }
+            ForkResult::Parent { child } => child,
+        };
+        drop(writer);
+
+        let status = waitpid(child, None).expect("waitpid failed");
+        assert!(matches!(status, WaitStatus::Exited(_, 0)), "{status:?}");
+        let mut refused = [0u8; 1];
+        reader.read_exact(&mut refused).unwrap();
+        assert_eq!(refused, [1]);
+    }
 }
@@ -0,0 +1,1099 @@
+//! A user-mode network stack for workloads without privileges on the host.
+//!
+//! Unprivileged users can't create veth pairs, but they can create a TAP
+//! device inside a network namespace they own. Frames the workload sends on
+//! it are handled by a TCP/IP stack in userspace (smoltcp), and each TCP
+//! connection and UDP flow is re-created as an ordinary socket on the host,
+//! like slirp4netns or pasta do. Host sockets must be created from the
+//! host's network namespace, so this happens in a [`SlirpHelper`] process
+//! forked before any namespaces are unshared. The supervisor creates the TAP
+//! device once it has unshared the workload's network namespace and passes
+//! its file descriptor to the helper.
+//!
+//! The network looks like slirp's: the workload is
+//! [`SLIRP_GUEST_ADDRESS`], the gateway [`SLIRP_GATEWAY`] stands for the
+//! host's loopback interface, and [`SLIRP_DNS`] forwards to the host's
+//! resolver. Host ports can be forwarded to the workload.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, BufReader, IoSlice, IoSliceMut, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process;
+
+use anyhow::{Result, anyhow, bail};
+use log::{debug, error};
+use nix::errno::Errno;
+use nix::sys::socket::{
+    self, AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType,
+    SockaddrIn,
+};
+use nix::sys::wait::waitpid;
+use nix::unistd::{ForkResult, Pid, fork};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, HardwareAddress, IpAddress, IpCidr,
+    IpEndpoint, IpListenEndpoint, IpProtocol, Ipv4Packet, TcpPacket, UdpPacket,
+};
+
+use crate::network::{
+    PortForward, Protocol, SLIRP_DNS, SLIRP_GATEWAY, SLIRP_GUEST_ADDRESS, SLIRP_PREFIX_LEN,
+    SLIRP_TAP_NAME, SlirpConfig,
+};
+
+/// The MAC address of the gateway, as in slirp.
+const GATEWAY_MAC: EthernetAddress = EthernetAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+
+/// The size of each TCP socket's buffers inside the stack.
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How long a UDP flow is kept without any traffic.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a TCP connection may go without its segments being acknowledged.
+const TCP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Create the TAP device `name` in the caller's network namespace.
+fn create_tap(name: &str) -> Result<OwnedFd> {
+    let fd = unsafe {
+        libc::open(
+            c"/dev/net/tun".as_ptr(),
+            libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        bail!(
+            "unable to open /dev/net/tun: {}",
+            io::Error::last_os_error()
+        );
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    ifr.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &ifr) } < 0 {
+        bail!(
+            "unable to create TAP device {name}: {}",
+            io::Error::last_os_error()
+        );
+    }
+
+    Ok(fd)
+}
+
+/// The first IPv4 nameserver in the host's resolv.conf.
+fn host_nameserver() -> Option<SocketAddrV4> {
+    fs::read_to_string(crate::etc::HOST_RESOLV_CONF)
+        .ok()?
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .find_map(|address| address.trim().parse::<Ipv4Addr>().ok())
+        .map(|address| SocketAddrV4::new(address, 53))
+}
+
+/// Start connecting to `address` without waiting for the connection to be
+/// established.
+fn connect_nonblocking(address: SocketAddrV4) -> io::Result<TcpStream> {
+    let fd = socket::socket(
+        AddressFamily::Inet,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    match socket::connect(fd.as_raw_fd(), &SockaddrIn::from(address)) {
+        Ok(()) | Err(Errno::EINPROGRESS) => Ok(TcpStream::from(fd)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The TAP device as seen by the stack. Frames are read from the TAP device
+/// by the engine, which looks at them before queueing them here.
+struct Tap {
+    fd: OwnedFd,
+    rx: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+struct TapRxToken(Vec<u8>);
+
+impl phy::RxToken for TapRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct TapTxToken<'a>(&'a OwnedFd);
+
+impl phy::TxToken for TapTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        // Like a NIC with a full queue, the frame is dropped if the TAP
+        // device can't take it, and TCP retransmits it.
+        unsafe { libc::write(self.0.as_raw_fd(), frame.as_ptr().cast(), len) };
+        result
+    }
+}
+
+impl Device for Tap {
+    type RxToken<'a> = TapRxToken;
+    type TxToken<'a> = TapTxToken<'a>;
+
+    fn receive(&mut self, _: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let frame = self.rx.pop_front()?;
+        Some((TapRxToken(frame), TapTxToken(&self.fd)))
+    }
+
+    fn transmit(&mut self, _: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TapTxToken(&self.fd))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = self.mtu + 14;
+        caps
+    }
+}
+
+/// A flow, identified by the workload's endpoint and the endpoint it talks
+/// to.
+type FlowKey = (IpEndpoint, IpEndpoint);
+
+/// What the engine needs to know about a frame before the stack sees it.
+enum Inbound {
+    /// A TCP connection request.
+    TcpSyn(FlowKey),
+    /// A UDP datagram to a port.
+    Udp(u16),
+}
+
+fn classify(frame: &[u8]) -> Option<Inbound> {
+    let eth = EthernetFrame::new_checked(frame).ok()?;
+    if eth.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let ip = Ipv4Packet::new_checked(eth.payload()).ok()?;
+    let endpoint = |addr: Ipv4Addr, port| IpEndpoint::new(IpAddress::Ipv4(addr), port);
+
+    match ip.next_header() {
+        IpProtocol::Tcp => {
+            let tcp = TcpPacket::new_checked(ip.payload()).ok()?;
+            (tcp.syn() && !tcp.ack()).then(|| {
+                Inbound::TcpSyn((
+                    endpoint(ip.src_addr(), tcp.src_port()),
+                    endpoint(ip.dst_addr(), tcp.dst_port()),
+                ))
+            })
+        }
+        IpProtocol::Udp => {
+            let udp = UdpPacket::new_checked(ip.payload()).ok()?;
+            Some(Inbound::Udp(udp.dst_port()))
+        }
+        _ => None,
+    }
+}
+
+/// A TCP connection between a socket in the stack and one on the host.
+struct TcpFlow {
+    handle: SocketHandle,
+    stream: TcpStream,
+    host_eof: bool,
+    guest_eof: bool,
+}
+
+/// A connection request from the workload, held back until the host
+/// connection it is forwarded to is established or refused.
+struct PendingConnect {
+    key: FlowKey,
+    stream: TcpStream,
+    syn: Vec<u8>,
+}
+
+/// A UDP flow from the workload to the host.
+struct UdpFlow {
+    socket: UdpSocket,
+    last_used: Instant,
+}
+
+/// A UDP socket in the stack, bound to a port on any address.
+struct UdpPort {
+    handle: SocketHandle,
+    last_used: Instant,
+}
+
+/// A host socket forwarded to the workload.
+enum Forward {
+    Tcp {
+        listener: TcpListener,
+        guest_port: u16,
+    },
+    Udp {
+        socket: UdpSocket,
+        guest_port: u16,
+    },
+}
+
+impl Forward {
+    fn bind(forward: &PortForward) -> Result<Forward> {
+        let address = match &forward.host_address {
+            Some(address) => address.parse()?,
+            None => Ipv4Addr::LOCALHOST.into(),
+        };
+        let address = SocketAddr::new(address, forward.host_port);
+
+        let bound = match forward.protocol {
+            Protocol::Tcp => TcpListener::bind(address).and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                Ok(Forward::Tcp {
+                    listener,
+                    guest_port: forward.guest_port,
+                })
+            }),
+            Protocol::Udp => UdpSocket::bind(address).and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                Ok(Forward::Udp {
+                    socket,
+                    guest_port: forward.guest_port,
+                })
+            }),
+        };
+        bound.map_err(|e| anyhow!("unable to listen on {address} for port forward: {e}"))
+    }
+
+    fn fd(&self) -> RawFd {
+        match self {
+            Forward::Tcp { listener, .. } => listener.as_raw_fd(),
+            Forward::Udp { socket, .. } => socket.as_raw_fd(),
+        }
+    }
+}
+
+/// The user-mode network of one workload.
+struct Engine {
+    iface: Interface,
+    device: Tap,
+    sockets: SocketSet<'static>,
+    tcp_flows: Vec<TcpFlow>,
+    pending: Vec<PendingConnect>,
+    udp_ports: HashMap<u16, UdpPort>,
+    udp_flows: HashMap<FlowKey, UdpFlow>,
+    forwards: Vec<Forward>,
+    /// The host client of a UDP port forward behind each local port.
+    udp_forwarded: HashMap<u16, (usize, SocketAddr)>,
+    next_port: u16,
+    host_loopback: bool,
+    dns: Option<SocketAddrV4>,
+}
+
+impl Engine {
+    fn new(tap: OwnedFd, config: &SlirpConfig, forwards: Vec<Forward>) -> Engine {
+        let mut device = Tap {
+            fd: tap,
+            rx: VecDeque::new(),
+            mtu: config.mtu.unwrap_or(1500) as usize,
+        };
+
+        let mut iface_config = Config::new(HardwareAddress::Ethernet(GATEWAY_MAC));
+        iface_config.random_seed = process::id() as u64 ^ Instant::now().total_micros() as u64;
+        let mut iface = Interface::new(iface_config, &mut device, Instant::now());
+        iface.update_ip_addrs(|addrs| {
+            for address in [SLIRP_GATEWAY, SLIRP_DNS] {
+                let _ = addrs.push(IpCidr::new(IpAddress::Ipv4(address), SLIRP_PREFIX_LEN));
+            }
+        });
+        // Accept packets to any address, as everything is routed through
+        // the gateway, which is us.
+        let _ = iface.routes_mut().add_default_ipv4_route(SLIRP_GATEWAY);
+        iface.set_any_ip(true);
+
+        Engine {
+            iface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            tcp_flows: Vec::new(),
+            pending: Vec::new(),
+            udp_ports: HashMap::new(),
+            udp_flows: HashMap::new(),
+            forwards,
+            udp_forwarded: HashMap::new(),
+            next_port: 49152,
+            host_loopback: config.host_loopback.unwrap_or(true),
+            dns: host_nameserver(),
+        }
+    }
+
+    /// The host address the workload reaches when it talks to `dst`.
+    fn translate(&self, dst: IpEndpoint) -> Option<SocketAddrV4> {
+        let IpAddress::Ipv4(address) = dst.addr;
+
+        if address == SLIRP_GATEWAY {
+            return self
+                .host_loopback
+                .then(|| SocketAddrV4::new(Ipv4Addr::LOCALHOST, dst.port));
+        }
+        if address == SLIRP_DNS {
+            return self.dns.filter(|_| dst.port == 53);
+        }
+
+        let network = IpCidr::new(IpAddress::Ipv4(SLIRP_GATEWAY), SLIRP_PREFIX_LEN);
+        if address.is_loopback()
+            || address.is_broadcast()
+            || address.is_multicast()
+            || address.is_unspecified()
+            || network.contains_addr(&dst.addr)
+        {
+            return None;
+        }
+
+        Some(SocketAddrV4::new(address, dst.port))
+    }
+
+    fn ephemeral_port(&mut self) -> u16 {
+        loop {
+            let port = self.next_port;
+            self.next_port = self.next_port.checked_add(1).unwrap_or(49152);
+            if !self.udp_ports.contains_key(&port) {
+                return port;
+            }
+        }
+    }
+
+    fn new_tcp_socket(&mut self) -> tcp::Socket<'static> {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        );
+        socket.set_timeout(Some(TCP_TIMEOUT));
+        socket
+    }
+
+    /// The stack's UDP socket for `port`, created if needed.
+    fn udp_port(&mut self, port: u16) -> SocketHandle {
+        let now = Instant::now();
+        if let Some(entry) = self.udp_ports.get_mut(&port) {
+            entry.last_used = now;
+            return entry.handle;
+        }
+
+        let buffer =
+            || udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 64], vec![0; 65536]);
+        let mut socket = udp::Socket::new(buffer(), buffer());
+        let _ = socket.bind(port);
+        let handle = self.sockets.add(socket);
+        self.udp_ports.insert(
+            port,
+            UdpPort {
+                handle,
+                last_used: now,
+            },
+        );
+        handle
+    }
+
+    /// Look at a frame from the workload before handing it to the stack.
+    fn ingress(&mut self, frame: Vec<u8>) {
+        match classify(&frame) {
+            Some(Inbound::TcpSyn(key)) => {
+                if self.pending.iter().any(|pending| pending.key == key) {
+                    // A retransmission; the original is still held back.
+                    return;
+                }
+                let known = self.tcp_flows.iter().any(|flow| {
+                    let socket = self.sockets.get::<tcp::Socket>(flow.handle);
+                    socket.remote_endpoint() == Some(key.0)
+                        && socket.local_endpoint() == Some(key.1)
+                });
+                if !known {
+                    match self.translate(key.1).map(connect_nonblocking) {
+                        Some(Ok(stream)) => {
+                            debug!("slirp: connecting {} -> {}", key.0, key.1);
+                            self.pending.push(PendingConnect {
+                                key,
+                                stream,
+                                syn: frame,
+                            });
+                            return;
+                        }
+                        // Without a listening socket, the stack answers
+                        // with a reset.
+                        _ => debug!("slirp: refusing {} -> {}", key.0, key.1),
+                    }
+                }
+            }
+            Some(Inbound::Udp(port)) => {
+                self.udp_port(port);
+            }
+            None => {}
+        }
+
+        self.device.rx.push_back(frame);
+    }
+
+    /// Hand connection requests whose host connection has been established
+    /// to the stack, with a socket to accept them, or without one if the
+    /// host connection failed.
+    fn check_pending(&mut self) {
+        let mut index = 0;
+        while index < self.pending.len() {
+            let pending = &self.pending[index];
+            let connected = match pending.stream.take_error() {
+                Ok(None) => match pending.stream.peer_addr() {
+                    Ok(_) => true,
+                    Err(e) if e.kind() == io::ErrorKind::NotConnected => {
+                        index += 1;
+                        continue;
+                    }
+                    Err(_) => false,
+                },
+                _ => false,
+            };
+
+            let pending = self.pending.swap_remove(index);
+            if connected {
+                let mut socket = self.new_tcp_socket();
+                let (_, dst) = pending.key;
+                let listen = IpListenEndpoint {
+                    addr: Some(dst.addr),
+                    port: dst.port,
+                };
+                if socket.listen(listen).is_ok() {
+                    let handle = self.sockets.add(socket);
+                    self.tcp_flows.push(TcpFlow {
+                        handle,
+                        stream: pending.stream,
+                        host_eof: false,
+                        guest_eof: false,
+                    });
+                }
+            }
+            self.device.rx.push_back(pending.syn);
+        }
+    }
+
+    /// Accept connections and datagrams on forwarded host ports.
+    fn accept_forwards(&mut self) {
+        for index in 0..self.forwards.len() {
+            match &self.forwards[index] {
+                Forward::Tcp {
+                    listener,
+                    guest_port,
+                } => {
+                    let guest_port = *guest_port;
+                    let mut accepted = Vec::new();
+                    while let Ok((stream, _)) = listener.accept() {
+                        accepted.push(stream);
+                    }
+
+                    for stream in accepted {
+                        if stream.set_nonblocking(true).is_err() {
+                            continue;
+                        }
+                        let mut socket = self.new_tcp_socket();
+                        let local =
+                            IpEndpoint::new(IpAddress::Ipv4(SLIRP_GATEWAY), self.ephemeral_port());
+                        let remote =
+                            IpEndpoint::new(IpAddress::Ipv4(SLIRP_GUEST_ADDRESS), guest_port);
+                        if socket.connect(self.iface.context(), remote, local).is_ok() {
+                            let handle = self.sockets.add(socket);
+                            self.tcp_flows.push(TcpFlow {
+                                handle,
+                                stream,
+                                host_eof: false,
+                                guest_eof: false,
+                            });
+                        }
+                    }
+                }
+                Forward::Udp { socket, guest_port } => {
+                    let guest_port = *guest_port;
+                    let mut buf = vec![0; 65536];
+                    let mut received = Vec::new();
+                    while let Ok((len, client)) = socket.recv_from(&mut buf) {
+                        received.push((buf[..len].to_vec(), client));
+                    }
+
+                    for (data, client) in received {
+                        let port = match self
+                            .udp_forwarded
+                            .iter()
+                            .find(|(_, assoc)| **assoc == (index, client))
+                        {
+                            Some((port, _)) => *port,
+                            None => {
+                                let port = self.ephemeral_port();
+                                self.udp_forwarded.insert(port, (index, client));
+                                port
+                            }
+                        };
+                        let handle = self.udp_port(port);
+                        let mut meta = udp::UdpMetadata::from(IpEndpoint::new(
+                            IpAddress::Ipv4(SLIRP_GUEST_ADDRESS),
+                            guest_port,
+                        ));
+                        meta.local_address = Some(IpAddress::Ipv4(SLIRP_GATEWAY));
+                        let _ = self
+                            .sockets
+                            .get_mut::<udp::Socket>(handle)
+                            .send_slice(&data, meta);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Move data between the stack's TCP sockets and the host's, and drop
+    /// connections which are closed on both sides.
+    fn pump_tcp(&mut self) {
+        self.tcp_flows.retain_mut(|flow| {
+            let socket = self.sockets.get_mut::<tcp::Socket>(flow.handle);
+
+            // From the workload to the host.
+            while socket.can_recv() {
+                let written = socket.recv(|data| match flow.stream.write(data) {
+                    Ok(len) => (len, Ok(len)),
+                    Err(e) => (0, Err(e)),
+                });
+                match written {
+                    Ok(Ok(len)) if len > 0 => {}
+                    Ok(Err(e)) if e.kind() != io::ErrorKind::WouldBlock => socket.abort(),
+                    _ => break,
+                }
+            }
+            let guest_closed = matches!(
+                socket.state(),
+                tcp::State::CloseWait
+                    | tcp::State::LastAck
+                    | tcp::State::Closing
+                    | tcp::State::TimeWait
+            );
+            if guest_closed && !flow.guest_eof && socket.recv_queue() == 0 {
+                let _ = flow.stream.shutdown(Shutdown::Write);
+                flow.guest_eof = true;
+            }
+
+            // From the host to the workload.
+            while !flow.host_eof && socket.may_send() && socket.can_send() {
+                let read = socket.send(|buf| match flow.stream.read(buf) {
+                    Ok(len) => (len, Ok(len)),
+                    Err(e) => (0, Err(e)),
+                });
+                match read {
+                    Ok(Ok(0)) => {
+                        socket.close();
+                        flow.host_eof = true;
+                    }
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    _ => {
+                        socket.abort();
+                        break;
+                    }
+                }
+            }
+
+            if socket.state() == tcp::State::Closed {
+                self.sockets.remove(flow.handle);
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Move datagrams between the stack's UDP sockets and the host's.
+    fn pump_udp(&mut self) {
+        let now = Instant::now();
+
+        // From the workload to the host.
+        let mut outbound = Vec::new();
+        for (port, entry) in &self.udp_ports {
+            let socket = self.sockets.get_mut::<udp::Socket>(entry.handle);
+            while let Ok((data, meta)) = socket.recv() {
+                let Some(local) = meta.local_address else {
+                    continue;
+                };
+                outbound.push((*port, meta.endpoint, local, data.to_vec()));
+            }
+        }
+
+        for (port, guest, local, data) in outbound {
+            if let Some(entry) = self.udp_ports.get_mut(&port) {
+                entry.last_used = now;
+            }
+
+            // Replies to a forwarded host port.
+            if local == IpAddress::Ipv4(SLIRP_GATEWAY)
+                && let Some((index, client)) = self.udp_forwarded.get(&port)
+                && let Some(Forward::Udp { socket, guest_port }) = self.forwards.get(*index)
+                && guest.port == *guest_port
+            {
+                let _ = socket.send_to(&data, client);
+                continue;
+            }
+
+            let key = (guest, IpEndpoint::new(local, port));
+            if !self.udp_flows.contains_key(&key) {
+                let Some(target) = self.translate(key.1) else {
+                    continue;
+                };
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+                    socket.connect(target)?;
+                    socket.set_nonblocking(true)?;
+                    Ok(socket)
+                });
+                match socket {
+                    Ok(socket) => {
+                        debug!("slirp: new UDP flow {} -> {target}", key.0);
+                        self.udp_flows.insert(
+                            key,
+                            UdpFlow {
+                                socket,
+                                last_used: now,
+                            },
+                        );
+                    }
+                    Err(e) => {
+                        debug!("slirp: unable to open UDP flow to {target}: {e}");
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(flow) = self.udp_flows.get_mut(&key) {
+                flow.last_used = now;
+                let _ = flow.socket.send(&data);
+            }
+        }
+
+        // From the host to the workload.
+        let mut buf = vec![0; 65536];
+        for (key, flow) in &mut self.udp_flows {
+            let Some(entry) = self.udp_ports.get_mut(&key.1.port) else {
+                continue;
+            };
+            let socket = self.sockets.get_mut::<udp::Socket>(entry.handle);
+            while socket.can_send() {
+                let Ok(len) = flow.socket.recv(&mut buf) else {
+                    break;
+                };
+                let mut meta = udp::UdpMetadata::from(key.0);
+                meta.local_address = Some(key.1.addr);
+                let _ = socket.send_slice(&buf[..len], meta);
+                flow.last_used = now;
+                entry.last_used = now;
+            }
+        }
+    }
+
+    /// Forget UDP flows and sockets which have been idle for a while.
+    fn expire_udp(&mut self) {
+        let now = Instant::now();
+        self.udp_flows
+            .retain(|_, flow| now - flow.last_used < UDP_IDLE_TIMEOUT);
+
+        let udp_flows = &self.udp_flows;
+        let sockets = &mut self.sockets;
+        let udp_forwarded = &mut self.udp_forwarded;
+        self.udp_ports.retain(|port, entry| {
+            let in_use = udp_flows.keys().any(|(_, dst)| dst.port == *port);
+            if in_use || now - entry.last_used < UDP_IDLE_TIMEOUT {
+                return true;
+            }
+            udp_forwarded.remove(port);
+            sockets.remove(entry.handle);
+            false
+        });
+    }
+
+    /// Read the frames the workload has sent. Returns false once the TAP
+    /// device is gone.
+    fn read_frames(&mut self, buf: &mut [u8]) -> bool {
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.device.fd.as_raw_fd(),
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                )
+            };
+            if len > 0 {
+                self.ingress(buf[..len as usize].to_vec());
+                continue;
+            }
+
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => true,
+                io::ErrorKind::Interrupted => continue,
+                _ => {
+                    debug!("slirp: TAP device closed: {err}");
+                    false
+                }
+            };
+        }
+    }
+
+    /// The file descriptors to wait on, with the events of interest.
+    fn pollfds(&self, control: &UnixStream) -> Vec<libc::pollfd> {
+        let pollfd = |fd: RawFd, events| libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        };
+
+        let mut fds = vec![
+            pollfd(control.as_raw_fd(), libc::POLLIN),
+            pollfd(self.device.fd.as_raw_fd(), libc::POLLIN),
+        ];
+        for flow in &self.tcp_flows {
+            let socket = self.sockets.get::<tcp::Socket>(flow.handle);
+            let mut events = 0;
+            if !flow.host_eof && socket.may_send() && socket.can_send() {
+                events |= libc::POLLIN;
+            }
+            if socket.recv_queue() > 0 {
+                events |= libc::POLLOUT;
+            }
+            fds.push(pollfd(flow.stream.as_raw_fd(), events));
+        }
+        for pending in &self.pending {
+            fds.push(pollfd(pending.stream.as_raw_fd(), libc::POLLOUT));
+        }
+        for flow in self.udp_flows.values() {
+            fds.push(pollfd(flow.socket.as_raw_fd(), libc::POLLIN));
+        }
+        for forward in &self.forwards {
+            fds.push(pollfd(forward.fd(), libc::POLLIN));
+        }
+        fds
+    }
+
+    /// Run until the TAP device goes away, or `control` is closed.
+    fn run(&mut self, control: &UnixStream) -> Result<()> {
+        let mut buf = vec![0; self.device.mtu + 14];
+
+        loop {
+            if !self.read_frames(&mut buf) {
+                return Ok(());
+            }
+            self.check_pending();
+            self.accept_forwards();
+
+            let now = Instant::now();
+            self.iface.poll(now, &mut self.device, &mut self.sockets);
+            self.pump_tcp();
+            self.pump_udp();
+            self.iface.poll(now, &mut self.device, &mut self.sockets);
+            self.expire_udp();
+
+            let timeout = self
+                .iface
+                .poll_delay(Instant::now(), &self.sockets)
+                .unwrap_or(Duration::from_secs(1))
+                .min(Duration::from_secs(1));
+            let mut fds = self.pollfds(control);
+            let ret = unsafe {
+                libc::poll(
+                    fds.as_mut_ptr(),
+                    fds.len() as libc::nfds_t,
+                    timeout.total_millis() as libc::c_int,
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    bail!("poll failed: {err}");
+                }
+            }
+
+            // The supervisor never writes after handing over the TAP
+            // device, so this means it is gone.
+            if fds[0].revents != 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// A process, forked before any namespaces are unshared, which runs the
+/// user-mode network of a workload once given its TAP device.
+pub struct SlirpHelper {
+    pid: Pid,
+    control: UnixStream,
+}
+
+impl SlirpHelper {
+    /// Fork the helper process. Forwarded host ports are bound right away.
+    pub fn spawn(config: &SlirpConfig) -> Result<SlirpHelper> {
+        let (control, mut helper) = UnixStream::pair()?;
+
+        match unsafe { fork() }? {
+            ForkResult::Parent { child } => {
+                drop(helper);
+
+                debug!("slirp helper pid = {child}");
+                Ok(SlirpHelper {
+                    pid: child,
+                    control,
+                })
+            }
+            ForkResult::Child => {
+                drop(control);
+
+                let forwards = config
+                    .port_forwards
+                    .iter()
+                    .map(Forward::bind)
+                    .collect::<Result<Vec<_>>>();
+                let engine =
+                    receive_tap(&helper).and_then(|tap| Ok(Engine::new(tap, config, forwards?)));
+
+                // Report whether the network is up, then run it.
+                let code = match engine {
+                    Ok(mut engine) => {
+                        let _ = helper.write_all(b"\n");
+                        match engine.run(&helper) {
+                            Ok(()) => 0,
+                            Err(e) => {
+                                error!("slirp: {e}");
+                                1
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let _ = helper.write_all(format!("{e}\n").as_bytes());
+                        1
+                    }
+                };
+                process::exit(code)
+            }
+        }
+    }
+
+    /// Create the TAP device of the user-mode network in the caller's
+    /// network namespace, and hand it over to the helper.
+    pub fn attach(&self) -> Result<()> {
+        let tap = create_tap(SLIRP_TAP_NAME)?;
+
+        let fds = [tap.as_raw_fd()];
+        socket::sendmsg::<()>(
+            self.control.as_raw_fd(),
+            &[IoSlice::new(b"t")],
+            &[ControlMessage::ScmRights(&fds)],
+            MsgFlags::empty(),
+            None,
+        )
+        .map_err(|e| anyhow!("unable to pass TAP device to slirp helper: {e}"))?;
+
+        let mut status = String::new();
+        BufReader::new(&self.control).read_line(&mut status)?;
+        match status.trim_end() {
+            "" if status.ends_with('\n') => Ok(()),
+            "" => bail!("slirp helper exited unexpectedly"),
+            error => bail!("slirp helper failed: {error}"),
+        }
+    }
+
+    /// Stop the helper, once the workload has exited.
+    pub fn stop(self) -> Result<()> {
+        let SlirpHelper { pid, control } = self;
+        drop(control);
+        waitpid(pid, None)?;
+        Ok(())
+    }
+}
+
+fn receive_tap(control: &UnixStream) -> Result<OwnedFd> {
+    let mut buf = [0u8; 1];
+    let mut iov = [IoSliceMut::new(&mut buf)];
+    let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+    let msg = socket::recvmsg::<()>(
+        control.as_fd().as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )
+    .map_err(|e| anyhow!("unable to receive TAP device: {e}"))?;
+
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg
+            && let Some(fd) = fds.first()
+        {
+            return Ok(unsafe { OwnedFd::from_raw_fd(*fd) });
+        }
+    }
+    bail!("supervisor did not pass a TAP device")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace::Namespace;
+    use crate::network::{NetworkConfig, WorkloadNetns};
+    use crate::unshare::unshare;
+    use nix::sys::wait::WaitStatus;
+    use nix::unistd::geteuid;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration as StdDuration, Instant as StdInstant};
+
+    const TIMEOUT: StdDuration = StdDuration::from_secs(5);
+
+    #[test]
+    fn root_only_workload_reaches_host_and_forwarded_port() {
+        if !geteuid().is_root() {
+            return;
+        }
+
+        let child = match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => unsafe { libc::_exit(slirp_test()) },
+            ForkResult::Parent { child } => child,
+        };
+        let status = waitpid(child, None).expect("waitpid failed");
+        assert!(matches!(status, WaitStatus::Exited(_, 0)), "{status:?}");
+    }
+
+    fn slirp_test() -> i32 {
+        let (Ok(tcp_echo), Ok(udp_echo)) = (
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)),
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)),
+        ) else {
+            return 1;
+        };
+        let (tcp_port, udp_port) = (
+            tcp_echo.local_addr().unwrap().port(),
+            udp_echo.local_addr().unwrap().port(),
+        );
+        let forward_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .map(|address| address.port())
+            .unwrap_or(0);
+
+        let config = SlirpConfig {
+            port_forwards: vec![PortForward {
+                host_port: forward_port,
+                guest_port: 8080,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let Ok(helper) = SlirpHelper::spawn(&config) else {
+            return 2;
+        };
+
+        // These threads stay in the host's network namespace.
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = tcp_echo.accept() {
+                let mut buf = [0; 64];
+                while let Ok(len @ 1..) = stream.read(&mut buf) {
+                    let _ = stream.write_all(&buf[..len]);
+                }
+            }
+        });
+        thread::spawn(move || {
+            let mut buf = [0; 64];
+            if let Ok((len, peer)) = udp_echo.recv_from(&mut buf) {
+                let _ = udp_echo.send_to(&buf[..len], peer);
+            }
+        });
+        let (start, started) = mpsc::channel::<()>();
+        let (done, forwarded) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = started.recv();
+            let result =
+                TcpStream::connect((Ipv4Addr::LOCALHOST, forward_port)).and_then(|mut s| {
+                    s.set_read_timeout(Some(TIMEOUT))?;
+                    s.write_all(b"forward")?;
+                    let mut buf = [0; 7];
+                    s.read_exact(&mut buf)?;
+                    Ok(buf)
+                });
+            let _ = done.send(result.map(|buf| &buf == b"forward").unwrap_or(false));
+        });
+
+        if unshare(&[Namespace::Net]).is_err() {
+            return 3;
+        }
+        let Ok(netns) = WorkloadNetns::open(None) else {
+            return 4;
+        };
+        if helper.attach().is_err() {
+            return 5;
+        }
+        let network = NetworkConfig {
+            slirp: Some(config),
+            ..Default::default()
+        };
+        if netns.setup(&network, "unused").is_err() {
+            return 6;
+        }
+
+        // The gateway stands for the host's loopback interface.
+        let gateway = |port| SocketAddr::from((SLIRP_GATEWAY, port));
+        let tcp = TcpStream::connect_timeout(&gateway(tcp_port), TIMEOUT).and_then(|mut s| {
+            s.set_read_timeout(Some(TIMEOUT))?;
+            s.write_all(b"tcp")?;
+            let mut buf = [0; 3];
+            s.read_exact(&mut buf)?;
+            Ok(buf)
+        });
+        if !matches!(tcp, Ok(buf) if &buf == b"tcp") {
+            return 7;
+        }
+
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|s| {
+            s.set_read_timeout(Some(TIMEOUT))?;
+            s.send_to(b"udp", gateway(udp_port))?;
+            let mut buf = [0; 3];
+            let (_, peer) = s.recv_from(&mut buf)?;
+            Ok((buf, peer))
+        });
+        if !matches!(udp, Ok((buf, peer)) if &buf == b"udp" && peer == gateway(udp_port)) {
+            return 8;
+        }
+
+        let Ok(listener) = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 8080)) else {
+            return 9;
+        };
+        let _ = listener.set_nonblocking(true);
+        let _ = start.send(());
+        let deadline = StdInstant::now() + TIMEOUT;
+        let mut stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(_) if StdInstant::now() < deadline => {
+                    thread::sleep(StdDuration::from_millis(10))
+                }
+                Err(_) => return 10,
+            }
+        };
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_read_timeout(Some(TIMEOUT));
+        let mut buf = [0; 7];
+        if stream.read_exact(&mut buf).is_err() || stream.write_all(&buf).is_err() {
+            return 11;
+        }
+        if forwarded.recv_timeout(TIMEOUT) != Ok(true) {
+            return 12;
+        }
+
+        if helper.stop().is_err() {
+            return 13;
+        }
+        0
+    }
+}
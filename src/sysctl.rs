@@ -0,0 +1,133 @@
+//! Namespaced kernel parameters.
+//!
+//! Most sysctls are global to the host, but some are scoped to a namespace,
+//! and `/proc/sys` shows the writer's own copy of them. The supervisor sets
+//! them once it has unshared the workload's namespaces, before the workload
+//! is forked. Only namespaces the workload owns may be configured: changing
+//! a joined or shared namespace would affect other processes.
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use anyhow::{Result, anyhow, bail};
+use log::debug;
+
+use crate::namespace::Namespace;
+
+/// Prefixes of the sysctls scoped to a namespace, in dotted form.
+const NAMESPACED: &[(&str, Namespace)] = &[
+    ("net.", Namespace::Net),
+    ("kernel.shm", Namespace::Ipc),
+    ("kernel.msg", Namespace::Ipc),
+    ("kernel.sem", Namespace::Ipc),
+    ("fs.mqueue.", Namespace::Ipc),
+    ("kernel.hostname", Namespace::Uts),
+    ("kernel.domainname", Namespace::Uts),
+];
+
+/// Split a sysctl key into its path components. Keys are either dotted,
+/// like `net.ipv4.ip_forward`, or use slashes, like
+/// `net/ipv4/conf/eth0.100/rp_filter`, which allows names containing dots.
+fn components(key: &str) -> Result<Vec<&str>> {
+    let separator = if key.contains('/') { '/' } else { '.' };
+    let components = key.split(separator).collect::<Vec<_>>();
+    if components
+        .iter()
+        .any(|c| c.is_empty() || *c == "." || *c == ".." || c.contains('\0'))
+    {
+        bail!("invalid sysctl '{key}'");
+    }
+    Ok(components)
+}
+
+/// The namespace a sysctl is scoped to. Host-global sysctls are rejected.
+pub fn namespace_of(key: &str) -> Result<Namespace> {
+    let dotted = components(key)?.join(".");
+
+    NAMESPACED
+        .iter()
+        .find(|(prefix, _)| dotted.starts_with(prefix))
+        .map(|(_, namespace)| *namespace)
+        .ok_or_else(|| anyhow!("sysctl '{key}' is not namespaced"))
+}
+
+/// Check that every sysctl belongs to one of the namespaces in `owned`.
+pub fn validate(sysctls: &BTreeMap<String, String>, owned: &[Namespace]) -> Result<()> {
+    for key in sysctls.keys() {
+        let namespace = namespace_of(key)?;
+        if !owned.contains(&namespace) {
+            bail!("sysctl '{key}' requires a new {namespace:?} namespace");
+        }
+    }
+    Ok(())
+}
+
+/// Write `sysctls` under `/proc/sys`, in the caller's namespaces.
+pub fn apply(sysctls: &BTreeMap<String, String>) -> Result<()> {
+    for (key, value) in sysctls {
+        let path = format!("/proc/sys/{}", components(key)?.join("/"));
+        debug!("setting sysctl {key} = {value}");
+        fs::write(&path, value).map_err(|e| anyhow!("unable to set sysctl '{key}': {e}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unshare::unshare;
+    use nix::sys::wait::{WaitStatus, waitpid};
+    use nix::unistd::{ForkResult, fork, geteuid};
+
+    #[test]
+    fn sysctls_are_scoped_to_their_namespace() {
+        assert_eq!(
+            namespace_of("net.ipv4.ip_unprivileged_port_start").unwrap(),
+            Namespace::Net
+        );
+        assert_eq!(
+            namespace_of("net/ipv4/conf/eth0.100/rp_filter").unwrap(),
+            Namespace::Net
+        );
+        assert_eq!(namespace_of("kernel.shmmax").unwrap(), Namespace::Ipc);
+        assert_eq!(namespace_of("kernel.sem").unwrap(), Namespace::Ipc);
+        assert_eq!(namespace_of("fs.mqueue.msg_max").unwrap(), Namespace::Ipc);
+        assert_eq!(namespace_of("kernel.domainname").unwrap(), Namespace::Uts);
+
+        assert!(namespace_of("kernel.pid_max").is_err());
+        assert!(namespace_of("vm.overcommit_memory").is_err());
+        assert!(namespace_of("fs.mqueuex").is_err());
+        assert!(namespace_of("net.ipv4..ip_forward").is_err());
+        assert!(namespace_of("net/../kernel/pid_max").is_err());
+
+        let sysctls = BTreeMap::from([("net.core.somaxconn".to_string(), "1024".to_string())]);
+        assert!(validate(&sysctls, &[Namespace::Net]).is_ok());
+        assert!(validate(&sysctls, &[Namespace::Ipc]).is_err());
+    }
+
+    #[test]
+    fn root_only_sysctls_are_set_in_the_new_namespace() {
+        if !geteuid().is_root() {
+            return;
+        }
+
+        let key = "net.ipv4.ip_unprivileged_port_start";
+        let path = "/proc/sys/net/ipv4/ip_unprivileged_port_start";
+        let host = fs::read_to_string(path).unwrap();
+        let value = if host.trim() == "80" { "81" } else { "80" };
+
+        let child = match unsafe { fork() }.expect("fork failed") {
+            ForkResult::Child => {
+                let sysctls = BTreeMap::from([(key.to_string(), value.to_string())]);
+                let ok = unshare(&[Namespace::Net]).is_ok()
+                    && apply(&sysctls).is_ok()
+                    && fs::read_to_string(path).is_ok_and(|v| v.trim() == value);
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            ForkResult::Parent { child } => child,
+        };
+        let status = waitpid(child, None).expect("waitpid failed");
+        assert!(matches!(status, WaitStatus::Exited(_, 0)), "{status:?}");
+        assert_eq!(fs::read_to_string(path).unwrap(), host);
+    }
+}
@@ -0,0 +1,308 @@
+//! Systemd cgroup driver.
+//!
+//! On systemd hosts, the cgroup tree belongs to systemd, and unprivileged
+//! users can only get a writable subtree by asking systemd to delegate one.
+//! Instead of requiring the caller to pre-create a delegated cgroup as
+//! described in the `cgroup` module, we can ask the system (or, for rootless
+//! use, the user) manager over D-Bus for a transient `.scope` unit with
+//! `Delegate=yes` containing the supervisor, and use that scope as the
+//! cgroup root.
+//!
+//! A cgroup with processes in it cannot enable controllers for its children,
+//! so once the scope exists the supervisor moves itself into a `supervisor`
+//! leaf, leaving the scope root free for the workload's subtree.
+
+use std::fs;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::cgroup::CGroup;
+use crate::config::ResourceLimits;
+use crate::dbus::{Connection, Encoder};
+
+/// How the cgroup used for a workload is obtained.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CGroupDriver {
+    /// Use `cgroupfs` (or the root hierarchy) directly.
+    #[default]
+    Cgroupfs,
+
+    /// Ask the systemd system manager for a delegated scope.
+    Systemd,
+
+    /// Ask the calling user's systemd manager for a delegated scope.
+    SystemdUser,
+}
+
+/// A systemd property value we know how to marshal.
+#[derive(Debug, PartialEq)]
+enum Property {
+    String(String),
+    Boolean(bool),
+    Uint64(u64),
+    Uint32Array(Vec<u32>),
+}
+
+/// Escape a string for use as a unit name, as systemd-escape(1) does.
+fn escape_unit_name(name: &str) -> String {
+    name.bytes()
+        .enumerate()
+        .map(|(i, b)| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' => (b as char).to_string(),
+            b'.' if i > 0 => ".".to_string(),
+            b'/' => "-".to_string(),
+            _ => format!("\\x{b:02x}"),
+        })
+        .collect()
+}
+
+/// Parse a cgroupfs limit value, where `max` means unlimited.
+fn parse_limit(value: &str) -> Option<u64> {
+    match value.trim() {
+        "max" => Some(u64::MAX),
+        v => v.parse().ok(),
+    }
+}
+
+/// Map cgroupfs resource limits onto the equivalent unit properties, so that
+/// systemd knows about the limits it is delegating. Limits without a systemd
+/// equivalent are still written to cgroupfs when the workload cgroup is set
+/// up.
+fn limit_properties(limits: &ResourceLimits) -> Vec<(&'static str, Property)> {
+    let mut properties = Vec::new();
+
+    for (key, value) in limits {
+        let mapped = match key.as_str() {
+            "memory.max" => parse_limit(value).map(|v| ("MemoryMax", v)),
+            "memory.high" => parse_limit(value).map(|v| ("MemoryHigh", v)),
+            "memory.low" => parse_limit(value).map(|v| ("MemoryLow", v)),
+            "memory.min" => parse_limit(value).map(|v| ("MemoryMin", v)),
+            "memory.swap.max" => parse_limit(value).map(|v| ("MemorySwapMax", v)),
+            "pids.max" => parse_limit(value).map(|v| ("TasksMax", v)),
+            "cpu.weight" => parse_limit(value).map(|v| ("CPUWeight", v)),
+            "io.weight" => {
+                parse_limit(value.trim_start_matches("default ")).map(|v| ("IOWeight", v))
+            }
+            "cpu.max" => {
+                // "$QUOTA $PERIOD" in microseconds; systemd wants the quota
+                // normalised to a one second period.
+                let mut fields = value.split_whitespace();
+                let quota = fields.next().and_then(parse_limit);
+                let period = fields
+                    .next()
+                    .and_then(|p| p.parse::<u64>().ok())
+                    .unwrap_or(100000);
+                match quota {
+                    Some(u64::MAX) => Some(("CPUQuotaPerSecUSec", u64::MAX)),
+                    Some(quota) if period > 0 => {
+                        properties.push(("CPUQuotaPeriodUSec", Property::Uint64(period)));
+                        Some(("CPUQuotaPerSecUSec", quota.saturating_mul(1000000) / period))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        match mapped {
+            Some((name, value)) => properties.push((name, Property::Uint64(value))),
+            None => debug!("resource limit {key} has no systemd equivalent"),
+        }
+    }
+
+    properties
+}
+
+fn marshal_start_transient_unit(unit: &str, properties: &[(&str, Property)]) -> Vec<u8> {
+    let mut body = Encoder::new();
+    body.string(unit);
+    body.string("fail");
+    body.array(8, |array| {
+        for (name, value) in properties {
+            array.structure(|property| {
+                property.string(name);
+                match value {
+                    Property::String(s) => property.variant("s", |v| v.string(s)),
+                    Property::Boolean(b) => property.variant("b", |v| v.boolean(*b)),
+                    Property::Uint64(n) => property.variant("t", |v| v.uint64(*n)),
+                    Property::Uint32Array(ns) => property.variant("au", |v| {
+                        v.array(4, |elements| ns.iter().for_each(|n| elements.uint32(*n)))
+                    }),
+                }
+            });
+        }
+    });
+    // No auxiliary units.
+    body.array(8, |_| {});
+    body.into_bytes()
+}
+
+/// The cgroup the calling process is in, relative to the cgroup2 root.
+fn own_cgroup() -> Result<String> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+    match cgroups.lines().find_map(|line| line.strip_prefix("0::")) {
+        Some(path) => Ok(path.to_string()),
+        None => bail!("not running on a cgroup2 (unified) hierarchy"),
+    }
+}
+
+/// The name of the scope unit of the workload named `identity`.
+pub fn unit_name(identity: &str) -> String {
+    format!("styrolite-{}.scope", escape_unit_name(identity))
+}
+
+/// Create a transient, delegated scope named after `identity` containing
+/// the supervisor, and return a handle to its cgroup. The supervisor is
+/// moved into a `supervisor` leaf of the scope before returning.
+///
+/// `limits` are set on the scope as the equivalent unit properties, so that
+/// systemd knows about them. The scope also holds the supervisor, so they
+/// are written to the workload's own cgroup below it as well.
+pub fn create_scope(
+    driver: CGroupDriver,
+    identity: &str,
+    limits: Option<&ResourceLimits>,
+) -> Result<CGroup> {
+    let mut connection = match driver {
+        CGroupDriver::Systemd => Connection::system()?,
+        CGroupDriver::SystemdUser => Connection::user()?,
+        CGroupDriver::Cgroupfs => bail!("the cgroupfs driver does not create scopes"),
+    };
+
+    let unit = unit_name(identity);
+    let pid = process::id();
+
+    let mut properties = vec![
+        (
+            "Description",
+            Property::String(format!("styrolite workload {identity}")),
+        ),
+        ("PIDs", Property::Uint32Array(vec![pid])),
+        ("Delegate", Property::Boolean(true)),
+        (
+            "CollectMode",
+            Property::String("inactive-or-failed".to_string()),
+        ),
+    ];
+    if let Some(limits) = limits {
+        properties.extend(limit_properties(limits));
+    }
+
+    debug!("creating systemd scope {unit} for supervisor (pid {pid})");
+    connection.call(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager",
+        "StartTransientUnit",
+        "ssa(sv)a(sa(sv))",
+        marshal_start_transient_unit(&unit, &properties),
+    )?;
+
+    // The reply only means the start job was queued; wait for systemd to
+    // actually move us into the scope.
+    let suffix = format!("/{unit}");
+    let mut scope = own_cgroup()?;
+    for _ in 0..100 {
+        if scope.ends_with(&suffix) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+        scope = own_cgroup()?;
+    }
+    if !scope.ends_with(&suffix) {
+        bail!("systemd did not move the supervisor into {unit} (still in {scope})");
+    }
+
+    let root = CGroup::open(&format!("/sys/fs/cgroup{scope}"))?;
+    let leaf = root.clone().create_child("supervisor")?;
+    if let Err(e) = leaf.set_child_value("cgroup.procs", &format!("{pid}")) {
+        warn!("unable to move supervisor out of the root of {unit}: {e}");
+    }
+
+    Ok(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_names_are_escaped() {
+        assert_eq!(escape_unit_name("abc-123"), "abc\\x2d123");
+        assert_eq!(escape_unit_name("a.b/c"), "a.b-c");
+        assert_eq!(escape_unit_name(".hidden"), "\\x2ehidden");
+    }
+
+    #[test]
+    fn limits_map_to_unit_properties() {
+        let limits = ResourceLimits::from([
+            ("memory.max".to_string(), "104857600".to_string()),
+            ("pids.max".to_string(), "max".to_string()),
+            ("cpu.max".to_string(), "50000 100000".to_string()),
+            ("hugetlb.2MB.max".to_string(), "0".to_string()),
+        ]);
+        let properties = limit_properties(&limits);
+        assert!(properties.contains(&("MemoryMax", Property::Uint64(104857600))));
+        assert!(properties.contains(&("TasksMax", Property::Uint64(u64::MAX))));
+        assert!(properties.contains(&("CPUQuotaPerSecUSec", Property::Uint64(500000))));
+        assert!(properties.contains(&("CPUQuotaPeriodUSec", Property::Uint64(100000))));
+        assert_eq!(properties.len(), 4);
+    }
+
+    /// Run a private dbus-daemon and send it a well-formed StartTransientUnit
+    /// call. There is no systemd on the private bus, so the call must fail
+    /// with the bus's own "no such name" error rather than a disconnect,
+    /// which is what a malformed message would cause.
+    #[test]
+    fn start_transient_unit_is_accepted_by_dbus_daemon() {
+        let Ok(dir) = tempfile::TempDir::new() else {
+            return;
+        };
+        let socket = dir.path().join("bus");
+        let address = format!("unix:path={}", socket.display());
+        let Ok(mut daemon) = std::process::Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--nopidfile"])
+            .arg(format!("--address={address}"))
+            .stderr(std::process::Stdio::null())
+            .spawn()
+        else {
+            return;
+        };
+        for _ in 0..100 {
+            if socket.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let result = Connection::open(&address).and_then(|mut connection| {
+            let properties = [
+                ("PIDs", Property::Uint32Array(vec![process::id()])),
+                ("Delegate", Property::Boolean(true)),
+                ("MemoryMax", Property::Uint64(1 << 20)),
+            ];
+            connection.call(
+                "org.freedesktop.systemd1",
+                "/org/freedesktop/systemd1",
+                "org.freedesktop.systemd1.Manager",
+                "StartTransientUnit",
+                "ssa(sv)a(sa(sv))",
+                marshal_start_transient_unit("styrolite-test.scope", &properties),
+            )
+        });
+        let _ = daemon.kill();
+        let _ = daemon.wait();
+
+        let err = result.expect_err("no systemd manager on a private bus");
+        assert!(
+            err.to_string()
+                .contains("org.freedesktop.DBus.Error.NameHasNoOwner"),
+            "{err}"
+        );
+    }
+}
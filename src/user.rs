@@ -0,0 +1,135 @@
+//! Resolution of user specs like `nginx`, `1000` or `app:staff` against a
+//! container's `/etc/passwd` and `/etc/group`, following Docker's rules:
+//!
+//! * The user is looked up by name, or by uid if numeric. A numeric user
+//!   with no passwd entry is used as is, with gid 0 and `HOME` set to `/`.
+//! * The primary group is the user's passwd group, unless a group is given,
+//!   by name or by gid. A numeric group with no group entry is used as is.
+//! * Without an explicit group, the supplementary groups are those listing
+//!   the user as a member.
+//!
+//! Missing files are treated as empty, so numeric specs always resolve.
+
+use std::fs;
+use std::io;
+
+use anyhow::{Result, anyhow, bail};
+
+/// A resolved user spec.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedUser {
+    pub uid: u32,
+    pub gid: u32,
+    pub supplemental_gids: Vec<u32>,
+    pub home: String,
+}
+
+/// Resolve `user` against the contents of a passwd and a group file.
+pub fn resolve(passwd: &str, group: &str, user: &str) -> Result<ResolvedUser> {
+    let (user, group_spec) = match user.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (user, None),
+    };
+    if user.is_empty() {
+        bail!("user spec has no user");
+    }
+
+    let entry = passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|f| f.len() > 3 && (f[0] == user || f[2] == user));
+    let (name, uid, passwd_gid, home) = match (&entry, user.parse::<u32>()) {
+        (Some(f), _) => (
+            Some(f[0]),
+            f[2].parse::<u32>()?,
+            f[3].parse::<u32>().unwrap_or(0),
+            f.get(5).copied().filter(|home| !home.is_empty()),
+        ),
+        (None, Ok(uid)) => (None, uid, 0, None),
+        (None, Err(_)) => bail!("user {user:?} not found in /etc/passwd"),
+    };
+
+    let groups = group
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .filter(|f| f.len() > 3);
+
+    let gid = match group_spec {
+        None => passwd_gid,
+        Some(spec) => match groups.clone().find(|f| f[0] == spec || f[2] == spec) {
+            Some(f) => f[2].parse()?,
+            None => spec
+                .parse()
+                .map_err(|_| anyhow!("group {spec:?} not found in /etc/group"))?,
+        },
+    };
+
+    let supplemental_gids = match (name, group_spec) {
+        (Some(name), None) => groups
+            .filter(|f| f[3].split(',').any(|member| member == name))
+            .filter_map(|f| f[2].parse().ok())
+            .filter(|&g| g != gid)
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Ok(ResolvedUser {
+        uid,
+        gid,
+        supplemental_gids,
+        home: home.unwrap_or("/").to_string(),
+    })
+}
+
+fn read_or_empty(path: &str) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(anyhow!("unable to read {path}: {e}")),
+    }
+}
+
+/// Resolve `user` against `/etc/passwd` and `/etc/group` of the current
+/// root, which must already be the container's.
+pub fn resolve_in_root(user: &str) -> Result<ResolvedUser> {
+    resolve(
+        &read_or_empty("/etc/passwd")?,
+        &read_or_empty("/etc/group")?,
+        user,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_resolve_like_docker() {
+        let passwd = "app:x:1000:1000::/home/app:/bin/sh\nweb:x:33:33:::/bin/sh\n";
+        let group = "app:x:1000:\nwheel:x:10:app\naudio:x:29:\n";
+        let resolve = |user| resolve(passwd, group, user).unwrap();
+        let user = |uid, gid, supplemental_gids: Vec<u32>, home: &str| ResolvedUser {
+            uid,
+            gid,
+            supplemental_gids,
+            home: home.to_string(),
+        };
+
+        assert_eq!(resolve("app"), user(1000, 1000, vec![10], "/home/app"));
+        assert_eq!(resolve("1000"), user(1000, 1000, vec![10], "/home/app"));
+        assert_eq!(resolve("app:audio"), user(1000, 29, vec![], "/home/app"));
+        assert_eq!(resolve("web"), user(33, 33, vec![], "/"));
+        assert_eq!(resolve("4242"), user(4242, 0, vec![], "/"));
+        assert_eq!(resolve("4242:4343"), user(4242, 4343, vec![], "/"));
+        assert!(super::resolve(passwd, group, "nobody").is_err());
+        assert!(super::resolve(passwd, group, "app:staff").is_err());
+        assert!(super::resolve(passwd, group, ":audio").is_err());
+
+        // Without passwd and group files, only numeric specs resolve.
+        assert_eq!(
+            super::resolve("", "", "0:0").unwrap(),
+            user(0, 0, vec![], "/")
+        );
+        assert!(super::resolve("", "", "root").is_err());
+    }
+}
@@ -1,28 +1,42 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::env;
 use std::ffi::CString;
 use std::fs;
-use std::io::Error;
+use std::io::{Error, Write};
 use std::mem::MaybeUninit;
-use std::path::PathBuf;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
 use std::process;
 use std::ptr;
 
-use crate::caps::{CapabilityBit, get_caps, set_caps, set_keep_caps};
+use crate::caps::{CapResult, CapabilityBit, get_caps, set_caps, set_keep_caps, set_securebits};
 use crate::cgroup::CGroup;
 use crate::config::{
-    AttachRequest, Capabilities, CreateDirMutation, CreateRequest, ExecutableSpec, IdMapping,
-    MountSpec, Mountable, Mutatable, Mutation, Wrappable,
+    AttachRequest, Capabilities, Configurable, CreateRequest, ExecutableSpec, IdMap, MountSpec,
+    Mountable, Mutatable, OverlayRootfs, OverlayUpper, Propagation, TimeOffsets, Wrappable,
 };
-use crate::namespace::Namespace;
+use crate::etc;
+use crate::idmap::{IdMapHelper, IdTranslator, render_mappings};
+use crate::namespace::{Namespace, PinHelper};
+use crate::network::{Netlink, WorkloadNetns};
+use crate::rootfs::{self, Rootfs};
+use crate::sched;
 use crate::signal;
-use crate::unshare::{setns, unshare};
+#[cfg(feature = "slirp")]
+use crate::slirp::SlirpHelper;
+use crate::sysctl;
+use crate::systemd::{self, CGroupDriver};
+use crate::unshare::{self, fork_into_cgroup, setns, unshare};
+use crate::user::{self, ResolvedUser};
 use anyhow::Context;
 use anyhow::{Result, anyhow, bail};
 use libc::{
-    self, PR_CAP_AMBIENT, PR_CAP_AMBIENT_LOWER, PR_CAP_AMBIENT_RAISE, PR_CAPBSET_DROP,
-    PR_SET_NO_NEW_PRIVS, c_int, prctl,
+    self, PR_CAP_AMBIENT, PR_CAP_AMBIENT_CLEAR_ALL, PR_CAP_AMBIENT_LOWER, PR_CAP_AMBIENT_RAISE,
+    PR_CAPBSET_DROP, PR_SET_NO_NEW_PRIVS, c_int, prctl,
 };
 use nix::sys::eventfd::{EfdFlags, EventFd};
+use nix::sys::signal::Signal;
 use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
 use nix::unistd::{ForkResult, Pid, fork};
 
@@ -73,6 +87,23 @@ fn reap_children() -> Result<()> {
     Ok(())
 }
 
+/// Write `contents` to the file `name` of process `pid`, looked up in the
+/// procfs `proc`.
+fn write_proc_file(proc: &OwnedFd, pid: libc::pid_t, name: &str, contents: &str) -> Result<()> {
+    let path = format!("{pid}/{name}");
+    let fd = rootfs::open_in_root(proc, Path::new(&path), libc::O_WRONLY)
+        .map_err(|e| anyhow!("unable to open /proc/{path}: {e}"))?;
+    fs::File::from(fd)
+        .write_all(contents.as_bytes())
+        .map_err(|e| anyhow!("unable to write /proc/{path}: {e}"))
+}
+
+/// Kill and reap a workload which will never be allowed to run.
+fn kill_workload(child: Pid) {
+    let _ = nix::sys::signal::kill(child, Signal::SIGKILL);
+    let _ = waitpid(child, None);
+}
+
 fn wait_for_pid(pid: libc::pid_t) -> Result<i32> {
     match waitpid(Pid::from_raw(pid), None)? {
         WaitStatus::Exited(_, code) => Ok(code),
@@ -80,13 +111,89 @@ fn wait_for_pid(pid: libc::pid_t) -> Result<i32> {
     }
 }
 
-fn fork_and_wait() -> Result<()> {
+/// Open `cgroup` for the workload to be spawned into. Its path is looked up
+/// in the caller's mount namespace, so this must be done before entering the
+/// container's.
+fn open_workload_cgroup(cgroup: &CGroup) -> Result<OwnedFd> {
+    if !cgroup.writable() {
+        bail!(
+            "unable to spawn workload into cgroup {}: access denied",
+            cgroup.path()
+        );
+    }
+
+    cgroup
+        .open_fd()
+        .map_err(|e| anyhow!("unable to open cgroup {}: {e}", cgroup.path()))
+}
+
+/// Fork the workload process, spawning it directly into `cgroup` if one is
+/// given so the supervisor stays outside of it. On kernels without
+/// `CLONE_INTO_CGROUP`, the supervisor is moved into `cgroup` before a plain
+/// fork(2) instead, which was the only behaviour before Linux 5.7.
+fn fork_workload(cgroup: Option<&OwnedFd>) -> Result<ForkResult> {
+    let Some(cgroup) = cgroup else {
+        return Ok(unsafe { fork() }?);
+    };
+
+    // The kernel only checks the target cgroup after allocating the child's
+    // pid. If that child would have been the first process in a new pid
+    // namespace, a failure there leaves the namespace unable to ever get an
+    // init, and every later fork(2) fails with ENOMEM. So the cgroup is
+    // checked up front by open_workload_cgroup, and we don't try to recover
+    // from failures we couldn't foresee.
+    match unsafe { fork_into_cgroup(cgroup) } {
+        Ok(result) => Ok(result),
+        Err(e)
+            if matches!(
+                e.raw_os_error(),
+                Some(libc::ENOSYS | libc::E2BIG | libc::EINVAL)
+            ) =>
+        {
+            // These are returned before any pid is allocated.
+            debug!("clone3(CLONE_INTO_CGROUP) unavailable ({e}), falling back to fork(2)");
+
+            let pid = process::id();
+            debug!("binding supervisor (pid {pid}) to the workload cgroup");
+            let procs = unsafe {
+                libc::openat(
+                    cgroup.as_raw_fd(),
+                    c"cgroup.procs".as_ptr(),
+                    libc::O_WRONLY | libc::O_CLOEXEC,
+                )
+            };
+            if procs < 0 {
+                bail!(
+                    "unable to bind supervisor to cgroup: {}",
+                    Error::last_os_error()
+                );
+            }
+            let mut procs = unsafe { fs::File::from_raw_fd(procs) };
+            procs
+                .write_all(pid.to_string().as_bytes())
+                .map_err(|e| anyhow!("unable to bind supervisor to cgroup: {e}"))?;
+
+            Ok(unsafe { fork() }?)
+        }
+        Err(e) => bail!("failed to spawn workload into cgroup: {e}"),
+    }
+}
+
+/// Fork, then wait for the child in the parent and exit with its status.
+/// Only the child returns. With `parent_death`, the child is killed if the
+/// parent dies, and gets the watch it needs to re-arm that after changing
+/// its credentials.
+fn fork_and_wait(
+    cgroup: Option<&OwnedFd>,
+    parent_death: bool,
+) -> Result<Option<signal::ParentWatch>> {
     if let Err(e) = unsafe { signal::setup_parent_signal_handlers() } {
         warn!("unable to set up parent signal handlers: {e}");
         process::exit(1)
     }
 
-    match unsafe { fork() }? {
+    let parent_watch = parent_death.then(signal::ParentWatch::new);
+    match fork_workload(cgroup)? {
         ForkResult::Parent { child } => {
             signal::store_child_pid(child.as_raw());
             debug!("child pid = {}", child.as_raw());
@@ -96,7 +203,11 @@ fn fork_and_wait() -> Result<()> {
             reap_children()?;
             process::exit(exitcode);
         }
-        ForkResult::Child => {}
+        ForkResult::Child => {
+            if let Some(watch) = &parent_watch {
+                watch.arm(Signal::SIGKILL)?;
+            }
+        }
     }
 
     if let Err(e) = unsafe { signal::reset_child_signal_handlers() } {
@@ -104,7 +215,7 @@ fn fork_and_wait() -> Result<()> {
         process::exit(1);
     }
 
-    Ok(())
+    Ok(parent_watch)
 }
 
 /// Find the first child PID of the given parent process.
@@ -147,19 +258,6 @@ fn first_child_pid_of(parent: libc::pid_t) -> Result<libc::pid_t> {
     Err(anyhow!("failed to find child PID of {parent}"))
 }
 
-fn render_uidgid_mappings(mappings: &[IdMapping]) -> String {
-    mappings
-        .iter()
-        .map(|mapping| {
-            format!(
-                "{} {} {}",
-                mapping.base_nsid, mapping.base_hostid, mapping.remap_count
-            )
-        })
-        .collect::<Vec<String>>()
-        .join("\n")
-}
-
 impl CreateRequest {
     fn get_boottime(&self) -> i64 {
         unsafe {
@@ -172,41 +270,158 @@ impl CreateRequest {
         }
     }
 
-    fn update_boottime(&self) -> Result<()> {
-        let boot_time = self.get_boottime() - 1;
-        let boot_time = if boot_time <= 0 {
-            "0".to_string()
-        } else {
-            format!("-{boot_time}")
-        };
-        let timecfg = format!("boottime {boot_time} 0\n");
-        fs::write("/proc/self/timens_offsets", timecfg.as_bytes())?;
+    /// Render the offsets written to `/proc/self/timens_offsets`, given the
+    /// host's boot time in seconds.
+    fn render_time_offsets(&self, host_boottime: i64) -> Result<String> {
+        match self.time_offsets.clone().unwrap_or_default() {
+            TimeOffsets::ResetBoottime => {
+                let boot_time = host_boottime - 1;
+                let boot_time = if boot_time <= 0 {
+                    "0".to_string()
+                } else {
+                    format!("-{boot_time}")
+                };
+                Ok(format!("boottime {boot_time} 0\n"))
+            }
+            TimeOffsets::PreserveHost => Ok(String::new()),
+            TimeOffsets::Explicit {
+                monotonic,
+                boottime,
+            } => {
+                let mut timecfg = String::new();
+                for (clock, offset) in [("monotonic", monotonic), ("boottime", boottime)] {
+                    let Some(offset) = offset else {
+                        continue;
+                    };
+                    timecfg.push_str(&format!("{clock} {} {}\n", offset.secs, offset.nanos));
+                }
+                Ok(timecfg)
+            }
+        }
+    }
+
+    fn update_time_offsets(&self) -> Result<()> {
+        let timecfg = self.render_time_offsets(self.get_boottime())?;
+        if !timecfg.is_empty() {
+            fs::write("/proc/self/timens_offsets", timecfg.as_bytes())?;
+        }
         Ok(())
     }
 
-    fn prepare_userns(&self, pid: libc::pid_t) -> Result<()> {
+    /// Write the id mappings of the child `pid`, looked up in `proc`, the
+    /// supervisor's own /proc. The child may have pivoted its root, which is
+    /// also ours, and its new /proc may show another pid namespace.
+    fn prepare_userns(&self, proc: &OwnedFd, pid: libc::pid_t) -> Result<()> {
         if let Some(uid_mappings) = &self.uid_mappings {
-            fs::write(
-                format!("/proc/{pid}/uid_map"),
-                render_uidgid_mappings(uid_mappings),
-            )?;
+            write_proc_file(proc, pid, "uid_map", &render_mappings(uid_mappings))?;
         }
 
         let sgd = self.setgroups_deny.unwrap_or(true);
         if sgd {
-            fs::write(format!("/proc/{pid}/setgroups"), "deny".as_bytes())?;
+            write_proc_file(proc, pid, "setgroups", "deny")?;
         }
 
         if let Some(gid_mappings) = &self.gid_mappings {
-            fs::write(
-                format!("/proc/{pid}/gid_map"),
-                render_uidgid_mappings(gid_mappings),
-            )?;
+            write_proc_file(proc, pid, "gid_map", &render_mappings(gid_mappings))?;
+        }
+
+        Ok(())
+    }
+
+    fn target_namespaces(&self) -> Vec<Namespace> {
+        self.namespaces.clone().unwrap_or(vec![
+            Namespace::Mount,
+            Namespace::Time,
+            Namespace::Uts,
+            Namespace::Pid,
+            Namespace::Ipc,
+            Namespace::User,
+        ])
+    }
+
+    fn joined_namespaces(&self) -> Vec<Namespace> {
+        self.join_namespaces
+            .iter()
+            .flatten()
+            .map(|join| join.namespace)
+            .collect()
+    }
+
+    /// Check that joined namespaces don't conflict with the ones created.
+    fn check_joined_namespaces(&self, target_ns: &[Namespace]) -> Result<()> {
+        let joined = self.joined_namespaces();
+        for (i, namespace) in joined.iter().enumerate() {
+            if target_ns.contains(namespace) {
+                bail!("the {namespace:?} namespace can't be both created and joined");
+            }
+            if joined[..i].contains(namespace) {
+                bail!("the {namespace:?} namespace is joined more than once");
+            }
+        }
+
+        // The rootfs is always set up in a mount namespace of its own.
+        if joined.contains(&Namespace::Mount) {
+            bail!("joining an existing mount namespace is not supported");
+        }
+
+        // Pinning a namespace which is neither created nor joined would pin
+        // the host's.
+        for pin in self.pin_namespaces.iter().flatten() {
+            if !target_ns.contains(&pin.namespace) && !joined.contains(&pin.namespace) {
+                bail!(
+                    "the {:?} namespace can't be pinned, as it is neither created nor joined",
+                    pin.namespace
+                );
+            }
+        }
+
+        // A joined user namespace already has its mappings.
+        if joined.contains(&Namespace::User)
+            && (self.uid_mappings.is_some()
+                || self.gid_mappings.is_some()
+                || self.id_map_helpers.unwrap_or(false))
+        {
+            bail!("uid/gid mappings can't be applied to a joined user namespace");
         }
 
         Ok(())
     }
 
+    /// How container ids are translated when mutating the rootfs. In the
+    /// two-stage path, the rootfs is mutated before the user namespace is
+    /// entered.
+    fn mutation_ids(&self) -> IdTranslator {
+        if self.skip_two_stage_userns.unwrap_or(false)
+            || !self.target_namespaces().contains(&Namespace::User)
+        {
+            return IdTranslator::default();
+        }
+
+        IdTranslator::new(
+            self.uid_mappings.as_deref().unwrap_or_default(),
+            self.gid_mappings.as_deref().unwrap_or_default(),
+        )
+    }
+
+    /// Resolve an idmap which refers to the container's own mappings.
+    fn resolve_idmap(&self, idmap: &IdMap) -> Result<IdMap> {
+        match idmap {
+            IdMap::Container => {
+                let (Some(uid_mappings), Some(gid_mappings)) =
+                    (&self.uid_mappings, &self.gid_mappings)
+                else {
+                    bail!("idmap uses the container's mappings, but uid/gid mappings are not set");
+                };
+
+                Ok(IdMap::Mappings {
+                    uid_mappings: uid_mappings.clone(),
+                    gid_mappings: gid_mappings.clone(),
+                })
+            }
+            mappings => Ok(mappings.clone()),
+        }
+    }
+
     fn identity(&self) -> Result<String> {
         let pid = process::id();
 
@@ -219,12 +434,48 @@ impl CreateRequest {
         }
     }
 
+    fn final_hostname(&self) -> Result<String> {
+        match &self.hostname {
+            Some(hostname) => Ok(hostname.to_string()),
+            None => Ok(format!("styrolite-{}", self.identity()?)),
+        }
+    }
+
+    /// Render the `/etc` files which are generated for this workload.
+    fn etc_files(&self) -> Result<Vec<(&'static str, String)>> {
+        let mut files = Vec::new();
+
+        if self.hostname_file.unwrap_or(false) {
+            files.push(("hostname", etc::render_hostname(&self.final_hostname()?)?));
+        }
+
+        if let Some(entries) = &self.hosts {
+            files.push((
+                "hosts",
+                etc::render_hosts(&self.final_hostname()?, entries)?,
+            ));
+        }
+
+        if let Some(dns) = &self.dns {
+            let host =
+                if dns.copy_from_host {
+                    Some(fs::read_to_string(etc::HOST_RESOLV_CONF).map_err(|e| {
+                        anyhow!("unable to read host {}: {e}", etc::HOST_RESOLV_CONF)
+                    })?)
+                } else {
+                    None
+                };
+            files.push((
+                "resolv.conf",
+                etc::render_resolv_conf(dns, host.as_deref())?,
+            ));
+        }
+
+        Ok(files)
+    }
+
     fn update_hostname(&self) -> Result<()> {
-        let wid = self.identity()?;
-        let final_hostname = match &self.hostname {
-            Some(hostname) => hostname.to_string(),
-            None => format!("styrolite-{wid}"),
-        };
+        let final_hostname = self.final_hostname()?;
         let final_hostname_cstr = CString::new(final_hostname.clone()).map_err(|e| {
             anyhow!("hostname '{final_hostname}' contains an interior NUL byte: {e}")
         })?;
@@ -242,27 +493,39 @@ impl CreateRequest {
         }
     }
 
-    fn prepare_cgroup(&self) -> Result<()> {
-        // If we haven't been given a cgroup OR limits, nothing to do here.
-        if self.limits.is_none() && self.cgroupfs.is_none() {
+    /// Prepare the cgroup the workload should be spawned into, if any.
+    fn prepare_cgroup(&self) -> Result<Option<CGroup>> {
+        let driver = self.cgroup_driver.unwrap_or_default();
+
+        // If we haven't been given a cgroup, a cgroup driver, limits OR a
+        // device policy, nothing to do here.
+        if self.limits.is_none()
+            && self.cgroupfs.is_none()
+            && self.devices.is_none()
+            && driver == CGroupDriver::Cgroupfs
+        {
             debug!("skipping prepare_cgroup");
-            return Ok(());
+            return Ok(None);
         }
 
         debug!(
-            "prepare_cgroup - limits: {:?} cgroupfs: {:?}",
+            "prepare_cgroup - limits: {:?} cgroupfs: {:?} driver: {driver:?}",
             self.limits, self.cgroupfs
         );
-        let pid = process::id();
-        let cgbase = self
-            .cgroupfs
-            .clone()
-            .unwrap_or("/sys/fs/cgroup".to_string());
-        let cgroot = CGroup::open(&cgbase)?;
+        let cgroot = if driver == CGroupDriver::Cgroupfs {
+            let cgbase = self
+                .cgroupfs
+                .clone()
+                .unwrap_or("/sys/fs/cgroup".to_string());
+            CGroup::open(&cgbase)?
+        } else {
+            systemd::create_scope(driver, &self.identity()?, self.limits.as_ref())?
+        };
 
-        if let Some(limits) = self.limits.clone() {
-            // if we have been given limits and a cgroup, create a subtree cgroup,
-            // set limits on it, and move ourselves into it.
+        if self.limits.is_some() || self.devices.is_some() {
+            // if we have been given limits or a device policy, create a subtree
+            // cgroup and set limits on it for the workload to be spawned into.
+            let limits = self.limits.clone().unwrap_or_default();
 
             // Ensure the correct controllers are enabled for limits we want to set
             // in our subtree, and attempt to enable them if not.
@@ -312,51 +575,82 @@ impl CreateRequest {
                     }
                 })
                 .collect();
-            debug!(
-                "binding supervisor (pid {pid}) to subtree cgroup: {:?}",
-                subtree
-            );
-            subtree
-                .clone()
-                .set_child_value("cgroup.procs", &format!("{pid}"))?;
+            debug!("workload will be spawned into subtree cgroup: {subtree:?}");
+            Ok(Some(subtree))
         } else {
-            // if we have been given a cgroup and *no* limits, just make sure we
-            // move ourselves into it.
-            debug!("binding supervisor (pid {pid}) to cgroup: {:?}", cgroot);
-            cgroot.set_child_value("cgroup.procs", &format!("{pid}"))?;
+            // if we have been given a cgroup and *no* limits, just make sure the
+            // workload is spawned into it.
+            debug!("workload will be spawned into cgroup: {cgroot:?}");
+            Ok(Some(cgroot))
         }
+    }
 
-        Ok(())
+    /// Assemble the overlay rootfs at `target`. An ephemeral upper layer
+    /// lives on its own tmpfs under `stage_base`, which disappears along with
+    /// the container's mount namespace.
+    fn mount_overlay_rootfs(
+        &self,
+        overlay: &OverlayRootfs,
+        stage_base: &str,
+        target: &str,
+    ) -> Result<()> {
+        let upper = match &overlay.upper {
+            None => None,
+            Some(OverlayUpper::Directory { upper, work }) => Some((upper.clone(), work.clone())),
+            Some(OverlayUpper::Ephemeral { size }) => {
+                let ephemeral = format!("{stage_base}/ephemeral");
+                let ephemeral_tmpfs = MountSpec {
+                    source: Some("tmpfs".to_string()),
+                    target: ephemeral.clone(),
+                    fstype: Some("tmpfs".to_string()),
+                    create_mountpoint: true,
+                    data: size.as_ref().map(|size| format!("size={size}")),
+                    ..Default::default()
+                };
+                ephemeral_tmpfs
+                    .mount()
+                    .map_err(|e| anyhow!("failed to mount ephemeral overlay tmpfs: {e}"))?;
+
+                let (upper, work) = (format!("{ephemeral}/upper"), format!("{ephemeral}/work"));
+                fs::create_dir_all(&upper)?;
+                fs::create_dir_all(&work)?;
+                Some((upper, work))
+            }
+        };
+
+        crate::mount::mount_overlay(
+            target,
+            &overlay.lower,
+            upper.as_ref().map(|(u, w)| (u.as_str(), w.as_str())),
+        )
+        .map_err(|e| anyhow!("failed to mount overlay rootfs: {e}"))
     }
 
     fn pivot_fs(&self) -> Result<()> {
         debug!("early mount!");
 
-        let mut rootfs = self
-            .rootfs
-            .clone()
-            .ok_or_else(|| anyhow!("expected rootfs to be configured"))?;
-
         let rootfs_readonly = self.rootfs_readonly.unwrap_or(false);
 
-        // Unshare rootfs mount so we can later pivot to a new rootfs.
-        // The unshared root mount will be cleaned up once the new rootfs is
-        // in place.
-        let oldroot = MountSpec {
-            source: None,
-            target: "/".to_string(),
-            fstype: None,
-            bind: false,
-            recurse: true,
-            unshare: true,
-            safe: false,
-            create_mountpoint: false,
-            read_only: false,
-            data: None,
+        // Detach the whole mount tree from the host's peer groups so we can
+        // later pivot to a new rootfs, and so that the staging mounts below
+        // don't propagate back to the host. pivot_root(2) refuses shared
+        // mounts, and a non-recursive type would leave submounts like /tmp
+        // shared, so the requested rootfs propagation is only applied once
+        // the pivot is done. The old root mount will be cleaned up once the
+        // new rootfs is in place.
+        let rootfs_propagation = self.rootfs_propagation.unwrap_or(Propagation::RPrivate);
+        let staging_propagation = match rootfs_propagation {
+            Propagation::Shared
+            | Propagation::RShared
+            | Propagation::Slave
+            | Propagation::RSlave => Propagation::RSlave,
+            Propagation::Private
+            | Propagation::RPrivate
+            | Propagation::Unbindable
+            | Propagation::RUnbindable => Propagation::RPrivate,
         };
 
-        oldroot
-            .mount()
+        crate::mount::set_propagation("/", staging_propagation)
             .map_err(|e| anyhow!("failed to unshare / in new mount namespace: {e}"))?;
 
         // If we want to clone the VFS root, e.g. for styrojail,
@@ -365,19 +659,27 @@ impl CreateRequest {
         let stage_root = format!("/tmp/styrolite-stage-{}/root", self.identity()?);
         let stage_old = format!("/tmp/styrolite-stage-{}/old", self.identity()?);
 
-        if rootfs == "/" {
+        // An overlay rootfs is assembled in the staging area.
+        let mut rootfs = match (&self.rootfs, &self.rootfs_overlay) {
+            (Some(rootfs), None) => rootfs.clone(),
+            (None, Some(_)) => stage_root.clone(),
+            (Some(_), Some(_)) => bail!("rootfs and rootfs_overlay are mutually exclusive"),
+            (None, None) => bail!("expected rootfs or rootfs_overlay to be configured"),
+        };
+
+        // Generated /etc files are also rendered into the staging area, which
+        // must then be a tmpfs so they don't end up on the host's /tmp.
+        let etc_files = self.etc_files()?;
+
+        if rootfs == "/" || self.rootfs_overlay.is_some() || !etc_files.is_empty() {
             // Mount a tmpfs staging area so we can pivot into a non-"/" mountpoint.
             let stage_tmpfs = MountSpec {
                 source: Some("tmpfs".to_string()),
-                target: stage_base,
+                target: stage_base.clone(),
                 fstype: Some("tmpfs".to_string()),
-                bind: false,
-                recurse: false,
-                unshare: false,
                 safe: true,
                 create_mountpoint: true,
-                read_only: false,
-                data: None,
+                ..Default::default()
             };
             stage_tmpfs
                 .mount()
@@ -387,18 +689,16 @@ impl CreateRequest {
                 .map_err(|e| anyhow!("failed to create staging root dir: {e}"))?;
             fs::create_dir_all(&stage_old)
                 .map_err(|e| anyhow!("failed to create staging old dir: {e}"))?;
+        }
 
+        if rootfs == "/" {
             let stage_bind = MountSpec {
                 source: Some("/".to_string()),
                 target: stage_root.clone(),
                 fstype: Some("none".to_string()),
                 bind: true,
                 recurse: true,
-                unshare: false,
-                safe: false,
-                create_mountpoint: false,
-                read_only: false,
-                data: None,
+                ..Default::default()
             };
             stage_bind
                 .mount()
@@ -407,6 +707,10 @@ impl CreateRequest {
             rootfs = stage_root.to_string();
         }
 
+        if let Some(overlay) = &self.rootfs_overlay {
+            self.mount_overlay_rootfs(overlay, &stage_base, &stage_root)?;
+        }
+
         // Now mount the new rootfs.
         let newroot = MountSpec {
             source: Some(rootfs.clone()),
@@ -414,11 +718,12 @@ impl CreateRequest {
             fstype: Some("none".to_string()),
             bind: true,
             recurse: true,
-            unshare: false,
-            safe: false,
-            create_mountpoint: false,
-            read_only: false,
-            data: None,
+            idmap: self
+                .rootfs_idmap
+                .as_ref()
+                .map(|idmap| self.resolve_idmap(idmap))
+                .transpose()?,
+            ..Default::default()
         };
 
         newroot
@@ -431,30 +736,39 @@ impl CreateRequest {
                 .map_err(|e| anyhow!("failed to make new rootfs readonly: {e}"))?;
         }
 
+        // From here on, paths inside the new rootfs are resolved without
+        // following its symlinks out of it.
+        let root = Rootfs::open(&rootfs)?;
+
         // Mount /proc.
         let procfs = MountSpec {
             source: Some("proc".to_string()),
-            target: format!("{rootfs}/proc"),
+            target: "/proc".to_string(),
             fstype: Some("proc".to_string()),
-            bind: false,
             recurse: true,
-            unshare: false,
             safe: true,
-            create_mountpoint: false,
-            read_only: false,
-            data: None,
+            ..Default::default()
         };
 
         procfs
-            .mount()
+            .mount_in(&root)
             .map_err(|e| anyhow!("failed to mount /proc: {e}"))?;
 
+        if self.standard_filesystems.unwrap_or(false) {
+            let ipc = self.target_namespaces().contains(&Namespace::Ipc);
+            crate::mount::mount_standard_filesystems(&root, ipc)
+                .map_err(|e| anyhow!("failed to mount standard filesystems: {e}"))?;
+        }
+
+        // Generated /etc files go below the mount specifications, so a
+        // mount of e.g. /etc/resolv.conf still takes precedence.
+        etc::mount_etc_files(&root, &format!("{stage_base}/etc"), &etc_files)?;
+
         if let Some(mounts) = &self.mounts {
             for mount in mounts {
-                let parented_target = format!("{}/{}", rootfs, mount.target);
-                let parented_mount = MountSpec {
+                let resolved_mount = MountSpec {
                     source: mount.source.clone(),
-                    target: parented_target.clone(),
+                    target: mount.target.clone(),
                     fstype: mount.fstype.clone(),
                     bind: mount.bind,
                     recurse: mount.recurse,
@@ -462,23 +776,28 @@ impl CreateRequest {
                     safe: mount.safe,
                     create_mountpoint: mount.create_mountpoint,
                     read_only: mount.read_only,
-                    data: None,
+                    data: mount.data.clone(),
+                    idmap: mount
+                        .idmap
+                        .as_ref()
+                        .map(|idmap| self.resolve_idmap(idmap))
+                        .transpose()?,
+                    propagation: mount.propagation,
+                    options: mount.options.clone(),
                 };
 
-                parented_mount
-                    .mount()
-                    .map_err(|e| anyhow!("failed to process mount spec {parented_target}: {e}"))?;
+                resolved_mount
+                    .mount_in(&root)
+                    .map_err(|e| anyhow!("failed to process mount spec {}: {e}", mount.target))?;
             }
         }
 
         if let Some(mutations) = &self.mutations {
+            let ids = self.mutation_ids();
             for mutation in mutations {
-                match mutation {
-                    Mutation::CreateDir(cdm) => {
-                        cdm.mutate(&rootfs)
-                            .map_err(|e| anyhow!("failed to create directory: {e}"))?;
-                    }
-                };
+                mutation
+                    .mutate(&root, &ids)
+                    .map_err(|e| anyhow!("failed to mutate rootfs: {e}"))?;
             }
         }
 
@@ -488,13 +807,13 @@ impl CreateRequest {
         // under the new rootfs; missing ones are skipped.
         if let Some(masked) = &self.masked_paths {
             for path in masked {
-                crate::mount::mask_path(&rootfs, path)
+                crate::mount::mask_path(&root, path)
                     .map_err(|e| anyhow!("failed to mask {path}: {e}"))?;
             }
         }
         if let Some(readonly) = &self.readonly_paths {
             for path in readonly {
-                crate::mount::make_readonly(&rootfs, path)
+                crate::mount::make_readonly(&root, path)
                     .map_err(|e| anyhow!("failed to make {path} read-only: {e}"))?;
             }
         }
@@ -503,6 +822,11 @@ impl CreateRequest {
             .pivot()
             .map_err(|e| anyhow!("failed to pivot to new rootfs: {e}"))?;
 
+        if rootfs_propagation != staging_propagation {
+            crate::mount::set_propagation("/", rootfs_propagation)
+                .map_err(|e| anyhow!("failed to set propagation of new rootfs: {e}"))?;
+        }
+
         Ok(())
     }
 }
@@ -520,14 +844,11 @@ impl Wrappable for CreateRequest {
     fn wrap(&self) -> Result<()> {
         debug!("executing with config {self:?}");
 
-        let target_ns = self.namespaces.clone().unwrap_or(vec![
-            Namespace::Mount,
-            Namespace::Time,
-            Namespace::Uts,
-            Namespace::Pid,
-            Namespace::Ipc,
-            Namespace::User,
-        ]);
+        if let Some(sig) = self.parent_death_signal()? {
+            signal::set_parent_death_signal(sig)?;
+        }
+
+        let target_ns = self.target_namespaces();
 
         debug!("namespaces: {target_ns:?}");
 
@@ -535,10 +856,32 @@ impl Wrappable for CreateRequest {
             "maybe create a new supervisor cgroup for workload identity {}",
             self.identity()?
         );
-        if let Err(e) = self.prepare_cgroup() {
+        let cgroup = self.prepare_cgroup().unwrap_or_else(|e| {
             warn!("unable to prepare cgroup: {e}");
+            None
+        });
+
+        if let Some(devices) = &self.devices {
+            // Unlike resource limits, a device policy that cannot be enforced
+            // must not be silently dropped.
+            let Some(cgroup) = &cgroup else {
+                bail!("a device policy was requested, but no cgroup could be prepared");
+            };
+
+            debug!("applying device policy to cgroup {cgroup:?}");
+            crate::devices::apply(&cgroup.open_fd()?, devices)?;
         }
 
+        // Like the limits, spawning into a cgroup is best effort here.
+        let cgroup = cgroup.as_ref().and_then(|cgroup| {
+            open_workload_cgroup(cgroup)
+                .inspect_err(|e| warn!("{e}"))
+                .ok()
+        });
+
+        self.check_joined_namespaces(&target_ns)?;
+        let joined_ns = self.joined_namespaces();
+
         let skip_two_stage_userns = self.skip_two_stage_userns.unwrap_or(false);
 
         let first_level_ns = if !skip_two_stage_userns {
@@ -551,17 +894,118 @@ impl Wrappable for CreateRequest {
             target_ns.clone()
         };
 
+        if let Some(sysctls) = &self.sysctls {
+            sysctl::validate(sysctls, &target_ns)?;
+        }
+
+        if let Some(offsets) = &self.time_offsets {
+            if !target_ns.contains(&Namespace::Time) {
+                bail!("time offsets require a new time namespace");
+            }
+            offsets.check()?;
+        }
+
+        if let Some(network) = &self.network {
+            if !target_ns.contains(&Namespace::Net) {
+                bail!("a network configuration requires a network namespace");
+            }
+            network.validate()?;
+        }
+
+        let slirp_config = self.network.as_ref().and_then(|n| n.slirp.as_ref());
+        #[cfg(not(feature = "slirp"))]
+        if slirp_config.is_some() {
+            bail!("user-mode networking requires the slirp feature");
+        }
+
+        // The id map helper must be forked while we still have the host's
+        // view of the filesystem and are still in the host user namespace.
+        let idmap_helper =
+            if self.id_map_helpers.unwrap_or(false) && target_ns.contains(&Namespace::User) {
+                debug!("spawning id map helper");
+                Some(IdMapHelper::spawn(
+                    self.uid_mappings.as_deref(),
+                    self.gid_mappings.as_deref(),
+                )?)
+            } else {
+                None
+            };
+
+        // Pins are bind mounts in the host's mount namespace, so they are
+        // made by a helper which stays there.
+        let pin_helper = match &self.pin_namespaces {
+            Some(pins) if !pins.is_empty() => {
+                debug!("spawning namespace pin helper");
+                Some(PinHelper::spawn(pins)?)
+            }
+            _ => None,
+        };
+
+        // A netlink socket stays bound to the network namespace it was opened
+        // in, so the host end of a veth pair is configured through a socket
+        // opened before unsharing.
+        let host_netlink = if self.network.as_ref().is_some_and(|n| n.veth.is_some()) {
+            Some(Netlink::open()?)
+        } else {
+            None
+        };
+
+        // Likewise, the user-mode network's host sockets are opened by a
+        // helper which stays in the host's network namespace.
+        #[cfg(feature = "slirp")]
+        let slirp_helper = match slirp_config {
+            Some(slirp) => {
+                debug!("spawning slirp helper");
+                Some(SlirpHelper::spawn(slirp)?)
+            }
+            None => None,
+        };
+
+        // Joined namespaces are entered before any are created, so that a
+        // joined user namespace owns the new ones, and a new user namespace
+        // does not prevent joining namespaces owned by the host's.
+        if let Some(joins) = &self.join_namespaces {
+            debug!("joining existing namespaces");
+            unshare::join(joins)?;
+        }
+
         debug!("unsharing namespaces");
         unshare(&first_level_ns)?;
 
-        debug!("update boot time");
-        if self.update_boottime().is_err() {
-            warn!("unable to update boot time");
+        let workload_netns = if target_ns.contains(&Namespace::Net) {
+            Some(WorkloadNetns::open(host_netlink)?)
+        } else {
+            None
+        };
+
+        #[cfg(feature = "slirp")]
+        if let Some(helper) = &slirp_helper {
+            helper.attach()?;
+        }
+
+        // The clocks and hostname of joined namespaces are left alone.
+        if target_ns.contains(&Namespace::Time) {
+            debug!("update time offsets");
+            match self.update_time_offsets() {
+                Ok(()) => {}
+                Err(e) if self.time_offsets.is_some() => {
+                    bail!("unable to set time offsets: {e}")
+                }
+                Err(_) => warn!("unable to update boot time"),
+            }
+        }
+
+        if !joined_ns.contains(&Namespace::Uts) {
+            debug!("setting hostname");
+            if self.update_hostname().is_err() {
+                warn!("unable to set hostname");
+            }
         }
 
-        debug!("setting hostname");
-        if self.update_hostname().is_err() {
-            warn!("unable to set hostname");
+        // Namespaced sysctls show the copy of the namespaces just unshared.
+        if let Some(sysctls) = &self.sysctls {
+            debug!("setting sysctls");
+            sysctl::apply(sysctls)?;
         }
 
         debug!("setting process limits");
@@ -578,41 +1022,116 @@ impl Wrappable for CreateRequest {
         debug!("all namespaces unshared -- forking child");
         let parent_efd = EventFd::from_value_and_flags(0, EfdFlags::EFD_SEMAPHORE)?;
         let child_efd = EventFd::from_value_and_flags(0, EfdFlags::EFD_SEMAPHORE)?;
-        match unsafe { fork() }? {
+        // In the two-stage path, the child pivots its root, and so ours,
+        // before the supervisor writes its id mappings. So /proc is opened
+        // now, while it is still the one showing the child's host pid.
+        let host_proc = if target_ns.contains(&Namespace::User) {
+            let proc = fs::File::open("/proc").map_err(|e| anyhow!("unable to open /proc: {e}"))?;
+            Some(OwnedFd::from(proc))
+        } else {
+            None
+        };
+        // fork(2) never passes the supervisor's parent death signal on, so
+        // the child arms its own before it can block on the supervisor.
+        let parent_watch = self
+            .parent_death_signal
+            .is_some()
+            .then(signal::ParentWatch::new);
+        match fork_workload(cgroup.as_ref())? {
             ForkResult::Parent { child } => {
                 signal::store_child_pid(child.as_raw());
 
                 debug!("child pid = {}", child.as_raw());
-                parent_efd.read()?;
-
-                if target_ns.contains(&Namespace::User) {
-                    debug!("child has dropped into its own userns, configuring from supervisor");
-                    // In the two-stage path, the child calls pivot_fs() before signaling.
-                    // pivot_root() changes /proc for the parent too.
-                    // If a PID namespace was created, the new /proc shows the child as PID 1
-                    // (not its host PID), so we must use 1 to find it in /proc.
-                    // Without a PID namespace, the new proc mount still shows global PIDs.
-                    let userns_pid =
-                        if !skip_two_stage_userns && target_ns.contains(&Namespace::Pid) {
-                            1
+
+                // The child blocks until it is told to go ahead, so if the
+                // supervisor can't configure it, it must be killed before
+                // giving up.
+                let configured = (|| -> Result<_> {
+                    parent_efd.read()?;
+
+                    if let Some(proc) = &host_proc {
+                        debug!(
+                            "child has dropped into its own userns, configuring from supervisor"
+                        );
+                        if let Some(helper) = idmap_helper {
+                            // newgidmap(1) decides whether setgroups(2) stays
+                            // allowed, so only deny it up front when asked to.
+                            if self.setgroups_deny == Some(true) {
+                                write_proc_file(proc, child.as_raw(), "setgroups", "deny")?;
+                            }
+
+                            // The helper lives in the host pid namespace.
+                            helper.map(child.as_raw())?;
                         } else {
-                            child.as_raw()
-                        };
-                    self.prepare_userns(userns_pid)?;
-                }
+                            self.prepare_userns(proc, child.as_raw())?;
+                        }
+                    }
+
+                    if let Some(helper) = pin_helper {
+                        debug!("pinning workload namespaces");
+                        helper.pin(child.as_raw())?;
+                    }
+
+                    // The supervisor shares the workload's network namespace, so
+                    // it can configure it from here.
+                    let network = if let Some(netns) = workload_netns {
+                        debug!("configuring workload network");
+                        let config = self.network.clone().unwrap_or_default();
+                        let host_name = format!("veth{}", child.as_raw());
+                        match netns.setup(&config, &host_name) {
+                            Ok(network) => Some(network),
+                            Err(e) if self.network.is_none() => {
+                                warn!("unable to set up workload network: {e}");
+                                None
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    } else {
+                        None
+                    };
+
+                    Ok(network)
+                })();
+                let network = match configured {
+                    Ok(network) => network,
+                    Err(e) => {
+                        kill_workload(child);
+                        return Err(e);
+                    }
+                };
 
                 // The supervisor has now configured the user namespace, so let the first process run.
-                child_efd.write(1)?;
+                if let Err(e) = child_efd.write(1) {
+                    kill_workload(child);
+                    return Err(e.into());
+                }
 
                 let exitcode = wait_for_pid(child.as_raw())?;
                 debug!("[pid {}] exitcode = {exitcode}", child.as_raw());
 
+                if let Some(network) = network
+                    && let Err(e) = network.teardown()
+                {
+                    warn!("unable to tear down workload network: {e}");
+                }
+
+                #[cfg(feature = "slirp")]
+                if let Some(helper) = slirp_helper
+                    && let Err(e) = helper.stop()
+                {
+                    warn!("unable to stop slirp helper: {e}");
+                }
+
                 debug!("reaping children of supervisor!");
                 reap_children()?;
 
                 process::exit(exitcode);
             }
-            ForkResult::Child => {}
+            ForkResult::Child => {
+                if let Some(watch) = &parent_watch {
+                    watch.arm(Signal::SIGKILL)?;
+                }
+            }
         }
 
         if let Err(e) = unsafe { signal::reset_child_signal_handlers() } {
@@ -647,6 +1166,12 @@ impl Wrappable for CreateRequest {
         // for this container.
         child_efd.read()?;
 
+        // Entering the user namespace may have changed our credentials,
+        // which clears the parent death signal.
+        if let Some(watch) = &parent_watch {
+            watch.arm(Signal::SIGKILL)?;
+        }
+
         if skip_two_stage_userns {
             // In two-stage mode, mounts are deferred until after
             // UID/GID namespace has been configured by the supervisor.
@@ -667,19 +1192,52 @@ impl Wrappable for CreateRequest {
             fs::write("/proc/self/oom_score_adj", score.to_string())?;
         }
 
+        self.exec.set_scheduling()?;
+
+        let exec = self.exec.resolve_user()?;
+
         // Bind the workload's terminal over /dev/console and hand the
         // workload uid ownership of it. We must do this here, after we have moved into
         // the mount/userns, but before we drop CAP_SYS_ADMIN/CAP_CHOWN.
-        setup_console(self.exec.uid)?;
+        setup_console(exec.uid)?;
 
-        preexec_prep(&self.exec, self.capabilities.as_ref())?;
+        preexec_prep(&exec, self.capabilities.as_ref())?;
+
+        // Changing credentials cleared the parent death signal again.
+        if let Some(watch) = &parent_watch {
+            watch.arm(Signal::SIGKILL)?;
+        }
 
         debug!("ready to launch workload");
-        self.exec.execute()
+        exec.execute()
     }
 }
 
 impl ExecutableSpec {
+    /// Resolve `user` against the passwd and group files of the current root,
+    /// filling in the ids and `HOME` which are not set explicitly.
+    fn resolve_user(&self) -> Result<Cow<'_, ExecutableSpec>> {
+        let Some(spec) = &self.user else {
+            return Ok(Cow::Borrowed(self));
+        };
+        let user = user::resolve_in_root(spec)?;
+        debug!("resolved user {spec:?} to {user:?}");
+        Ok(Cow::Owned(self.with_user(user)))
+    }
+
+    /// Fill in the ids and `HOME` which are not set explicitly from `user`.
+    fn with_user(&self, user: ResolvedUser) -> ExecutableSpec {
+        let mut exec = self.clone();
+        exec.uid.get_or_insert(user.uid);
+        exec.gid.get_or_insert(user.gid);
+        exec.supplemental_gids.get_or_insert(user.supplemental_gids);
+        exec.environment
+            .get_or_insert_with(BTreeMap::new)
+            .entry("HOME".to_string())
+            .or_insert(user.home);
+        exec
+    }
+
     fn execute(&self) -> Result<()> {
         let executable = self
             .executable
@@ -714,6 +1272,14 @@ impl ExecutableSpec {
             env::set_current_dir(wd.clone())?;
         }
 
+        if let Some(mask) = self.umask {
+            unsafe { libc::umask(mask as libc::mode_t) };
+        }
+
+        if let Some(personality) = &self.personality {
+            personality.apply()?;
+        }
+
         if self.no_new_privs {
             self.set_no_new_privs()?;
         }
@@ -773,6 +1339,23 @@ impl ExecutableSpec {
         }
     }
 
+    /// Apply the scheduling policy, CPU affinity and I/O priority, while
+    /// the capabilities some of them need are still held.
+    fn set_scheduling(&self) -> Result<()> {
+        // SCHED_DEADLINE tasks can't change their affinity, so it is set
+        // first.
+        if let Some(cpus) = &self.cpu_affinity {
+            sched::set_cpu_affinity(cpus)?;
+        }
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.apply()?;
+        }
+        if let Some(io_priority) = &self.io_priority {
+            io_priority.apply()?;
+        }
+        Ok(())
+    }
+
     fn set_process_limits(&self) -> Result<()> {
         if self.process_limits.is_none() {
             return Ok(());
@@ -831,32 +1414,30 @@ impl AttachRequest {
         }
     }
 
-    fn attach_cgroup(&self) -> Result<()> {
-        let pid = process::id();
-        let cgbase = self
-            .cgroupfs
-            .clone()
-            .unwrap_or("/sys/fs/cgroup".to_string());
-        let name = format!("styrolite-{}", self.identity()?);
-
-        let mut path = PathBuf::from(&cgbase);
-        path.push(&name);
+    /// Find the cgroup of the workload being attached to, if any. It was
+    /// either created under `cgroupfs` or is a systemd scope, depending on
+    /// the driver used by `prepare_cgroup`, so it is looked up from the
+    /// workload process itself.
+    fn attach_cgroup(&self, target_pid: libc::pid_t) -> Result<Option<CGroup>> {
+        let Some(identity) = &self.workload_id else {
+            return Ok(None);
+        };
+        let Some(cgroup) = CGroup::of_process(target_pid)? else {
+            return Ok(None);
+        };
 
-        if !path.exists() {
-            return Ok(());
+        // Don't join a cgroup the workload merely shares with others, like
+        // the one the supervisor was started in.
+        let name = Path::new(cgroup.path())
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        if name != format!("styrolite-{identity}") && name != systemd::unit_name(identity) {
+            debug!("workload cgroup {cgroup:?} is not its own, not attaching to it");
+            return Ok(None);
         }
 
-        let path_str = path
-            .to_str()
-            .ok_or(anyhow!("path is somehow not valid utf-8"))?;
-        let subtree = CGroup::open(path_str)?;
-
-        debug!("binding supervisor (pid {pid}) to cgroup");
-        subtree
-            .clone()
-            .set_child_value("cgroup.procs", &format!("{pid}"))?;
-
-        Ok(())
+        Ok(Some(cgroup))
     }
 }
 
@@ -864,6 +1445,15 @@ impl Wrappable for AttachRequest {
     fn wrap(&self) -> Result<()> {
         debug!("executing with config {self:?}");
 
+        #[allow(deprecated)]
+        if self.cgroupfs.is_some() {
+            warn!("cgroupfs is ignored when attaching, the workload's cgroup is used");
+        }
+
+        if let Some(sig) = self.parent_death_signal()? {
+            signal::set_parent_death_signal(sig)?;
+        }
+
         let target_ns = self.namespaces.clone().unwrap_or(vec![
             Namespace::Mount,
             Namespace::Time,
@@ -881,9 +1471,14 @@ impl Wrappable for AttachRequest {
             "maybe attach to a pre-existing supervisor cgroup for workload identity {}",
             self.identity()?
         );
-        if self.attach_cgroup().is_err() {
-            warn!("unable to set resource limits, cgroup access denied!");
-        }
+        let cgroup = self.attach_cgroup(target_pid).unwrap_or_else(|e| {
+            warn!("unable to find the workload cgroup: {e}");
+            None
+        });
+        // The path is the host's, so it must be opened before setns(2). The
+        // attached process must not escape the workload's limits, so a
+        // cgroup that can't be used is an error.
+        let cgroup = cgroup.as_ref().map(open_workload_cgroup).transpose()?;
 
         debug!("determined that we want to use the namespaces of host PID {target_pid}");
         setns(target_pid, &target_ns)?;
@@ -899,21 +1494,19 @@ impl Wrappable for AttachRequest {
         }
 
         debug!("all namespaces joined -- forking child");
-        fork_and_wait()?;
+        let parent_watch = fork_and_wait(cgroup.as_ref(), self.parent_death_signal.is_some())?;
 
-        preexec_prep(&self.exec, self.capabilities.as_ref())?;
+        self.exec.set_scheduling()?;
 
-        self.exec.execute()
-    }
-}
+        let exec = self.exec.resolve_user()?;
+        preexec_prep(&exec, self.capabilities.as_ref())?;
 
-// TODO(kaniini): Move the mutations to their own rust sources.
-impl Mutatable for CreateDirMutation {
-    fn mutate(&self, rootfs: &str) -> Result<()> {
-        let mut path = PathBuf::from(rootfs);
-        path.push(self.target.clone());
+        // Changing credentials cleared the parent death signal.
+        if let Some(watch) = &parent_watch {
+            watch.arm(Signal::SIGKILL)?;
+        }
 
-        Ok(fs::create_dir_all(path)?)
+        exec.execute()
     }
 }
 
@@ -976,9 +1569,6 @@ fn setup_console(uid: Option<u32>) -> Result<()> {
             return Ok(());
         };
 
-        let pts_path =
-            fs::read_link(format!("/proc/self/fd/{tty_fd}")).context("could not read TTY FD")?;
-
         // If /dev/console isn't here, we should be fine to create it and bind over it,
         // rather than bind over the existing one.
         if !std::path::Path::new("/dev/console").exists() {
@@ -986,16 +1576,12 @@ fn setup_console(uid: Option<u32>) -> Result<()> {
         }
 
         let console_mount = MountSpec {
-            source: Some(pts_path.to_string_lossy().into_owned()),
+            // Bind through the fd rather than the pts path it links to: with
+            // a standard /dev, /dev/pts is a new devpts instance by now.
+            source: Some(format!("/proc/self/fd/{tty_fd}")),
             target: "/dev/console".to_string(),
-            fstype: None,
             bind: true,
-            recurse: false,
-            unshare: false,
-            safe: false,
-            create_mountpoint: false,
-            read_only: false,
-            data: None,
+            ..Default::default()
         };
         console_mount
             .mount()
@@ -1025,32 +1611,89 @@ fn apply_capabilities(capabilities: Option<&Capabilities>) -> Result<()> {
     };
 
     debug!("setting process capabilities");
-    let mut current_capabilities = get_caps()?;
+    let current_capabilities = get_caps()?;
     let drops = Capabilities::names_as_bits(caps.drop.as_deref().unwrap_or(&[]))?;
     let raises = Capabilities::names_as_bits(caps.raise.as_deref().unwrap_or(&[]))?;
     let raises_ambient = Capabilities::names_as_bits(caps.raise_ambient.as_deref().unwrap_or(&[]))?;
 
-    for drop in &drops {
-        if !raises.contains(drop) && !raises_ambient.contains(drop) {
-            let error = unsafe { prctl(PR_CAPBSET_DROP, drop.to_cap_number() as c_int, 0, 0, 0) };
-            if error != 0 {
-                bail!(
-                    "failed to drop bounding capability: {}",
-                    Error::last_os_error()
-                );
-            }
+    let bounding_drops = match &caps.bounding {
+        Some(names) => {
+            let bounding = Capabilities::names_as_bits(names)?;
+            CapabilityBit::supported()
+                .into_iter()
+                .filter(|bit| !bounding.contains(bit))
+                .collect()
         }
-    }
-
-    current_capabilities.effective =
-        CapabilityBit::clear_bits(current_capabilities.effective, &drops);
-    current_capabilities.effective =
-        CapabilityBit::set_bits(current_capabilities.effective, &raises);
-    current_capabilities.permitted = current_capabilities.effective;
-    current_capabilities.inheritable = current_capabilities.effective;
-    set_caps(current_capabilities)?;
-
-    for drop in &drops {
+        None => drops
+            .iter()
+            .copied()
+            .filter(|drop| !raises.contains(drop) && !raises_ambient.contains(drop))
+            .collect::<Vec<_>>(),
+    };
+    for drop in &bounding_drops {
+        let error = unsafe { prctl(PR_CAPBSET_DROP, drop.to_cap_number() as c_int, 0, 0, 0) };
+        if error != 0 {
+            bail!(
+                "failed to drop bounding capability: {}",
+                Error::last_os_error()
+            );
+        }
+    }
+
+    let derived = CapabilityBit::set_bits(
+        CapabilityBit::clear_bits(current_capabilities.effective, &drops),
+        &raises,
+    );
+    let set_or_derived = |names: &Option<Vec<String>>| -> Result<u64> {
+        match names {
+            Some(names) => Ok(CapabilityBit::raw_bits(&Capabilities::names_as_bits(
+                names,
+            )?)),
+            None => Ok(derived),
+        }
+    };
+    let target = CapResult {
+        effective: set_or_derived(&caps.effective)?,
+        permitted: set_or_derived(&caps.permitted)?,
+        inheritable: set_or_derived(&caps.inheritable)?,
+    };
+
+    let ambient = match &caps.ambient {
+        Some(names) => Capabilities::names_as_bits(names)?,
+        None => raises_ambient,
+    };
+    for raise in &ambient {
+        if !raise.get_from(target.permitted & target.inheritable) {
+            bail!(
+                "ambient capability CAP_{} must also be permitted and inheritable",
+                raise.as_ref()
+            );
+        }
+    }
+
+    // Raising ambient capabilities needs them in the inheritable set, and
+    // setting securebits needs CAP_SETPCAP, which the workload may not keep.
+    // So only the inheritable set is changed first, and the other sets once
+    // nothing else requires the supervisor's capabilities.
+    set_caps(CapResult {
+        inheritable: target.inheritable,
+        ..current_capabilities
+    })?;
+
+    let lowered = if caps.ambient.is_some() {
+        let error = unsafe { prctl(PR_CAP_AMBIENT, PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) };
+        if error != 0 {
+            bail!(
+                "failed to clear ambient capabilities: {}",
+                Error::last_os_error()
+            );
+        }
+        &[][..]
+    } else {
+        &drops[..]
+    };
+
+    for drop in lowered {
         let error = unsafe {
             prctl(
                 PR_CAP_AMBIENT,
@@ -1068,7 +1711,7 @@ fn apply_capabilities(capabilities: Option<&Capabilities>) -> Result<()> {
         }
     }
 
-    for raise in &raises_ambient {
+    for raise in &ambient {
         let error = unsafe {
             prctl(
                 PR_CAP_AMBIENT,
@@ -1085,6 +1728,12 @@ fn apply_capabilities(capabilities: Option<&Capabilities>) -> Result<()> {
             );
         }
     }
+
+    if let Some(securebits) = &caps.securebits {
+        set_securebits(securebits)?;
+    }
+
+    set_caps(target)?;
     Ok(())
 }
 
@@ -1094,7 +1743,8 @@ fn apply_capabilities(capabilities: Option<&Capabilities>) -> Result<()> {
 /// 1. `set_keep_caps` (SECBIT_NO_SETUID_FIXUP) so the kernel does not clear
 ///    the permitted/effective cap sets on a uid 0 <-> non-zero transition.
 /// 2. `apply_gid_uid` to drop primary GID, supplemental GIDs, and UID.
-/// 3. `apply_capabilities` to apply the workload's final cap raises/drops.
+/// 3. `apply_capabilities` to apply the workload's final cap sets and
+///    securebits.
 ///
 /// Both `CreateRequest::wrap` and `AttachRequest::wrap` must run this
 /// sequence.
@@ -1114,13 +1764,21 @@ fn preexec_prep(exec: &ExecutableSpec, capabilities: Option<&Capabilities>) -> R
 
 #[cfg(test)]
 mod tests {
-    use super::{apply_capabilities, apply_gid_uid, preexec_prep};
-    use crate::caps::{CapabilityBit, get_caps};
-    use crate::config::{Capabilities, CreateRequest, ExecutableSpec};
-    use crate::namespace::Namespace;
+    use super::{
+        apply_capabilities, apply_gid_uid, fork_workload, open_workload_cgroup, preexec_prep,
+    };
+    use crate::caps::{CapabilityBit, SECBIT_NOROOT, SECBIT_NOROOT_LOCKED, SecureBit, get_caps};
+    use crate::cgroup::CGroup;
+    use crate::config::{
+        Capabilities, ClockOffset, CreateDirMutation, CreateRequest, DnsConfig, ExecutableSpec,
+        HostEntry, IdMap, IdMapping, MountSpec, Mountable, Mutation, OverlayRootfs, OverlayUpper,
+        Personality, PersonalityDomain, PersonalityFlag, Propagation, TimeOffsets,
+    };
+    use crate::namespace::{Namespace, NamespaceJoin, NamespaceSource};
     use crate::unshare::unshare;
     use nix::sys::wait::{WaitStatus, waitpid};
     use nix::unistd::{ForkResult, fork, geteuid};
+    use std::os::unix::fs::MetadataExt;
 
     const NOBODY_UID: u32 = 65534;
 
@@ -1293,8 +1951,7 @@ mod tests {
                 };
                 let caps = Capabilities {
                     raise: Some(vec!["CAP_NET_RAW".to_string()]),
-                    raise_ambient: None,
-                    drop: None,
+                    ..Default::default()
                 };
                 if preexec_prep(&exec, Some(&caps)).is_err() {
                     return 1;
@@ -1313,6 +1970,56 @@ mod tests {
         });
     }
 
+    #[test]
+    fn root_only_explicit_capability_sets_and_securebits_are_applied() {
+        if !is_root() {
+            return;
+        }
+        let names = |names: &[&str]| Some(names.iter().map(|n| n.to_string()).collect());
+        assert!(unsafe {
+            in_child(|| {
+                let caps = Capabilities {
+                    bounding: names(&["CAP_CHOWN", "CAP_NET_RAW", "CAP_SETPCAP"]),
+                    effective: names(&["CAP_CHOWN"]),
+                    permitted: names(&["CAP_CHOWN", "CAP_NET_RAW"]),
+                    inheritable: names(&["CAP_NET_RAW"]),
+                    ambient: names(&["CAP_NET_RAW"]),
+                    securebits: Some(vec![SecureBit::NoRoot, SecureBit::NoRootLocked]),
+                    ..Default::default()
+                };
+                if apply_capabilities(Some(&caps)).is_err() {
+                    return 1;
+                }
+                let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+                    return 2;
+                };
+                let set = |name: &str| {
+                    status
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .and_then(|bits| u64::from_str_radix(bits.trim(), 16).ok())
+                };
+                let chown = CapabilityBit::Chown.bit_mask();
+                let net_raw = CapabilityBit::NetRaw.bit_mask();
+                let setpcap = CapabilityBit::Setpcap.bit_mask();
+                if set("CapBnd:") != Some(chown | net_raw | setpcap) {
+                    return 3;
+                }
+                if set("CapEff:") != Some(chown)
+                    || set("CapPrm:") != Some(chown | net_raw)
+                    || set("CapInh:") != Some(net_raw)
+                    || set("CapAmb:") != Some(net_raw)
+                {
+                    return 4;
+                }
+                if libc::prctl(libc::PR_GET_SECUREBITS) != SECBIT_NOROOT | SECBIT_NOROOT_LOCKED {
+                    return 5;
+                }
+                0
+            })
+        });
+    }
+
     #[test]
     fn root_only_raise_then_setuid_without_keep_caps_drops_cap() {
         if !is_root() {
@@ -1322,8 +2029,7 @@ mod tests {
             in_child(|| {
                 let caps = Capabilities {
                     raise: Some(vec!["CAP_NET_RAW".to_string()]),
-                    raise_ambient: None,
-                    drop: None,
+                    ..Default::default()
                 };
                 if apply_capabilities(Some(&caps)).is_err() {
                     return 1;
@@ -1350,4 +2056,639 @@ mod tests {
             })
         });
     }
+
+    /// Find a mounted cgroup2 hierarchy, if the host has one.
+    fn cgroup2_mountpoint() -> Option<String> {
+        std::fs::read_to_string("/proc/self/mounts")
+            .ok()?
+            .lines()
+            .map(|line| line.split(' ').collect::<Vec<_>>())
+            .find(|fields| fields.len() > 2 && fields[2] == "cgroup2")
+            .map(|fields| fields[1].to_string())
+    }
+
+    /// fork_workload() must place the child in the requested cgroup while
+    /// leaving the supervisor (the caller) where it was.
+    #[test]
+    fn root_only_fork_workload_spawns_into_cgroup() {
+        if !is_root() {
+            return;
+        }
+        let Some(mountpoint) = cgroup2_mountpoint() else {
+            return;
+        };
+        let name = format!("styrolite-test-{}", std::process::id());
+        let Ok(cgroup) = CGroup::open(&mountpoint).and_then(|root| root.create_child(&name)) else {
+            return;
+        };
+        let before = std::fs::read_to_string("/proc/self/cgroup").unwrap();
+        let cgroup_fd = open_workload_cgroup(&cgroup).unwrap();
+
+        assert!(unsafe {
+            in_child(|| match fork_workload(Some(&cgroup_fd)) {
+                Ok(ForkResult::Child) => {
+                    let own = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+                    libc::_exit(if own.contains(&name) { 0 } else { 1 })
+                }
+                Ok(ForkResult::Parent { child }) => match waitpid(child, None) {
+                    Ok(WaitStatus::Exited(_, 0)) => 0,
+                    _ => 2,
+                },
+                Err(_) => 3,
+            })
+        });
+
+        assert_eq!(
+            before,
+            std::fs::read_to_string("/proc/self/cgroup").unwrap()
+        );
+        let _ = std::fs::remove_dir(cgroup.path());
+    }
+
+    #[test]
+    fn explicit_ids_take_precedence_over_the_user() {
+        let passwd = "app:x:1000:1000::/home/app:/bin/sh\n";
+        let group = "wheel:x:10:app\n";
+        let resolve = |spec| crate::user::resolve(passwd, group, spec).unwrap();
+
+        let exec = ExecutableSpec {
+            gid: Some(5),
+            ..Default::default()
+        };
+        let resolved = exec.with_user(resolve("app"));
+        assert_eq!(resolved.uid, Some(1000));
+        assert_eq!(resolved.gid, Some(5));
+        assert_eq!(resolved.supplemental_gids, Some(vec![10]));
+        assert_eq!(
+            resolved.environment.as_ref().unwrap().get("HOME").unwrap(),
+            "/home/app"
+        );
+
+        let exec = ExecutableSpec {
+            supplemental_gids: Some(vec![]),
+            environment: Some([("HOME".to_string(), "/data".to_string())].into()),
+            ..Default::default()
+        };
+        let resolved = exec.with_user(resolve("4242"));
+        assert_eq!(resolved.uid, Some(4242));
+        assert_eq!(resolved.gid, Some(0));
+        assert_eq!(resolved.supplemental_gids, Some(vec![]));
+        assert_eq!(
+            resolved.environment.as_ref().unwrap().get("HOME").unwrap(),
+            "/data"
+        );
+    }
+
+    #[test]
+    fn umask_and_personality_are_set_for_the_workload() {
+        let exec = ExecutableSpec {
+            executable: Some("/bin/sh".to_string()),
+            arguments: Some(vec![
+                "-c".to_string(),
+                "test $(umask) = 0027 && test $(cat /proc/self/personality) = 00040008".to_string(),
+            ]),
+            umask: Some(0o027),
+            personality: Some(Personality {
+                domain: PersonalityDomain::Linux32,
+                flags: vec![PersonalityFlag::AddrNoRandomize],
+            }),
+            ..Default::default()
+        };
+        assert!(unsafe { in_child(|| exec.execute().map_or(1, |_| 0)) });
+    }
+
+    #[test]
+    fn time_offsets_default_to_resetting_boottime() {
+        let req = |time_offsets| CreateRequest {
+            time_offsets,
+            ..Default::default()
+        };
+        assert_eq!(
+            req(None).render_time_offsets(3600).unwrap(),
+            "boottime -3599 0\n"
+        );
+        assert_eq!(
+            req(Some(TimeOffsets::ResetBoottime))
+                .render_time_offsets(0)
+                .unwrap(),
+            "boottime 0 0\n"
+        );
+        assert_eq!(
+            req(Some(TimeOffsets::PreserveHost))
+                .render_time_offsets(3600)
+                .unwrap(),
+            ""
+        );
+
+        let offset = |secs, nanos| Some(ClockOffset { secs, nanos });
+        let explicit = req(Some(TimeOffsets::Explicit {
+            monotonic: offset(86400, 500),
+            boottime: offset(-10, 0),
+        }));
+        assert_eq!(
+            explicit.render_time_offsets(3600).unwrap(),
+            "monotonic 86400 500\nboottime -10 0\n"
+        );
+        let invalid = req(Some(TimeOffsets::Explicit {
+            monotonic: None,
+            boottime: offset(1, 1_000_000_000),
+        }));
+        assert!(invalid.time_offsets.unwrap().check().is_err());
+    }
+
+    #[test]
+    fn joined_namespaces_must_not_be_created() {
+        let join = |namespace| NamespaceJoin {
+            namespace,
+            source: NamespaceSource::Pid { pid: 1 },
+        };
+        let created = [Namespace::Mount, Namespace::Pid, Namespace::User];
+        let req = |joins: Vec<NamespaceJoin>| CreateRequest {
+            join_namespaces: Some(joins),
+            ..Default::default()
+        };
+
+        let pod = req(vec![join(Namespace::Net), join(Namespace::Ipc)]);
+        assert!(pod.check_joined_namespaces(&created).is_ok());
+        assert!(
+            req(vec![join(Namespace::Pid)])
+                .check_joined_namespaces(&created)
+                .is_err()
+        );
+        assert!(
+            req(vec![join(Namespace::Net), join(Namespace::Net)])
+                .check_joined_namespaces(&[])
+                .is_err()
+        );
+        assert!(
+            req(vec![join(Namespace::Mount)])
+                .check_joined_namespaces(&[])
+                .is_err()
+        );
+
+        let mut userns = req(vec![join(Namespace::User)]);
+        assert!(userns.check_joined_namespaces(&[Namespace::Mount]).is_ok());
+        userns.id_map_helpers = Some(true);
+        assert!(userns.check_joined_namespaces(&[Namespace::Mount]).is_err());
+    }
+
+    #[test]
+    fn container_idmap_requires_container_mappings() {
+        let req = CreateRequest::default();
+        assert!(req.resolve_idmap(&IdMap::Container).is_err());
+
+        let mapping = || {
+            vec![IdMapping {
+                base_nsid: 0,
+                base_hostid: 100000,
+                remap_count: 65536,
+            }]
+        };
+        let req = CreateRequest {
+            uid_mappings: Some(mapping()),
+            gid_mappings: Some(mapping()),
+            ..Default::default()
+        };
+        let Ok(IdMap::Mappings { uid_mappings, .. }) = req.resolve_idmap(&IdMap::Container) else {
+            panic!("container idmap did not resolve to explicit mappings");
+        };
+        assert_eq!(uid_mappings[0].base_hostid, 100000);
+    }
+
+    /// Files owned by id 0 on disk must show up as the mapped host id through
+    /// an idmapped bind mount.
+    #[test]
+    fn root_only_idmapped_bind_mount_remaps_owners() {
+        if !is_root() {
+            return;
+        }
+        let (Ok(source), Ok(target)) = (tempfile::TempDir::new(), tempfile::TempDir::new()) else {
+            return;
+        };
+        if std::fs::write(source.path().join("file"), "").is_err() {
+            return;
+        }
+
+        let mapping = || {
+            vec![IdMapping {
+                base_nsid: 0,
+                base_hostid: 1000,
+                remap_count: 1,
+            }]
+        };
+        let spec = MountSpec {
+            source: Some(source.path().to_string_lossy().into_owned()),
+            target: target.path().to_string_lossy().into_owned(),
+            bind: true,
+            idmap: Some(IdMap::Mappings {
+                uid_mappings: mapping(),
+                gid_mappings: mapping(),
+            }),
+            ..Default::default()
+        };
+
+        assert!(unsafe {
+            in_child(|| {
+                if unshare(&[Namespace::Mount]).is_err() {
+                    return 1;
+                }
+                if spec.mount().is_err() {
+                    return 2;
+                }
+                match std::fs::metadata(target.path().join("file")) {
+                    Ok(meta) if meta.uid() == 1000 && meta.gid() == 1000 => 0,
+                    _ => 3,
+                }
+            })
+        });
+    }
+
+    /// An overlay rootfs with an ephemeral upper shows the union of its
+    /// layers and is writable, without modifying the lower layers.
+    #[test]
+    fn root_only_pivot_fs_assembles_ephemeral_overlay() {
+        if !is_root() {
+            return;
+        }
+        let (Ok(top), Ok(base)) = (tempfile::TempDir::new(), tempfile::TempDir::new()) else {
+            return;
+        };
+        let populated = std::fs::create_dir(base.path().join("proc"))
+            .and_then(|_| std::fs::write(base.path().join("base"), "base"))
+            .and_then(|_| std::fs::write(top.path().join("top"), "top"));
+        if populated.is_err() {
+            return;
+        }
+
+        let req = CreateRequest {
+            rootfs_overlay: Some(OverlayRootfs {
+                lower: vec![
+                    top.path().to_string_lossy().into_owned(),
+                    base.path().to_string_lossy().into_owned(),
+                ],
+                upper: Some(OverlayUpper::Ephemeral { size: None }),
+            }),
+            workload_id: Some("overlay-test".to_string()),
+            ..Default::default()
+        };
+
+        assert!(unsafe {
+            in_child(|| {
+                if unshare(&[Namespace::Mount]).is_err() {
+                    return 1;
+                }
+                if req.pivot_fs().is_err() {
+                    return 2;
+                }
+                if !std::path::Path::new("/top").exists() || !std::path::Path::new("/base").exists()
+                {
+                    return 3;
+                }
+                if std::fs::write("/written", "").is_err() {
+                    return 4;
+                }
+                0
+            })
+        });
+
+        assert!(!top.path().join("written").exists());
+        assert!(!base.path().join("written").exists());
+    }
+
+    #[test]
+    fn root_only_pivot_fs_applies_mount_options() {
+        if !is_root() {
+            return;
+        }
+        assert!(unsafe {
+            in_child(|| {
+                let Some(rootfs_dir) = make_minimal_rootfs() else {
+                    return 1;
+                };
+                let req = CreateRequest {
+                    mounts: Some(vec![MountSpec {
+                        source: Some("tmpfs".to_string()),
+                        target: "/scratch".to_string(),
+                        fstype: Some("tmpfs".to_string()),
+                        create_mountpoint: true,
+                        data: Some("size=1m".to_string()),
+                        options: ["nosuid", "nodev", "noatime", "nosymfollow", "mode=700"]
+                            .iter()
+                            .map(|o| o.to_string())
+                            .collect(),
+                        ..Default::default()
+                    }]),
+                    ..request_with_rootfs(&rootfs_dir)
+                };
+                if unshare(&[Namespace::Mount]).is_err() {
+                    return 2;
+                }
+                if req.pivot_fs().is_err() {
+                    return 3;
+                }
+
+                let mut vfs: libc::statvfs = std::mem::zeroed();
+                if libc::statvfs(c"/scratch".as_ptr(), &mut vfs) < 0 {
+                    return 4;
+                }
+                // libc has no ST_NOSYMFOLLOW.
+                let expected = libc::ST_NOSUID | libc::ST_NODEV | libc::ST_NOATIME | 0x2000;
+                if vfs.f_flag & expected != expected || vfs.f_flag & libc::ST_NOEXEC != 0 {
+                    return 5;
+                }
+                if vfs.f_blocks * vfs.f_frsize != 1024 * 1024 {
+                    return 6;
+                }
+                match std::fs::metadata("/scratch") {
+                    Ok(m) if m.mode() & 0o777 == 0o700 => 0,
+                    _ => 7,
+                }
+            })
+        });
+    }
+
+    #[test]
+    fn root_only_rejected_mount_options_are_explained() {
+        if !is_root() {
+            return;
+        }
+        assert!(unsafe {
+            in_child(|| {
+                let Some(rootfs_dir) = make_minimal_rootfs() else {
+                    return 1;
+                };
+                let req = CreateRequest {
+                    mounts: Some(vec![MountSpec {
+                        source: Some("tmpfs".to_string()),
+                        target: "/scratch".to_string(),
+                        fstype: Some("tmpfs".to_string()),
+                        create_mountpoint: true,
+                        options: vec!["size=lots".to_string()],
+                        ..Default::default()
+                    }]),
+                    ..request_with_rootfs(&rootfs_dir)
+                };
+                if unshare(&[Namespace::Mount]).is_err() {
+                    return 2;
+                }
+                match req.pivot_fs() {
+                    Err(e) if e.to_string().contains("option \"size\"") => 0,
+                    Err(_) => 3,
+                    Ok(()) => 4,
+                }
+            })
+        });
+    }
+
+    #[test]
+    fn root_only_pivot_fs_confines_symlinks_to_rootfs() {
+        if !is_root() {
+            return;
+        }
+        let (Ok(host), Some(rootfs_dir)) = (tempfile::TempDir::new(), make_minimal_rootfs()) else {
+            return;
+        };
+        let outside = host.path().to_string_lossy().into_owned();
+        let shadow = rootfs_dir.path().join(outside.trim_start_matches('/'));
+        let prepared = std::fs::create_dir_all(&shadow)
+            .and_then(|_| std::os::unix::fs::symlink(&outside, rootfs_dir.path().join("escape")));
+        if prepared.is_err() {
+            return;
+        }
+
+        let req = CreateRequest {
+            mounts: Some(vec![MountSpec {
+                source: Some("tmpfs".to_string()),
+                target: "/escape/mnt".to_string(),
+                fstype: Some("tmpfs".to_string()),
+                create_mountpoint: true,
+                ..Default::default()
+            }]),
+            mutations: Some(vec![Mutation::CreateDir(CreateDirMutation {
+                target: "/escape/created".to_string(),
+            })]),
+            ..request_with_rootfs(&rootfs_dir)
+        };
+
+        assert!(unsafe {
+            in_child(|| {
+                if unshare(&[Namespace::Mount]).is_err() {
+                    return 1;
+                }
+                if req.pivot_fs().is_err() {
+                    return 2;
+                }
+                0
+            })
+        });
+
+        assert!(shadow.join("mnt").is_dir() && shadow.join("created").is_dir());
+        assert!(std::fs::read_dir(host.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn root_only_rslave_rootfs_receives_host_mounts() {
+        if !is_root() {
+            return;
+        }
+        let (Ok(host), Some(rootfs_dir)) = (tempfile::TempDir::new(), make_minimal_rootfs()) else {
+            return;
+        };
+        let volume = host.path().to_string_lossy().into_owned();
+
+        assert!(unsafe {
+            in_child(|| {
+                use std::io::{Read, Write};
+
+                // Stand in for the host: a shared volume in a mount namespace
+                // of our own.
+                if unshare(&[Namespace::Mount]).is_err()
+                    || crate::mount::set_propagation("/", Propagation::RPrivate).is_err()
+                {
+                    return 1;
+                }
+                let host_mount = MountSpec {
+                    source: Some("tmpfs".to_string()),
+                    target: volume.clone(),
+                    fstype: Some("tmpfs".to_string()),
+                    propagation: Some(Propagation::Shared),
+                    ..Default::default()
+                };
+                if host_mount.mount().is_err()
+                    || std::fs::create_dir(host.path().join("sub")).is_err()
+                {
+                    return 2;
+                }
+
+                let req = CreateRequest {
+                    rootfs_propagation: Some(Propagation::RSlave),
+                    mounts: Some(vec![MountSpec {
+                        source: Some(volume.clone()),
+                        target: "/vol".to_string(),
+                        bind: true,
+                        recurse: true,
+                        create_mountpoint: true,
+                        ..Default::default()
+                    }]),
+                    ..request_with_rootfs(&rootfs_dir)
+                };
+                let (Ok((mut ready_rx, mut ready_tx)), Ok((mut go_rx, mut go_tx))) =
+                    (std::io::pipe(), std::io::pipe())
+                else {
+                    return 3;
+                };
+
+                let child = match fork() {
+                    Ok(ForkResult::Child) => {
+                        let status = (|| {
+                            if unshare(&[Namespace::Mount]).is_err() || req.pivot_fs().is_err() {
+                                return 1;
+                            }
+                            let mut byte = [0u8];
+                            if ready_tx.write_all(&byte).is_err()
+                                || go_rx.read_exact(&mut byte).is_err()
+                            {
+                                return 2;
+                            }
+                            match std::fs::read_to_string("/vol/sub/file") {
+                                Ok(content) if content == "late" => 0,
+                                _ => 3,
+                            }
+                        })();
+                        libc::_exit(status)
+                    }
+                    Ok(ForkResult::Parent { child }) => child,
+                    Err(_) => return 4,
+                };
+                drop((ready_tx, go_rx));
+
+                // Mount a new volume only once the workload has pivoted.
+                let mut byte = [0u8];
+                if ready_rx.read_exact(&mut byte).is_err() {
+                    return 5;
+                }
+                let late_mount = MountSpec {
+                    source: Some("tmpfs".to_string()),
+                    target: format!("{volume}/sub"),
+                    fstype: Some("tmpfs".to_string()),
+                    ..Default::default()
+                };
+                if late_mount.mount().is_err()
+                    || std::fs::write(host.path().join("sub/file"), "late").is_err()
+                    || go_tx.write_all(&byte).is_err()
+                {
+                    return 6;
+                }
+
+                match waitpid(child, None) {
+                    Ok(WaitStatus::Exited(_, 0)) => 0,
+                    _ => 7,
+                }
+            })
+        });
+    }
+
+    #[test]
+    fn root_only_pivot_fs_mounts_standard_filesystems() {
+        if !is_root() {
+            return;
+        }
+        assert!(unsafe {
+            in_child(|| {
+                let Some(rootfs_dir) = make_minimal_rootfs() else {
+                    return 1;
+                };
+                let req = CreateRequest {
+                    standard_filesystems: Some(true),
+                    namespaces: Some(vec![Namespace::Mount, Namespace::Ipc]),
+                    ..request_with_rootfs(&rootfs_dir)
+                };
+                if unshare(&[Namespace::Mount, Namespace::Ipc]).is_err() {
+                    return 2;
+                }
+                if req.pivot_fs().is_err() {
+                    return 3;
+                }
+                let null_is_device = std::fs::metadata("/dev/null")
+                    .is_ok_and(|m| std::os::unix::fs::FileTypeExt::is_char_device(&m.file_type()));
+                if !null_is_device || std::fs::write("/dev/null", "discarded").is_err() {
+                    return 4;
+                }
+                if !std::path::Path::new("/dev/ptmx").exists() {
+                    return 5;
+                }
+                if std::fs::read_link("/dev/stdin").ok()
+                    != Some(std::path::PathBuf::from("/proc/self/fd/0"))
+                {
+                    return 6;
+                }
+                for dir in ["/dev/shm", "/dev/mqueue", "/sys/kernel"] {
+                    if !std::path::Path::new(dir).is_dir() {
+                        return 7;
+                    }
+                }
+                if std::fs::write("/sys/kernel/styrolite", "").is_ok() {
+                    return 8;
+                }
+                0
+            })
+        });
+    }
+
+    #[test]
+    fn root_only_pivot_fs_mounts_generated_etc_files() {
+        if !is_root() {
+            return;
+        }
+        let Some(rootfs_dir) = make_minimal_rootfs() else {
+            return;
+        };
+        // The rootfs' own /etc/hosts is shadowed, not modified.
+        let rootfs_hosts = rootfs_dir.path().join("etc/hosts");
+        std::fs::create_dir_all(rootfs_dir.path().join("etc")).expect("create etc");
+        std::fs::write(&rootfs_hosts, "original\n").expect("write hosts");
+
+        assert!(unsafe {
+            in_child(|| {
+                let req = CreateRequest {
+                    hostname: Some("box".to_string()),
+                    hostname_file: Some(true),
+                    hosts: Some(vec![HostEntry {
+                        address: "10.0.0.2".to_string(),
+                        names: vec!["db".to_string()],
+                    }]),
+                    dns: Some(DnsConfig {
+                        servers: vec!["192.0.2.53".to_string()],
+                        ..Default::default()
+                    }),
+                    ..request_with_rootfs(&rootfs_dir)
+                };
+                if unshare(&[Namespace::Mount]).is_err() {
+                    return 2;
+                }
+                if req.pivot_fs().is_err() {
+                    return 3;
+                }
+                if std::fs::read_to_string("/etc/hostname").ok().as_deref() != Some("box\n") {
+                    return 4;
+                }
+                let hosts = std::fs::read_to_string("/etc/hosts").unwrap_or_default();
+                if !hosts.contains("127.0.1.1\tbox\n") || !hosts.contains("10.0.0.2\tdb\n") {
+                    return 5;
+                }
+                let resolv = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+                if !resolv.contains("nameserver 192.0.2.53\n") {
+                    return 6;
+                }
+                if std::fs::write("/etc/hosts", "changed").is_ok() {
+                    return 7;
+                }
+                0
+            })
+        });
+        assert_eq!(
+            std::fs::read_to_string(&rootfs_hosts).unwrap(),
+            "original\n"
+        );
+    }
 }